@@ -0,0 +1,569 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::data::{Rating, RatingChange, Role, Task, TaskVersion, User, Vote};
+use crate::elo::Outcome;
+use crate::engine::{
+    check_retry, compact, is_outdated, new_vote, open_tasks, pick_question, picked_question,
+    ratings_after_retraction, CompactionReport, Engine,
+};
+use crate::errors::Error;
+use crate::persistence::AsyncPersistence;
+use crate::query::{TaskQuery, UserQuery};
+
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+/// The asynchronous counterpart of `Engine`, working on an `AsyncPersistence`.
+///
+/// It applies the same rules and policies as the `Engine` it is built from.
+/// No borrow of the backend is held across the optimistic concurrency loops,
+/// so many requests can go through the same backend at the same time.
+pub struct AsyncEngine {
+    engine: Engine,
+}
+
+impl Default for AsyncEngine {
+    fn default() -> Self {
+        AsyncEngine::new(Engine::new())
+    }
+}
+
+impl AsyncEngine {
+    pub fn new(engine: Engine) -> Self {
+        AsyncEngine { engine }
+    }
+
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub async fn get_question(
+        &self,
+        persistence: &impl AsyncPersistence,
+    ) -> Result<(Task, Task), Error> {
+        let open = open_tasks();
+        let pick = |i: usize| {
+            let query = open.clone().with_offset(i).with_limit(1);
+            async move {
+                persistence
+                    .query_tasks(&query)
+                    .await
+                    .map(|mut tasks| tasks.pop())
+            }
+        };
+        // See `Engine::get_question`.
+        let mut attempts = 0;
+        loop {
+            let (i0, i1) = pick_question(persistence.count_tasks(&open).await?)?;
+            match picked_question(pick(i0).await?, pick(i1).await?) {
+                Ok(question) => return Ok(question),
+                Err(e) => check_retry(e, &mut attempts)?,
+            }
+        }
+    }
+
+    pub async fn answer_question(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t0: &Task,
+        t1: &Task,
+        outcome: Outcome,
+        comment: Option<&str>,
+    ) -> Result<Vote, Error> {
+        let user = self
+            .authorize(persistence, u_id, Role::can_vote, "vote")
+            .await?;
+        let now: DateTime<Utc> = SystemTime::now().into();
+        if let Some(since) = self.engine.limit_window_start(&user, &now)? {
+            let votes = persistence.list_votes_for_user_since(u_id, &since).await?;
+            let retractions = persistence
+                .list_retractions_for_user_since(u_id, &since)
+                .await?;
+            self.engine
+                .check_vote_limit(&user, &now, &votes, &retractions)?;
+        }
+        let mut attempts = 0;
+        loop {
+            let snapshot = persistence.get_snapshot().await?;
+            let (vote, r0, r1) = new_vote(&snapshot, u_id, t0, t1, outcome, comment);
+            match persistence
+                .add_vote_and_update_ratings(snapshot.etag(), &vote, &r0, &r1)
+                .await
+            {
+                Ok(_) => return Ok(vote),
+                Err(e) => check_retry(e, &mut attempts)?,
+            }
+        }
+    }
+
+    pub async fn retract_vote(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        v_id: &Uuid,
+    ) -> Result<(), Error> {
+        self.replace_vote(persistence, u_id, v_id, None).await?;
+        Ok(())
+    }
+
+    pub async fn correct_vote(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        v_id: &Uuid,
+        outcome: Outcome,
+    ) -> Result<Vote, Error> {
+        self.replace_vote(persistence, u_id, v_id, Some(outcome))
+            .await?
+            .ok_or(Error::generic("correction without a replacement vote"))
+    }
+
+    pub async fn get_current_ranking(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+    ) -> Result<Vec<Rating>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read the ranking")
+            .await?;
+        Ok(persistence.get_snapshot().await?.ranking().clone())
+    }
+
+    pub async fn get_rating_history(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<RatingChange>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read the ranking")
+            .await?;
+        persistence.list_rating_changes(t_id).await
+    }
+
+    pub async fn get_ranking_at(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        time: &DateTime<Utc>,
+    ) -> Result<Vec<Rating>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read the ranking")
+            .await?;
+        persistence.get_ranking_at(time).await
+    }
+
+    pub async fn compact_votes(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+    ) -> Result<CompactionReport, Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "compact votes")
+            .await?;
+        let cutoff = self.engine.compaction_cutoff(&SystemTime::now().into());
+
+        let mut attempts = 0;
+        loop {
+            let snapshot = persistence.get_snapshot().await?;
+            let (archive, report) = compact(
+                &snapshot,
+                &persistence.get_vote_archive().await?,
+                &persistence.list_votes().await?,
+                retracted_vote_ids(persistence).await?,
+                cutoff,
+            );
+            if report.compacted_votes() == 0 {
+                return Ok(report);
+            }
+            match persistence.compact_votes(snapshot.etag(), &archive).await {
+                Ok(_) => return Ok(report),
+                Err(e) => check_retry(e, &mut attempts)?,
+            }
+        }
+    }
+
+    pub async fn get_task_comments(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<Vote>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read comments")
+            .await?;
+        let retracted = retracted_vote_ids(persistence).await?;
+        Ok(persistence
+            .list_votes_for_task(t_id)
+            .await?
+            .into_iter()
+            .filter(|v| v.comment().is_some() && !retracted.contains(v.id()))
+            .collect())
+    }
+
+    pub async fn add_task(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t: &Task,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "add tasks")
+            .await?;
+        persistence.upsert_task(t, u_id).await
+    }
+
+    pub async fn edit_task(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t: &Task,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "edit tasks")
+            .await?;
+        persistence.get_task(t.id()).await?;
+        persistence.upsert_task(t, u_id).await
+    }
+
+    pub async fn get_task_history(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<TaskVersion>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read task history")
+            .await?;
+        persistence.list_task_versions(t_id).await
+    }
+
+    pub async fn get_outdated_votes(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<Vote>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read votes")
+            .await?;
+        let current = persistence.get_task(t_id).await?;
+        let retracted = retracted_vote_ids(persistence).await?;
+        Ok(persistence
+            .list_votes_for_task(t_id)
+            .await?
+            .into_iter()
+            .filter(|v| !retracted.contains(v.id()) && is_outdated(v, &current))
+            .collect())
+    }
+
+    pub async fn close_task(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "close tasks")
+            .await?;
+        persistence.close_task(t_id).await
+    }
+
+    pub async fn list_users(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+    ) -> Result<Vec<User>, Error> {
+        self.authorize(persistence, u_id, Role::can_manage_users, "list users")
+            .await?;
+        persistence.list_users().await
+    }
+
+    pub async fn query_users(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        query: &UserQuery,
+    ) -> Result<Vec<User>, Error> {
+        self.authorize(persistence, u_id, Role::can_manage_users, "list users")
+            .await?;
+        persistence.query_users(query).await
+    }
+
+    pub async fn query_tasks(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        query: &TaskQuery,
+    ) -> Result<Vec<Task>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "list tasks")
+            .await?;
+        persistence.query_tasks(query).await
+    }
+
+    pub async fn upsert_user(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        u: &User,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_users, "manage users")
+            .await?;
+        persistence.upsert_user(u).await
+    }
+
+    async fn replace_vote(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        v_id: &Uuid,
+        outcome: Option<Outcome>,
+    ) -> Result<Option<Vote>, Error> {
+        self.authorize(persistence, u_id, Role::can_vote, "retract votes")
+            .await?;
+        let vote = persistence.get_vote(v_id).await?;
+        let (retraction, replacement) = self.engine.prepare_retraction(u_id, &vote, outcome)?;
+
+        let mut attempts = 0;
+        loop {
+            let snapshot = persistence.get_snapshot().await?;
+            let ratings = ratings_after_retraction(
+                &snapshot,
+                &persistence.get_vote_archive().await?,
+                &persistence.list_votes().await?,
+                retracted_vote_ids(persistence).await?,
+                &retraction,
+                replacement.as_ref(),
+            )?;
+            match persistence
+                .retract_vote_and_replace_ratings(
+                    snapshot.etag(),
+                    &retraction,
+                    replacement.as_ref(),
+                    &ratings,
+                )
+                .await
+            {
+                Ok(_) => return Ok(replacement),
+                Err(e) => check_retry(e, &mut attempts)?,
+            }
+        }
+    }
+
+    async fn authorize(
+        &self,
+        persistence: &impl AsyncPersistence,
+        u_id: &str,
+        allowed: fn(&Role) -> bool,
+        action: &str,
+    ) -> Result<User, Error> {
+        let user = persistence.get_user(u_id).await?;
+        if !allowed(&user.role()) {
+            return Err(Error::permission_denied(u_id, action));
+        }
+        Ok(user)
+    }
+}
+
+async fn retracted_vote_ids(persistence: &impl AsyncPersistence) -> Result<HashSet<Uuid>, Error> {
+    Ok(persistence
+        .list_retractions()
+        .await?
+        .iter()
+        .map(|r| *r.vote())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::async_engine::AsyncEngine;
+    use crate::data::{Role, Task, User};
+    use crate::elo::Outcome;
+    use crate::engine::{Engine, RetentionPolicy, RetractionPolicy};
+    use crate::errors::ErrorCode;
+    use crate::fixtures::TempPath;
+    use crate::persistence::{
+        AsyncPersistence, BlockingPersistence, InMemory, LoggingPersistence, Persistence,
+        SQLitePersistence,
+    };
+    use crate::query::{TaskQuery, TaskStatus};
+
+    use chrono::TimeDelta;
+    use std::sync::{Arc, Mutex};
+    use url::Url;
+    use uuid::Uuid;
+
+    const TEST_USER_ID: &str = "test_user";
+    const TEST_ADMIN_ID: &str = "test_admin";
+
+    async fn init() -> (BlockingPersistence<InMemory>, Task, Task) {
+        let mut database = InMemory::new();
+        database.upsert_user(&User::new(TEST_USER_ID, 2)).unwrap();
+        database
+            .upsert_user(&User::with_role(TEST_ADMIN_ID, -1, Role::Admin))
+            .unwrap();
+        let persistence = BlockingPersistence::new(database);
+        let engine = AsyncEngine::default();
+        let mut tasks = Vec::new();
+        for i in 0..2 {
+            let task = Task::new(
+                Uuid::new_v4(),
+                &format!("task {}", i),
+                Url::parse(&format!("https://localhost/{}", i)).unwrap(),
+                false,
+            );
+            engine
+                .add_task(&persistence, TEST_ADMIN_ID, &task)
+                .await
+                .unwrap();
+            tasks.push(task);
+        }
+        let t1 = tasks.pop().unwrap();
+        let t0 = tasks.pop().unwrap();
+        (persistence, t0, t1)
+    }
+
+    #[tokio::test]
+    async fn test_async_answer_question() {
+        let (persistence, t0, t1) = init().await;
+        let engine = AsyncEngine::default();
+        let (q0, q1) = engine.get_question(&persistence).await.unwrap();
+        assert_ne!(q0.id(), q1.id());
+
+        engine
+            .answer_question(&persistence, TEST_USER_ID, &t0, &t1, Outcome::P0Win, None)
+            .await
+            .unwrap();
+        engine
+            .answer_question(&persistence, TEST_USER_ID, &t0, &t1, Outcome::P0Win, None)
+            .await
+            .unwrap();
+        let result = engine
+            .answer_question(&persistence, TEST_USER_ID, &t0, &t1, Outcome::P0Win, None)
+            .await;
+        assert_eq!(result.err().unwrap().code(), ErrorCode::UserLimitExceeded);
+
+        let ranking = engine
+            .get_current_ranking(&persistence, TEST_USER_ID)
+            .await
+            .unwrap();
+        assert_eq!(ranking[1].task(), t0.id());
+        let result = engine.close_task(&persistence, TEST_USER_ID, t0.id()).await;
+        assert_eq!(result.err().unwrap().code(), ErrorCode::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_async_question_while_tasks_are_closed() {
+        let path = TempPath::new(".db");
+        // Closes the task set here on another connection, right after the
+        // engine has counted the open tasks.
+        let to_close: Arc<Mutex<Option<Uuid>>> = Arc::default();
+        let (closing, db_path) = (to_close.clone(), path.to_path_buf());
+        let inner = SQLitePersistence::new(path.to_path_buf()).unwrap();
+        let mut database = LoggingPersistence::new(inner, move |call| {
+            if call.method() != "count_tasks" {
+                return;
+            }
+            if let Some(t_id) = closing.lock().unwrap().take() {
+                let mut other = SQLitePersistence::new(db_path.clone()).unwrap();
+                other.close_task(&t_id).unwrap();
+            }
+        });
+        database
+            .upsert_user(&User::with_role(TEST_ADMIN_ID, -1, Role::Admin))
+            .unwrap();
+        let persistence = BlockingPersistence::new(database);
+        let engine = AsyncEngine::default();
+        let open = TaskQuery::new().with_status(TaskStatus::Open);
+
+        for i in 0..20 {
+            let task = Task::new(
+                Uuid::new_v4(),
+                &format!("task {}", i),
+                Url::parse(&format!("https://localhost/{}", i)).unwrap(),
+                false,
+            );
+            engine
+                .add_task(&persistence, TEST_ADMIN_ID, &task)
+                .await
+                .unwrap();
+            let tasks = persistence.query_tasks(&open).await.unwrap();
+            if tasks.len() < 3 {
+                continue;
+            }
+            *to_close.lock().unwrap() = Some(*tasks[0].id());
+
+            let (t0, t1) = engine.get_question(&persistence).await.unwrap();
+            assert_ne!(t0.id(), t1.id());
+            assert_ne!(t0.id(), tasks[0].id());
+            assert_ne!(t1.id(), tasks[0].id());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_correct_vote() {
+        let (persistence, t0, t1) = init().await;
+        let engine = AsyncEngine::new(
+            Engine::new().with_retraction_policy(RetractionPolicy::new(TimeDelta::hours(1), false)),
+        );
+        let vote = engine
+            .answer_question(&persistence, TEST_USER_ID, &t0, &t1, Outcome::P1Win, None)
+            .await
+            .unwrap();
+        let corrected = engine
+            .correct_vote(&persistence, TEST_USER_ID, vote.id(), Outcome::P0Win)
+            .await
+            .unwrap();
+        assert_eq!(corrected.time(), vote.time());
+        let ranking = persistence.get_snapshot().await.unwrap();
+        assert_eq!(ranking.ranking()[1].task(), t0.id());
+        let result = engine
+            .retract_vote(&persistence, TEST_USER_ID, vote.id())
+            .await;
+        assert_eq!(
+            result.err().unwrap().code(),
+            ErrorCode::RetractionNotAllowed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_async_compact_votes() {
+        let (persistence, t0, t1) = init().await;
+        let engine = AsyncEngine::new(
+            Engine::new()
+                .with_retraction_policy(RetractionPolicy::new(TimeDelta::zero(), true))
+                .with_retention_policy(RetentionPolicy::new(TimeDelta::zero())),
+        );
+        for outcome in [Outcome::P0Win, Outcome::Draw] {
+            engine
+                .answer_question(&persistence, TEST_ADMIN_ID, &t0, &t1, outcome, None)
+                .await
+                .unwrap();
+        }
+        let before = persistence.get_snapshot().await.unwrap();
+        let report = engine
+            .compact_votes(&persistence, TEST_ADMIN_ID)
+            .await
+            .unwrap();
+        assert_eq!(report.compacted_votes(), 2);
+        assert_eq!(report.pairs(), 1);
+        assert!(persistence.list_votes().await.unwrap().is_empty());
+        let archive = persistence.get_vote_archive().await.unwrap();
+        assert_eq!(archive.tally(t0.id(), t1.id()).unwrap().total(), 2);
+        let after = persistence.get_snapshot().await.unwrap();
+        assert_eq!(after.ranking()[1].task(), before.ranking()[1].task());
+        let result = engine.compact_votes(&persistence, TEST_USER_ID).await;
+        assert_eq!(result.err().unwrap().code(), ErrorCode::PermissionDenied);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_async_concurrent_votes() {
+        let (persistence, t0, t1) = init().await;
+        let mut handles = Vec::new();
+        for _ in 0..4 {
+            let persistence = persistence.clone();
+            let (t0, t1) = (t0.clone(), t1.clone());
+            handles.push(tokio::spawn(async move {
+                AsyncEngine::default()
+                    .answer_question(&persistence, TEST_ADMIN_ID, &t0, &t1, Outcome::Draw, None)
+                    .await
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+        assert_eq!(persistence.list_votes().await.unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,817 @@
+//! A test suite that every `Persistence` implementation is expected to pass.
+//!
+//! Each `check_*` function takes a fresh, empty backend and panics on the
+//! first behaviour that differs from the contract of the trait. The
+//! `persistence_conformance_tests!` macro turns all of them into `#[test]`
+//! functions for a given backend:
+//!
+//! ```ignore
+//! mod conformance {
+//!     pelo::persistence_conformance_tests!(MyPersistence::new());
+//! }
+//! ```
+
+use chrono::{DateTime, TimeDelta, Utc};
+use url::Url;
+use uuid::Uuid;
+
+use crate::data::{PairTally, Rating, Retraction, Role, Task, User, Vote, VoteArchive};
+use crate::elo::Outcome;
+use crate::errors::{Error, ErrorCode};
+use crate::persistence::Persistence;
+use crate::query::{TaskOrder, TaskQuery, TaskStatus, UserQuery};
+
+const EPSILON: f32 = 0.000001;
+
+const CONFORMANCE_USER_ID: &str = "conformance_user";
+
+/// Generates one `#[test]` per conformance check. The expression is evaluated
+/// once per test and must produce a fresh, empty backend.
+#[macro_export]
+macro_rules! persistence_conformance_tests {
+    ($factory:expr) => {
+        #[test]
+        fn conformance_users() {
+            $crate::conformance::check_users($factory);
+        }
+        #[test]
+        fn conformance_tasks() {
+            $crate::conformance::check_tasks($factory);
+        }
+        #[test]
+        fn conformance_snapshot() {
+            $crate::conformance::check_snapshot($factory);
+        }
+        #[test]
+        fn conformance_votes() {
+            $crate::conformance::check_votes($factory);
+        }
+        #[test]
+        fn conformance_retractions() {
+            $crate::conformance::check_retractions($factory);
+        }
+        #[test]
+        fn conformance_upsert_keeps_rating() {
+            $crate::conformance::check_upsert_keeps_rating($factory);
+        }
+        #[test]
+        fn conformance_unrelated_votes() {
+            $crate::conformance::check_unrelated_votes($factory);
+        }
+        #[test]
+        fn conformance_rating_history() {
+            $crate::conformance::check_rating_history($factory);
+        }
+        #[test]
+        fn conformance_queries() {
+            $crate::conformance::check_queries($factory);
+        }
+        #[test]
+        fn conformance_etag_token() {
+            $crate::conformance::check_etag_token($factory);
+        }
+        #[test]
+        fn conformance_transactions() {
+            $crate::conformance::check_transactions($factory);
+        }
+        #[test]
+        fn conformance_vote_compaction() {
+            $crate::conformance::check_vote_compaction($factory);
+        }
+    };
+}
+
+/// Runs every check, creating a new backend for each of them.
+pub fn check_all<P: Persistence, F: Fn() -> P>(factory: F) {
+    check_users(factory());
+    check_tasks(factory());
+    check_snapshot(factory());
+    check_votes(factory());
+    check_retractions(factory());
+    check_upsert_keeps_rating(factory());
+    check_unrelated_votes(factory());
+    check_rating_history(factory());
+    check_queries(factory());
+    check_etag_token(factory());
+    check_transactions(factory());
+    check_vote_compaction(factory());
+}
+
+pub fn check_users<P: Persistence>(mut p: P) {
+    assert!(p.list_users().unwrap().is_empty());
+    assert_code(p.get_user(CONFORMANCE_USER_ID), ErrorCode::UserNotFound);
+
+    p.upsert_user(&User::new(CONFORMANCE_USER_ID, 2)).unwrap();
+    let user = p.get_user(CONFORMANCE_USER_ID).unwrap();
+    assert_eq!(user.id(), CONFORMANCE_USER_ID);
+    assert_eq!(user.limit_votes_per_week(), 2);
+    assert_eq!(user.role(), Role::Voter);
+
+    p.upsert_user(&User::with_role(CONFORMANCE_USER_ID, -1, Role::Admin))
+        .unwrap();
+    let users = p.list_users().unwrap();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].role(), Role::Admin);
+    assert!(!users[0].is_limited());
+}
+
+pub fn check_tasks<P: Persistence>(mut p: P) {
+    assert!(p.list_tasks().unwrap().is_empty());
+    let t0 = new_task("task zero");
+    let t1 = new_task("task one");
+    p.upsert_task(&t0, CONFORMANCE_USER_ID).unwrap();
+    p.upsert_task(&t1, CONFORMANCE_USER_ID).unwrap();
+
+    let tasks = p.list_tasks().unwrap();
+    assert_eq!(tasks.len(), 2);
+    assert!(tasks.iter().all(|t| t.version() == 1 && !t.closed()));
+
+    // Storing the same content again does not create a version.
+    p.upsert_task(&t0, CONFORMANCE_USER_ID).unwrap();
+    let edited = Task::new(*t0.id(), "edited", t0.link().clone(), false);
+    p.upsert_task(&edited, "editor").unwrap();
+    let versions = p.list_task_versions(t0.id()).unwrap();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0].version(), 1);
+    assert_eq!(versions[0].author(), CONFORMANCE_USER_ID);
+    assert_eq!(versions[1].version(), 2);
+    assert_eq!(versions[1].author(), "editor");
+    assert_eq!(versions[1].summary(), "edited");
+    assert!(p.list_task_versions(&Uuid::new_v4()).unwrap().is_empty());
+
+    p.close_task(t1.id()).unwrap();
+    let tasks = p.list_tasks().unwrap();
+    let closed = tasks.iter().find(|t| t.id() == t1.id()).unwrap();
+    assert!(closed.closed());
+    let renamed = tasks.iter().find(|t| t.id() == t0.id()).unwrap();
+    assert_eq!(renamed.summary(), "edited");
+    assert_eq!(renamed.version(), 2);
+    let read = p.get_task(t0.id()).unwrap();
+    assert_eq!(read.summary(), "edited");
+    assert_eq!(read.version(), 2);
+    assert!(p.get_task(t1.id()).unwrap().closed());
+    assert_code(p.get_task(&Uuid::new_v4()), ErrorCode::TaskNotFound);
+    assert_code(p.close_task(&Uuid::new_v4()), ErrorCode::TaskNotFound);
+}
+
+pub fn check_snapshot<P: Persistence>(mut p: P) {
+    assert!(p.get_snapshot().unwrap().ranking().is_empty());
+    let (t0, t1) = setup_tasks(&mut p);
+
+    let snapshot0 = p.get_snapshot().unwrap();
+    assert_eq!(snapshot0.ranking().len(), 2);
+    for rating in snapshot0.ranking() {
+        assert!((rating.elo() - 1200.0).abs() < EPSILON);
+    }
+
+    add_vote(&mut p, &t0, &t1, Outcome::P0Win, now());
+    let snapshot1 = p.get_snapshot().unwrap();
+    assert_ne!(snapshot1.etag().token, snapshot0.etag().token);
+    // The ranking is sorted from the lowest to the highest rating.
+    let ranking = snapshot1.ranking();
+    assert_eq!(ranking.len(), 2);
+    assert_eq!(ranking[0].task(), t1.id());
+    assert_eq!(ranking[1].task(), t0.id());
+    assert!((ranking[0].elo() - 1184.0).abs() < EPSILON);
+    assert!((ranking[1].elo() - 1216.0).abs() < EPSILON);
+}
+
+pub fn check_votes<P: Persistence>(mut p: P) {
+    let (t0, t1) = setup_tasks(&mut p);
+    let start = now();
+    let earlier = start - TimeDelta::minutes(10);
+
+    let stale = p.get_snapshot().unwrap();
+    let first = add_vote(&mut p, &t0, &t1, Outcome::P1Win, start);
+    let mut second = Vote::new(
+        CONFORMANCE_USER_ID,
+        earlier,
+        *t1.id(),
+        *t0.id(),
+        Outcome::Draw,
+    );
+    second.set_comment(Some("too close to call"));
+    second.set_task_versions(1, 1);
+    let snapshot = p.get_snapshot().unwrap();
+    p.add_vote_and_update_ratings(
+        snapshot.etag(),
+        &second,
+        &Rating::with_elo(*t1.id(), 1216.0),
+        &Rating::with_elo(*t0.id(), 1184.0),
+    )
+    .unwrap();
+
+    // A stale etag is rejected, and so is a vote on an unknown task.
+    let result = p.add_vote_and_update_ratings(
+        stale.etag(),
+        &Vote::new(
+            CONFORMANCE_USER_ID,
+            start,
+            *t0.id(),
+            *t1.id(),
+            Outcome::P0Win,
+        ),
+        &Rating::new(*t0.id()),
+        &Rating::new(*t1.id()),
+    );
+    assert_code(result, ErrorCode::OptimisticConcurrencyRetryTransaction);
+    let unknown = Uuid::new_v4();
+    let snapshot = p.get_snapshot().unwrap();
+    let result = p.add_vote_and_update_ratings(
+        snapshot.etag(),
+        &Vote::new(
+            CONFORMANCE_USER_ID,
+            start,
+            *t0.id(),
+            unknown,
+            Outcome::P0Win,
+        ),
+        &Rating::new(*t0.id()),
+        &Rating::new(unknown),
+    );
+    assert_code(result, ErrorCode::TaskNotFound);
+    assert_eq!(
+        p.get_snapshot().unwrap().etag().token,
+        snapshot.etag().token
+    );
+
+    // Votes are listed in time order.
+    let votes = p.list_votes().unwrap();
+    assert_eq!(votes.len(), 2);
+    assert_eq!(votes[0].id(), second.id());
+    assert_eq!(votes[1].id(), first.id());
+    assert_eq!(p.list_votes_for_task(t0.id()).unwrap().len(), 2);
+    assert!(p.list_votes_for_task(&unknown).unwrap().is_empty());
+
+    let stored = p.get_vote(second.id()).unwrap();
+    assert_eq!(stored.voter(), CONFORMANCE_USER_ID);
+    assert_eq!(stored.time(), second.time());
+    assert!(matches!(stored.outcome(), Outcome::Draw));
+    assert_eq!(stored.comment(), Some("too close to call"));
+    assert_eq!(stored.task_versions(), (1, 1));
+    assert_code(p.get_vote(&unknown), ErrorCode::VoteNotFound);
+
+    // The lower bound of the time range is inclusive.
+    let count = p
+        .get_num_votes_for_user_since(CONFORMANCE_USER_ID, &earlier)
+        .unwrap();
+    assert_eq!(count, 2);
+    let count = p
+        .get_num_votes_for_user_since(CONFORMANCE_USER_ID, &start)
+        .unwrap();
+    assert_eq!(count, 1);
+    let votes = p
+        .list_votes_for_user_since(CONFORMANCE_USER_ID, &start)
+        .unwrap();
+    assert_eq!(votes.len(), 1);
+    assert_eq!(votes[0].id(), first.id());
+    assert_eq!(
+        p.get_num_votes_for_user_since("someone_else", &earlier)
+            .unwrap(),
+        0
+    );
+}
+
+pub fn check_retractions<P: Persistence>(mut p: P) {
+    let (t0, t1) = setup_tasks(&mut p);
+    let vote = add_vote(&mut p, &t0, &t1, Outcome::P1Win, now());
+    let replacement = Vote::new(
+        CONFORMANCE_USER_ID,
+        *vote.time(),
+        *t0.id(),
+        *t1.id(),
+        Outcome::P0Win,
+    );
+    let retraction = Retraction::new(
+        *vote.id(),
+        CONFORMANCE_USER_ID,
+        now(),
+        Some(*replacement.id()),
+    );
+    let ratings = [
+        Rating::with_elo(*t0.id(), 1216.0),
+        Rating::with_elo(*t1.id(), 1184.0),
+    ];
+
+    let stale = p.get_snapshot().unwrap();
+    add_vote(&mut p, &t0, &t1, Outcome::Draw, now());
+    let result =
+        p.retract_vote_and_replace_ratings(stale.etag(), &retraction, Some(&replacement), &ratings);
+    assert_code(result, ErrorCode::OptimisticConcurrencyRetryTransaction);
+
+    let snapshot = p.get_snapshot().unwrap();
+    p.retract_vote_and_replace_ratings(snapshot.etag(), &retraction, Some(&replacement), &ratings)
+        .unwrap();
+    assert_ne!(
+        p.get_snapshot().unwrap().etag().token,
+        snapshot.etag().token
+    );
+    assert_eq!(p.list_votes().unwrap().len(), 3);
+    let retractions = p.list_retractions().unwrap();
+    assert_eq!(retractions.len(), 1);
+    assert_eq!(retractions[0].vote(), vote.id());
+    assert_eq!(retractions[0].replacement(), Some(replacement.id()));
+
+    // The lower bound of the time range is inclusive.
+    let since = *retractions[0].time();
+    let listed = p
+        .list_retractions_for_user_since(CONFORMANCE_USER_ID, &since)
+        .unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].vote(), vote.id());
+    let later = since + TimeDelta::seconds(1);
+    assert!(p
+        .list_retractions_for_user_since(CONFORMANCE_USER_ID, &later)
+        .unwrap()
+        .is_empty());
+    assert!(p
+        .list_retractions_for_user_since("someone_else", &since)
+        .unwrap()
+        .is_empty());
+    for rating in p.get_snapshot().unwrap().ranking() {
+        let expected = if rating.task() == t0.id() {
+            1216.0
+        } else {
+            1184.0
+        };
+        assert!((rating.elo() - expected).abs() < EPSILON);
+    }
+
+    let snapshot = p.get_snapshot().unwrap();
+    let result = p.retract_vote_and_replace_ratings(snapshot.etag(), &retraction, None, &[]);
+    assert_code(result, ErrorCode::RetractionNotAllowed);
+    let missing = Retraction::new(Uuid::new_v4(), CONFORMANCE_USER_ID, now(), None);
+    let result = p.retract_vote_and_replace_ratings(snapshot.etag(), &missing, None, &[]);
+    assert_code(result, ErrorCode::VoteNotFound);
+}
+
+pub fn check_upsert_keeps_rating<P: Persistence>(mut p: P) {
+    let (t0, t1) = setup_tasks(&mut p);
+    add_vote(&mut p, &t0, &t1, Outcome::P0Win, now());
+
+    let renamed = Task::new(*t0.id(), "renamed", t0.link().clone(), false);
+    p.upsert_task(&renamed, CONFORMANCE_USER_ID).unwrap();
+    p.upsert_task(&renamed, CONFORMANCE_USER_ID).unwrap();
+
+    let ranking = p.get_snapshot().unwrap().ranking().clone();
+    assert_eq!(ranking.len(), 2);
+    let r0 = ranking.iter().find(|r| r.task() == t0.id()).unwrap();
+    assert!((r0.elo() - 1216.0).abs() < EPSILON);
+}
+
+fn now() -> DateTime<Utc> {
+    std::time::SystemTime::now().into()
+}
+
+pub fn check_unrelated_votes<P: Persistence>(mut p: P) {
+    let (t0, t1) = setup_tasks(&mut p);
+    let (t2, t3) = (new_task("task two"), new_task("task three"));
+    p.upsert_task(&t2, CONFORMANCE_USER_ID).unwrap();
+    p.upsert_task(&t3, CONFORMANCE_USER_ID).unwrap();
+    let start = now();
+    let vote = |t0: &Task, t1: &Task| {
+        Vote::new(
+            CONFORMANCE_USER_ID,
+            start,
+            *t0.id(),
+            *t1.id(),
+            Outcome::P0Win,
+        )
+    };
+
+    // Both votes are based on the same snapshot, but involve different tasks.
+    let snapshot = p.get_snapshot().unwrap();
+    for (t0, t1) in [(&t0, &t1), (&t2, &t3)] {
+        p.add_vote_and_update_ratings(
+            snapshot.etag(),
+            &vote(t0, t1),
+            &Rating::with_elo(*t0.id(), 1216.0),
+            &Rating::with_elo(*t1.id(), 1184.0),
+        )
+        .unwrap();
+    }
+    assert_ne!(
+        p.get_snapshot().unwrap().etag().token,
+        snapshot.etag().token
+    );
+    assert_eq!(p.list_votes().unwrap().len(), 2);
+
+    // A vote on a task whose rating changed since the snapshot is rejected.
+    let result = p.add_vote_and_update_ratings(
+        snapshot.etag(),
+        &vote(&t1, &t2),
+        &Rating::with_elo(*t1.id(), 1216.0),
+        &Rating::with_elo(*t2.id(), 1184.0),
+    );
+    assert_code(result, ErrorCode::OptimisticConcurrencyRetryTransaction);
+    assert_eq!(p.list_votes().unwrap().len(), 2);
+}
+
+pub fn check_rating_history<P: Persistence>(mut p: P) {
+    let (t0, t1) = setup_tasks(&mut p);
+    let created = now();
+    assert!(p
+        .get_ranking_at(&(created - TimeDelta::hours(1)))
+        .unwrap()
+        .is_empty());
+    std::thread::sleep(std::time::Duration::from_millis(10));
+
+    let first = add_vote(&mut p, &t0, &t1, Outcome::P0Win, now());
+    let second = add_vote(&mut p, &t0, &t1, Outcome::P0Win, now());
+    let changes = p.list_rating_changes(t0.id()).unwrap();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].vote(), first.id());
+    assert!((changes[0].old_elo() - 1200.0).abs() < EPSILON);
+    assert!((changes[0].new_elo() - 1216.0).abs() < EPSILON);
+    assert_eq!(changes[1].vote(), second.id());
+    assert!((changes[1].new_elo() - 1232.0).abs() < EPSILON);
+    assert!(changes.iter().all(|c| !c.is_retraction()));
+    assert_eq!(p.list_rating_changes(t1.id()).unwrap().len(), 2);
+    assert!(p.list_rating_changes(&Uuid::new_v4()).unwrap().is_empty());
+
+    let ranking = p.get_ranking_at(&created).unwrap();
+    assert_eq!(ranking.len(), 2);
+    assert!(ranking.iter().all(|r| (r.elo() - 1200.0).abs() < EPSILON));
+    let ranking = p.get_ranking_at(changes[0].time()).unwrap();
+    assert_eq!(ranking[0].task(), t1.id());
+    assert_eq!(ranking[1].task(), t0.id());
+    assert!((ranking[1].elo() - 1216.0).abs() < EPSILON);
+
+    // A retraction records the ratings it changes, and leaves the past alone.
+    let snapshot = p.get_snapshot().unwrap();
+    let retraction = Retraction::new(*first.id(), CONFORMANCE_USER_ID, now(), None);
+    p.retract_vote_and_replace_ratings(
+        snapshot.etag(),
+        &retraction,
+        None,
+        &[
+            Rating::with_elo(*t0.id(), 1216.0),
+            Rating::with_elo(*t1.id(), 1184.0),
+        ],
+    )
+    .unwrap();
+    let changes = p.list_rating_changes(t0.id()).unwrap();
+    assert_eq!(changes.len(), 3);
+    assert!(changes[2].is_retraction());
+    assert_eq!(changes[2].vote(), first.id());
+    assert!((changes[2].old_elo() - 1232.0).abs() < EPSILON);
+    assert!((changes[2].new_elo() - 1216.0).abs() < EPSILON);
+    let ranking = p.get_ranking_at(changes[1].time()).unwrap();
+    assert!((ranking[1].elo() - 1232.0).abs() < EPSILON);
+    let ranking = p.get_ranking_at(&now()).unwrap();
+    assert!((ranking[1].elo() - 1216.0).abs() < EPSILON);
+}
+
+pub fn check_queries<P: Persistence>(mut p: P) {
+    let mut alpha = new_task("Alpha task");
+    alpha.set_tags(&["x"]);
+    let mut beta = new_task("beta TASK 100%");
+    beta.set_tags(&["y", "x", "y"]);
+    beta.close();
+    let mut gamma = new_task("gamma");
+    gamma.set_tags(&["y"]);
+    for t in [&alpha, &beta, &gamma] {
+        p.upsert_task(t, CONFORMANCE_USER_ID).unwrap();
+    }
+    let summaries = |query: TaskQuery| -> Vec<String> {
+        p.query_tasks(&query)
+            .unwrap()
+            .iter()
+            .map(|t| t.summary().to_string())
+            .collect()
+    };
+    let by_summary = TaskQuery::new().with_order(TaskOrder::Summary, false);
+
+    assert_eq!(
+        summaries(by_summary.clone()),
+        ["Alpha task", "beta TASK 100%", "gamma"]
+    );
+    assert_eq!(
+        summaries(by_summary.clone().with_status(TaskStatus::Open)),
+        ["Alpha task", "gamma"]
+    );
+    assert_eq!(
+        summaries(by_summary.clone().with_status(TaskStatus::Closed)),
+        ["beta TASK 100%"]
+    );
+    assert_eq!(
+        summaries(by_summary.clone().with_tag("x")),
+        ["Alpha task", "beta TASK 100%"]
+    );
+    assert_eq!(
+        summaries(by_summary.clone().with_tag("x").with_tag("y")),
+        ["beta TASK 100%"]
+    );
+    assert_eq!(
+        summaries(by_summary.clone().with_text("task")),
+        ["Alpha task", "beta TASK 100%"]
+    );
+    assert_eq!(
+        summaries(by_summary.clone().with_text("0%")),
+        ["beta TASK 100%"]
+    );
+    assert!(summaries(by_summary.clone().with_text("a_t")).is_empty());
+    assert_eq!(
+        summaries(
+            TaskQuery::new()
+                .with_order(TaskOrder::Summary, true)
+                .with_offset(1)
+                .with_limit(1)
+        ),
+        ["beta TASK 100%"]
+    );
+    assert_eq!(summaries(by_summary.clone().with_offset(2)), ["gamma"]);
+    let query = TaskQuery::new().with_tag("y").with_limit(1);
+    assert_eq!(p.count_tasks(&query).unwrap(), 2);
+    assert_eq!(p.count_tasks(&TaskQuery::new()).unwrap(), 3);
+
+    // Tags are stored with the task, and replaced when it is upserted.
+    let ids: Vec<Uuid> = p
+        .query_tasks(&TaskQuery::new())
+        .unwrap()
+        .iter()
+        .map(|t| *t.id())
+        .collect();
+    let mut sorted = ids.clone();
+    sorted.sort();
+    assert_eq!(ids, sorted);
+    let stored = p.query_tasks(&TaskQuery::new().with_tag("y")).unwrap();
+    let stored = stored.iter().find(|t| t.id() == beta.id()).unwrap();
+    assert_eq!(stored.tags(), ["x", "y"]);
+    beta.set_tags(&["z"]);
+    p.upsert_task(&beta, CONFORMANCE_USER_ID).unwrap();
+    assert_eq!(p.count_tasks(&TaskQuery::new().with_tag("x")).unwrap(), 1);
+
+    p.upsert_user(&User::with_role("admin", -1, Role::Admin))
+        .unwrap();
+    p.upsert_user(&User::new("Voter one", 2)).unwrap();
+    p.upsert_user(&User::new("voter two", 2)).unwrap();
+    let ids = |query: UserQuery| -> Vec<String> {
+        p.query_users(&query)
+            .unwrap()
+            .iter()
+            .map(|u| u.id().to_string())
+            .collect()
+    };
+    assert_eq!(ids(UserQuery::new()), ["Voter one", "admin", "voter two"]);
+    assert_eq!(ids(UserQuery::new().with_role(Role::Admin)), ["admin"]);
+    assert_eq!(
+        ids(UserQuery::new().with_text("VOTER")),
+        ["Voter one", "voter two"]
+    );
+    assert_eq!(
+        ids(UserQuery::new().with_offset(1).with_limit(1)),
+        ["admin"]
+    );
+}
+
+pub fn check_etag_token<P: Persistence>(mut p: P) {
+    let mut token = p.get_etag_token().unwrap();
+    let mut assert_changed = |p: &mut P| {
+        let next = p.get_etag_token().unwrap();
+        assert_ne!(next, token);
+        assert_eq!(p.get_etag_token().unwrap(), next);
+        assert_eq!(p.get_snapshot().unwrap().etag().token, next);
+        token = next;
+    };
+
+    p.upsert_user(&User::new(CONFORMANCE_USER_ID, -1)).unwrap();
+    assert_changed(&mut p);
+    let t0 = new_task("task zero");
+    let t1 = new_task("task one");
+    p.upsert_task(&t0, CONFORMANCE_USER_ID).unwrap();
+    assert_changed(&mut p);
+    p.upsert_task(&t1, CONFORMANCE_USER_ID).unwrap();
+    assert_changed(&mut p);
+    add_vote(&mut p, &t0, &t1, Outcome::P0Win, now());
+    assert_changed(&mut p);
+    p.close_task(t1.id()).unwrap();
+    assert_changed(&mut p);
+}
+
+/// Backends that do not support transactions must fail to begin one with
+/// `NotImplemented`, and are not checked further.
+pub fn check_transactions<P: Persistence>(mut p: P) {
+    let (t0, t1) = setup_tasks(&mut p);
+    if let Err(e) = p.begin_transaction() {
+        assert_eq!(e.code(), ErrorCode::NotImplemented);
+        assert_code(p.transaction(|_| Ok(())), ErrorCode::NotImplemented);
+        return;
+    }
+    assert_code(p.begin_transaction(), ErrorCode::GenericError);
+    let t2 = new_task("task two");
+    p.upsert_task(&t2, CONFORMANCE_USER_ID).unwrap();
+    add_vote(&mut p, &t0, &t2, Outcome::P0Win, now());
+    // Reads see the writes of the transaction in progress.
+    assert_eq!(p.list_tasks().unwrap().len(), 3);
+    p.commit_transaction().unwrap();
+    assert_code(p.commit_transaction(), ErrorCode::GenericError);
+    assert_code(p.rollback_transaction(), ErrorCode::GenericError);
+    assert_eq!(p.list_tasks().unwrap().len(), 3);
+    assert_eq!(p.list_votes().unwrap().len(), 1);
+
+    let before = p.get_snapshot().unwrap();
+    p.begin_transaction().unwrap();
+    p.upsert_task(&new_task("task three"), CONFORMANCE_USER_ID)
+        .unwrap();
+    add_vote(&mut p, &t0, &t1, Outcome::P1Win, now());
+    p.close_task(t2.id()).unwrap();
+    p.rollback_transaction().unwrap();
+    let after = p.get_snapshot().unwrap();
+    assert_eq!(after.ranking().len(), 3);
+    for rating in after.ranking() {
+        let old = before
+            .ranking()
+            .iter()
+            .find(|r| r.task() == rating.task())
+            .unwrap();
+        assert!((rating.elo() - old.elo()).abs() < EPSILON);
+    }
+    assert_eq!(p.list_votes().unwrap().len(), 1);
+    assert!(p.list_tasks().unwrap().iter().all(|t| !t.closed()));
+
+    let result = p.transaction(|p| {
+        p.close_task(t2.id())?;
+        p.close_task(&Uuid::new_v4())
+    });
+    assert_code(result, ErrorCode::TaskNotFound);
+    assert!(p.list_tasks().unwrap().iter().all(|t| !t.closed()));
+    let closed = p.transaction(|p| {
+        p.close_task(t2.id())?;
+        Ok(*t2.id())
+    });
+    assert_eq!(closed.unwrap(), *t2.id());
+    let tasks = p.list_tasks().unwrap();
+    assert!(tasks.iter().find(|t| t.id() == t2.id()).unwrap().closed());
+    add_vote(&mut p, &t0, &t1, Outcome::Draw, now());
+    assert_eq!(p.list_votes().unwrap().len(), 2);
+}
+
+/// Backends that do not support vote compaction must fail with
+/// `NotImplemented`, and are not checked further. They must still return an
+/// empty archive.
+pub fn check_vote_compaction<P: Persistence>(mut p: P) {
+    let (t0, t1) = setup_tasks(&mut p);
+    let start = now() - TimeDelta::hours(2);
+    assert!(p.get_vote_archive().unwrap().cutoff().is_none());
+    let etag = p.get_snapshot().unwrap().etag().clone();
+    match p.compact_votes(&etag, &VoteArchive::new()) {
+        Err(e) if e.code() == ErrorCode::NotImplemented => return,
+        // An archive without a cutoff cannot cover any vote.
+        result => assert_code(result, ErrorCode::GenericError),
+    }
+
+    let old = add_vote(&mut p, &t0, &t1, Outcome::P0Win, start);
+    let retracted = add_vote(
+        &mut p,
+        &t1,
+        &t0,
+        Outcome::P0Win,
+        start + TimeDelta::minutes(1),
+    );
+    let snapshot = p.get_snapshot().unwrap();
+    let retraction = Retraction::new(*retracted.id(), CONFORMANCE_USER_ID, now(), None);
+    p.retract_vote_and_replace_ratings(
+        snapshot.etag(),
+        &retraction,
+        None,
+        &[
+            Rating::with_elo(*t0.id(), 1216.0),
+            Rating::with_elo(*t1.id(), 1184.0),
+        ],
+    )
+    .unwrap();
+    let recent = add_vote(&mut p, &t0, &t1, Outcome::Draw, now());
+    let recent_retraction = Retraction::new(*recent.id(), CONFORMANCE_USER_ID, now(), None);
+    let snapshot = p.get_snapshot().unwrap();
+    p.retract_vote_and_replace_ratings(snapshot.etag(), &recent_retraction, None, &[])
+        .unwrap();
+
+    let cutoff = start + TimeDelta::hours(1);
+    let archive = VoteArchive::with_contents(
+        cutoff,
+        vec![
+            Rating::with_elo(*t0.id(), 1216.0),
+            Rating::with_elo(*t1.id(), 1184.0),
+        ],
+        vec![PairTally::with_counts(*t0.id(), *t1.id(), 1, 0, 0, 1)],
+        1,
+    );
+    let result = p.compact_votes(snapshot.etag(), &archive);
+    assert_code(result, ErrorCode::OptimisticConcurrencyRetryTransaction);
+    assert_eq!(p.list_votes().unwrap().len(), 3);
+
+    let before = p.get_snapshot().unwrap();
+    p.compact_votes(before.etag(), &archive).unwrap();
+    let after = p.get_snapshot().unwrap();
+    assert_ne!(after.etag().token, before.etag().token);
+    for t in [&t0, &t1] {
+        assert!(after.etag().revision(t.id()) >= before.etag().revision(t.id()));
+    }
+    assert_eq!(after.ranking().len(), 2);
+    for rating in after.ranking() {
+        let old = before
+            .ranking()
+            .iter()
+            .find(|r| r.task() == rating.task())
+            .unwrap();
+        assert!((rating.elo() - old.elo()).abs() < EPSILON);
+    }
+
+    // The votes before the cutoff are gone with their retractions; the later
+    // ones stay.
+    let votes = p.list_votes().unwrap();
+    assert_eq!(votes.len(), 1);
+    assert_eq!(votes[0].id(), recent.id());
+    assert_code(p.get_vote(old.id()), ErrorCode::VoteNotFound);
+    let retractions = p.list_retractions().unwrap();
+    assert_eq!(retractions.len(), 1);
+    assert_eq!(retractions[0].vote(), recent.id());
+    assert_eq!(
+        p.get_num_votes_for_user_since(CONFORMANCE_USER_ID, &start)
+            .unwrap(),
+        1
+    );
+
+    let stored = p.get_vote_archive().unwrap();
+    assert_eq!(stored.cutoff(), Some(&cutoff));
+    assert_eq!(stored.retractions(), 1);
+    assert_eq!(stored.tallies(), archive.tallies());
+    assert!((stored.rating(t0.id()).elo() - 1216.0).abs() < EPSILON);
+    assert!((stored.rating(&Uuid::new_v4()).elo() - 1200.0).abs() < EPSILON);
+
+    // A later compaction replaces the archive.
+    let archive = VoteArchive::with_contents(
+        now(),
+        vec![],
+        vec![PairTally::with_counts(*t0.id(), *t1.id(), 1, 1, 0, 2)],
+        2,
+    );
+    let snapshot = p.get_snapshot().unwrap();
+    p.compact_votes(snapshot.etag(), &archive).unwrap();
+    assert!(p.list_votes().unwrap().is_empty());
+    assert!(p.list_retractions().unwrap().is_empty());
+    let stored = p.get_vote_archive().unwrap();
+    assert_eq!(stored.tallies(), archive.tallies());
+    assert!(stored.ratings().is_empty());
+}
+
+fn new_task(summary: &str) -> Task {
+    let id = Uuid::new_v4();
+    Task::new(
+        id,
+        summary,
+        Url::parse(&format!("https://localhost/{}", id)).unwrap(),
+        false,
+    )
+}
+
+fn setup_tasks<P: Persistence>(p: &mut P) -> (Task, Task) {
+    p.upsert_user(&User::new(CONFORMANCE_USER_ID, -1)).unwrap();
+    let t0 = new_task("task zero");
+    let t1 = new_task("task one");
+    p.upsert_task(&t0, CONFORMANCE_USER_ID).unwrap();
+    p.upsert_task(&t1, CONFORMANCE_USER_ID).unwrap();
+    (t0, t1)
+}
+
+// Adds a vote that moves the two ratings by 16 points from the current ones,
+// or leaves them unchanged for a draw.
+fn add_vote<P: Persistence>(
+    p: &mut P,
+    t0: &Task,
+    t1: &Task,
+    outcome: Outcome,
+    time: DateTime<Utc>,
+) -> Vote {
+    let snapshot = p.get_snapshot().unwrap();
+    let elo = |t: &Task| {
+        snapshot
+            .ranking()
+            .iter()
+            .find(|r| r.task() == t.id())
+            .unwrap()
+            .elo()
+    };
+    let delta = match outcome {
+        Outcome::P0Win => 16.0,
+        Outcome::P1Win => -16.0,
+        Outcome::Draw => 0.0,
+    };
+    let vote = Vote::new(CONFORMANCE_USER_ID, time, *t0.id(), *t1.id(), outcome);
+    p.add_vote_and_update_ratings(
+        snapshot.etag(),
+        &vote,
+        &Rating::with_elo(*t0.id(), elo(t0) + delta),
+        &Rating::with_elo(*t1.id(), elo(t1) - delta),
+    )
+    .unwrap();
+    vote
+}
+
+fn assert_code<T: std::fmt::Debug>(result: Result<T, Error>, code: ErrorCode) {
+    match result {
+        Ok(value) => panic!("expected {}, got Ok({:?})", code, value),
+        Err(e) => assert_eq!(e.code(), code, "unexpected error: {}", e.msg()),
+    }
+}
@@ -4,19 +4,86 @@ use url::Url;
 use uuid::Uuid;
 
 use crate::elo::Outcome;
+use crate::errors::Error;
+use crate::limits::LimitPolicy;
 
 use std::fmt;
+use std::str::FromStr;
+
+/// The role of a user determines which `Engine` operations they may perform.
+///
+/// - `Admin` can do everything, including managing users and tasks.
+/// - `Voter` can vote and read the ranking.
+/// - `Viewer` can only read the ranking.
+///
+/// Users stored before roles existed are voters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Role {
+    Admin,
+    #[default]
+    Voter,
+    Viewer,
+}
+impl Role {
+    pub fn can_manage_users(&self) -> bool {
+        matches!(self, Role::Admin)
+    }
+    pub fn can_manage_tasks(&self) -> bool {
+        matches!(self, Role::Admin)
+    }
+    pub fn can_vote(&self) -> bool {
+        matches!(self, Role::Admin | Role::Voter)
+    }
+    pub fn can_view(&self) -> bool {
+        true
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Voter => "voter",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "voter" => Ok(Role::Voter),
+            "viewer" => Ok(Role::Viewer),
+            _ => Err(Error::generic(&format!("unknown role '{}'", s))),
+        }
+    }
+}
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     id: String,
     limit_votes_per_week: i32,
+    #[serde(default)]
+    role: Role,
+    #[serde(default)]
+    limit_policy: Option<LimitPolicy>,
 }
 impl User {
     pub fn new(id: &str, limit_votes_per_week: i32) -> Self {
+        User::with_role(id, limit_votes_per_week, Role::Voter)
+    }
+
+    pub fn with_role(id: &str, limit_votes_per_week: i32, role: Role) -> Self {
         User {
             id: id.to_string(),
-            limit_votes_per_week: limit_votes_per_week,
+            limit_votes_per_week,
+            role,
+            limit_policy: None,
         }
     }
 
@@ -26,18 +93,34 @@ impl User {
     pub fn limit_votes_per_week(&self) -> i32 {
         self.limit_votes_per_week
     }
+    pub fn role(&self) -> Role {
+        self.role
+    }
     pub fn is_limited(&self) -> bool {
         self.limit_votes_per_week >= 0
     }
+    /// A user-specific limit policy. When set, it replaces both the weekly
+    /// limit and the default policy of the `Engine`.
+    pub fn limit_policy(&self) -> Option<&LimitPolicy> {
+        self.limit_policy.as_ref()
+    }
+    pub fn set_limit_policy(&mut self, policy: Option<LimitPolicy>) {
+        self.limit_policy = policy;
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SerializedVote")]
 pub struct Vote {
+    id: Uuid,
     voter: String,
     time: DateTime<Utc>,
     task0: Uuid,
     task1: Uuid,
     outcome: Outcome,
+    comment: Option<String>,
+    task0_version: u32,
+    task1_version: u32,
 }
 impl Vote {
     pub fn new(
@@ -46,16 +129,34 @@ impl Vote {
         task0: Uuid,
         task1: Uuid,
         outcome: Outcome,
+    ) -> Self {
+        Vote::with_id(Uuid::new_v4(), voter, time, task0, task1, outcome)
+    }
+
+    pub fn with_id(
+        id: Uuid,
+        voter: &str,
+        time: DateTime<Utc>,
+        task0: Uuid,
+        task1: Uuid,
+        outcome: Outcome,
     ) -> Self {
         Vote {
+            id,
             voter: voter.to_string(),
-            time: time,
-            task0: task0,
-            task1: task1,
-            outcome: outcome,
+            time,
+            task0,
+            task1,
+            outcome,
+            comment: None,
+            task0_version: 0,
+            task1_version: 0,
         }
     }
 
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
     pub fn voter(&self) -> &str {
         &self.voter
     }
@@ -71,6 +172,127 @@ impl Vote {
     pub fn outcome(&self) -> Outcome {
         self.outcome
     }
+    /// The rationale the voter gave for the outcome, if any.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+    pub fn set_comment(&mut self, comment: Option<&str>) {
+        self.comment = comment.map(|c| c.to_string());
+    }
+    /// The versions of the two tasks that the voter saw, or 0 if unknown.
+    pub fn task_versions(&self) -> (u32, u32) {
+        (self.task0_version, self.task1_version)
+    }
+    pub fn set_task_versions(&mut self, task0_version: u32, task1_version: u32) {
+        self.task0_version = task0_version;
+        self.task1_version = task1_version;
+    }
+    /// Tells whether the vote was in favour of the task (`Some(true)`),
+    /// against it (`Some(false)`), or neither because it was a draw or the
+    /// task was not part of the vote (`None`).
+    pub fn favours(&self, t_id: &Uuid) -> Option<bool> {
+        let winner = match self.outcome {
+            Outcome::P0Win => &self.task0,
+            Outcome::P1Win => &self.task1,
+            Outcome::Draw => return None,
+        };
+        if &self.task0 != t_id && &self.task1 != t_id {
+            return None;
+        }
+        Some(winner == t_id)
+    }
+}
+
+// A vote as it is serialized, including by versions of the library that did
+// not give votes an id.
+#[derive(Deserialize)]
+struct SerializedVote {
+    id: Option<Uuid>,
+    voter: String,
+    time: DateTime<Utc>,
+    task0: Uuid,
+    task1: Uuid,
+    outcome: Outcome,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default)]
+    task0_version: u32,
+    #[serde(default)]
+    task1_version: u32,
+}
+impl From<SerializedVote> for Vote {
+    fn from(v: SerializedVote) -> Self {
+        let id = v
+            .id
+            .unwrap_or_else(|| legacy_vote_id(&v.voter, &v.time, &v.task0, &v.task1));
+        Vote {
+            id,
+            voter: v.voter,
+            time: v.time,
+            task0: v.task0,
+            task1: v.task1,
+            outcome: v.outcome,
+            comment: v.comment,
+            task0_version: v.task0_version,
+            task1_version: v.task1_version,
+        }
+    }
+}
+
+// Votes serialized without an id are given one derived from their voter, time
+// and tasks, so that they get the same one every time they are read. The hash
+// is a 128-bit FNV-1a, which unlike the hashers of the standard library does
+// not change between releases.
+fn legacy_vote_id(voter: &str, time: &DateTime<Utc>, task0: &Uuid, task1: &Uuid) -> Uuid {
+    const FNV_OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+    let time = time.to_rfc3339();
+    let bytes = voter
+        .as_bytes()
+        .iter()
+        .chain(&[0])
+        .chain(time.as_bytes())
+        .chain(task0.as_bytes())
+        .chain(task1.as_bytes());
+    let hash = bytes.fold(FNV_OFFSET_BASIS, |hash, byte| {
+        (hash ^ *byte as u128).wrapping_mul(FNV_PRIME)
+    });
+    uuid::Builder::from_custom_bytes(hash.to_be_bytes()).into_uuid()
+}
+
+/// A compensating record that cancels a vote. Retracted votes are kept until
+/// they are compacted, but they no longer contribute to the ratings. When the
+/// retraction is part of a correction, `replacement` is the id of the vote
+/// that supersedes it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Retraction {
+    vote: Uuid,
+    voter: String,
+    time: DateTime<Utc>,
+    replacement: Option<Uuid>,
+}
+impl Retraction {
+    pub fn new(vote: Uuid, voter: &str, time: DateTime<Utc>, replacement: Option<Uuid>) -> Self {
+        Retraction {
+            vote,
+            voter: voter.to_string(),
+            time,
+            replacement,
+        }
+    }
+
+    pub fn vote(&self) -> &Uuid {
+        &self.vote
+    }
+    pub fn voter(&self) -> &str {
+        &self.voter
+    }
+    pub fn time(&self) -> &DateTime<Utc> {
+        &self.time
+    }
+    pub fn replacement(&self) -> Option<&Uuid> {
+        self.replacement.as_ref()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -79,20 +301,36 @@ pub struct Task {
     summary: String,
     link: Url,
     closed: bool,
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    tags: Vec<String>,
 }
 impl Task {
     pub fn new(id: Uuid, summary: &str, link: Url, closed: bool) -> Self {
+        Task::with_version(id, summary, link, closed, 0)
+    }
+
+    pub fn with_version(id: Uuid, summary: &str, link: Url, closed: bool, version: u32) -> Self {
         Task {
-            id: id,
+            id,
             summary: summary.to_string(),
-            link: link,
-            closed: closed,
+            link,
+            closed,
+            version,
+            tags: Vec::new(),
         }
     }
 
     pub fn id(&self) -> &Uuid {
         &self.id
     }
+    /// The version of the summary and link, as assigned by the persistence
+    /// layer when the task is stored. Tasks that were never stored have
+    /// version 0.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
     pub fn summary(&self) -> &str {
         &self.summary
     }
@@ -105,6 +343,15 @@ impl Task {
     pub fn close(&mut self) {
         self.closed = true;
     }
+    /// The tags of the task, sorted and without duplicates.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    pub fn set_tags<S: AsRef<str>>(&mut self, tags: &[S]) {
+        self.tags = tags.iter().map(|t| t.as_ref().to_string()).collect();
+        self.tags.sort();
+        self.tags.dedup();
+    }
 }
 impl fmt::Display for Task {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -119,6 +366,56 @@ impl fmt::Display for Task {
     }
 }
 
+/// A past or current content of a task, recorded every time its summary or
+/// link is edited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskVersion {
+    task: Uuid,
+    version: u32,
+    time: DateTime<Utc>,
+    author: String,
+    summary: String,
+    link: Url,
+}
+impl TaskVersion {
+    pub fn new(
+        task: Uuid,
+        version: u32,
+        time: DateTime<Utc>,
+        author: &str,
+        summary: &str,
+        link: Url,
+    ) -> Self {
+        TaskVersion {
+            task,
+            version,
+            time,
+            author: author.to_string(),
+            summary: summary.to_string(),
+            link,
+        }
+    }
+
+    pub fn task(&self) -> &Uuid {
+        &self.task
+    }
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    pub fn time(&self) -> &DateTime<Utc> {
+        &self.time
+    }
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+    pub fn summary(&self) -> &str {
+        &self.summary
+    }
+    pub fn link(&self) -> &Url {
+        &self.link
+    }
+}
+
 const DEFAULT_START_RATING: f32 = 1200.0;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -132,10 +429,7 @@ impl Rating {
     }
 
     pub fn with_elo(task: Uuid, elo: f32) -> Self {
-        Rating {
-            task: task,
-            elo: elo,
-        }
+        Rating { task, elo }
     }
 
     pub fn task(&self) -> &Uuid {
@@ -145,3 +439,272 @@ impl Rating {
         self.elo
     }
 }
+
+/// A change of the rating of a task. `vote` is the vote that was cast, or the
+/// one that was retracted or corrected if `is_retraction` is true; a
+/// retraction changes the ratings of every task whose history it affects.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RatingChange {
+    task: Uuid,
+    time: DateTime<Utc>,
+    old_elo: f32,
+    new_elo: f32,
+    vote: Uuid,
+    retraction: bool,
+}
+impl RatingChange {
+    pub fn new(
+        task: Uuid,
+        time: DateTime<Utc>,
+        old_elo: f32,
+        new_elo: f32,
+        vote: Uuid,
+        retraction: bool,
+    ) -> Self {
+        RatingChange {
+            task,
+            time,
+            old_elo,
+            new_elo,
+            vote,
+            retraction,
+        }
+    }
+
+    pub fn task(&self) -> &Uuid {
+        &self.task
+    }
+    pub fn time(&self) -> &DateTime<Utc> {
+        &self.time
+    }
+    pub fn old_elo(&self) -> f32 {
+        self.old_elo
+    }
+    pub fn new_elo(&self) -> f32 {
+        self.new_elo
+    }
+    pub fn vote(&self) -> &Uuid {
+        &self.vote
+    }
+    pub fn is_retraction(&self) -> bool {
+        self.retraction
+    }
+}
+
+/// The votes cast on a pair of tasks that were compacted into a
+/// `VoteArchive`: how often `task0` was found more important than `task1`
+/// (`wins`), as important, or less. `retracted` counts the votes that had
+/// been retracted, which are dropped without counting as any outcome.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairTally {
+    task0: Uuid,
+    task1: Uuid,
+    wins: u32,
+    draws: u32,
+    losses: u32,
+    retracted: u32,
+}
+impl PairTally {
+    pub fn new(task0: Uuid, task1: Uuid) -> Self {
+        PairTally::with_counts(task0, task1, 0, 0, 0, 0)
+    }
+
+    pub fn with_counts(
+        task0: Uuid,
+        task1: Uuid,
+        wins: u32,
+        draws: u32,
+        losses: u32,
+        retracted: u32,
+    ) -> Self {
+        PairTally {
+            task0,
+            task1,
+            wins,
+            draws,
+            losses,
+            retracted,
+        }
+    }
+
+    pub fn task0(&self) -> &Uuid {
+        &self.task0
+    }
+    pub fn task1(&self) -> &Uuid {
+        &self.task1
+    }
+    pub fn wins(&self) -> u32 {
+        self.wins
+    }
+    pub fn draws(&self) -> u32 {
+        self.draws
+    }
+    pub fn losses(&self) -> u32 {
+        self.losses
+    }
+    pub fn retracted(&self) -> u32 {
+        self.retracted
+    }
+    /// The number of votes on the pair, retracted ones included.
+    pub fn total(&self) -> u32 {
+        self.wins + self.draws + self.losses + self.retracted
+    }
+
+    // Counts a vote on the pair, whichever way round it was asked.
+    fn record(&mut self, vote: &Vote, retracted: bool) {
+        if retracted {
+            self.retracted += 1;
+            return;
+        }
+        match vote.favours(&self.task0) {
+            Some(true) => self.wins += 1,
+            None => self.draws += 1,
+            Some(false) => self.losses += 1,
+        }
+    }
+}
+
+/// What is left of the votes cast before `cutoff` once they are compacted:
+/// the rating every task had reached with them, which later votes are
+/// replayed from when ratings are recomputed, and a tally per pair of tasks.
+/// `retractions` counts the retractions of compacted votes, which are dropped
+/// with them.
+///
+/// An archive without a cutoff is empty: nothing was compacted yet.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VoteArchive {
+    cutoff: Option<DateTime<Utc>>,
+    ratings: Vec<Rating>,
+    tallies: Vec<PairTally>,
+    retractions: u32,
+}
+impl VoteArchive {
+    pub fn new() -> Self {
+        VoteArchive::default()
+    }
+
+    pub fn with_contents(
+        cutoff: DateTime<Utc>,
+        ratings: Vec<Rating>,
+        tallies: Vec<PairTally>,
+        retractions: u32,
+    ) -> Self {
+        VoteArchive {
+            cutoff: Some(cutoff),
+            ratings,
+            tallies,
+            retractions,
+        }
+    }
+
+    pub fn cutoff(&self) -> Option<&DateTime<Utc>> {
+        self.cutoff.as_ref()
+    }
+    pub fn ratings(&self) -> &[Rating] {
+        &self.ratings
+    }
+    pub fn tallies(&self) -> &[PairTally] {
+        &self.tallies
+    }
+    pub fn retractions(&self) -> u32 {
+        self.retractions
+    }
+
+    /// The rating a task had reached with the compacted votes, which is the
+    /// default rating if it took part in none.
+    pub fn rating(&self, t_id: &Uuid) -> Rating {
+        self.ratings
+            .iter()
+            .find(|r| r.task() == t_id)
+            .cloned()
+            .unwrap_or(Rating::new(*t_id))
+    }
+
+    /// The tally of a pair, in whichever order the tasks are given.
+    pub fn tally(&self, t0: &Uuid, t1: &Uuid) -> Option<&PairTally> {
+        self.tallies
+            .iter()
+            .find(|t| (t.task0() == t0 && t.task1() == t1) || (t.task0() == t1 && t.task1() == t0))
+    }
+
+    /// The number of compacted votes in which the task took part.
+    pub fn num_votes_for_task(&self, t_id: &Uuid) -> u64 {
+        self.tallies
+            .iter()
+            .filter(|t| t.task0() == t_id || t.task1() == t_id)
+            .map(|t| t.total() as u64)
+            .sum()
+    }
+
+    // Moves the cutoff forward, and replaces the ratings with the ones
+    // reached with the votes compacted up to it.
+    pub(crate) fn advance(&mut self, cutoff: DateTime<Utc>, ratings: Vec<Rating>) {
+        self.cutoff = Some(cutoff);
+        self.ratings = ratings;
+    }
+
+    // Counts a compacted vote in the tally of its pair, and its retraction
+    // if it was retracted.
+    pub(crate) fn record(&mut self, vote: &Vote, retracted: bool) {
+        let found = self.tallies.iter().position(|t| {
+            (t.task0() == vote.task0() && t.task1() == vote.task1())
+                || (t.task0() == vote.task1() && t.task1() == vote.task0())
+        });
+        let index = found.unwrap_or_else(|| {
+            // Pairs are stored in a stable order, whichever way round they
+            // were first asked.
+            let (t0, t1) = if vote.task0() < vote.task1() {
+                (*vote.task0(), *vote.task1())
+            } else {
+                (*vote.task1(), *vote.task0())
+            };
+            self.tallies.push(PairTally::new(t0, t1));
+            self.tallies.len() - 1
+        });
+        self.tallies[index].record(vote, retracted);
+        if retracted {
+            self.retractions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{Role, User, Vote};
+    use crate::elo::Outcome;
+
+    #[test]
+    fn test_deserialize_user_without_role() {
+        let user: User =
+            serde_json::from_str(r#"{"id":"test_user","limit_votes_per_week":2}"#).unwrap();
+        assert_eq!(user.id(), "test_user");
+        assert_eq!(user.limit_votes_per_week(), 2);
+        assert_eq!(user.role(), Role::Voter);
+        assert!(user.limit_policy().is_none());
+    }
+
+    #[test]
+    fn test_deserialize_vote_without_id() {
+        let json = r#"{
+            "voter": "test_user",
+            "time": "2023-06-10T12:00:00Z",
+            "task0": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "task1": "9f3c2a5e-8c4b-4d2a-9e1f-3b7a6c5d4e2f",
+            "outcome": "P1Win"
+        }"#;
+        let vote0: Vote = serde_json::from_str(json).unwrap();
+        let vote1: Vote = serde_json::from_str(json).unwrap();
+        assert_eq!(vote0.voter(), "test_user");
+        assert_eq!(
+            vote0.task1().to_string(),
+            "9f3c2a5e-8c4b-4d2a-9e1f-3b7a6c5d4e2f"
+        );
+        assert!(matches!(vote0.outcome(), Outcome::P1Win));
+        assert!(vote0.comment().is_none());
+        assert_eq!(vote0.task_versions(), (0, 0));
+        assert_eq!(vote0.id(), vote1.id());
+
+        let other: Vote = serde_json::from_str(&json.replace("test_user", "other_user")).unwrap();
+        assert_ne!(other.id(), vote0.id());
+    }
+}
@@ -33,6 +33,8 @@ pub fn new_elo_pair(p0_elo: f32, p1_elo: f32, outcome: Outcome) -> (f32, f32) {
 }
 
 #[cfg(test)]
+// The expected ratings are written out in full, beyond what an f32 holds.
+#[allow(clippy::excessive_precision)]
 mod tests {
     use crate::elo::{new_elo_pair, Outcome};
 
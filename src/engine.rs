@@ -1,37 +1,191 @@
-use chrono::{DateTime, Days, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use rand::distributions::{Distribution, Uniform};
 use rand::prelude::*;
 
-use crate::data::{Rating, Task, Vote};
+use crate::data::{
+    Rating, RatingChange, Retraction, Role, Task, TaskVersion, User, Vote, VoteArchive,
+};
 use crate::elo::{new_elo_pair, Outcome};
 use crate::errors::{Error, ErrorCode};
-use crate::persistence::Persistence;
+use crate::limits::{LimitCheck, LimitPolicy, LimitWindow};
+use crate::persistence::{Persistence, Snapshot};
+use crate::query::{TaskQuery, TaskStatus, UserQuery};
 
+use std::collections::{HashMap, HashSet};
 use std::time::SystemTime;
+use uuid::Uuid;
 
 const MAX_OPTIMISTIC_CONCURRENCY_ATTEMPTS: i32 = 8;
+const DEFAULT_RETRACTION_WINDOW_MINUTES: i64 = 15;
+const DEFAULT_RETENTION_DAYS: i64 = 365;
 
-pub struct Engine;
+/// Controls for how long users can retract or correct their own votes, and
+/// whether retracted votes still count towards their vote limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetractionPolicy {
+    window: TimeDelta,
+    count_retracted_votes: bool,
+}
+impl RetractionPolicy {
+    pub fn new(window: TimeDelta, count_retracted_votes: bool) -> Self {
+        RetractionPolicy {
+            window,
+            count_retracted_votes,
+        }
+    }
+
+    pub fn window(&self) -> &TimeDelta {
+        &self.window
+    }
+    pub fn count_retracted_votes(&self) -> bool {
+        self.count_retracted_votes
+    }
+}
+impl Default for RetractionPolicy {
+    fn default() -> Self {
+        RetractionPolicy::new(TimeDelta::minutes(DEFAULT_RETRACTION_WINDOW_MINUTES), true)
+    }
+}
+
+/// Controls how long votes are kept one by one before `Engine::compact_votes`
+/// folds them into the vote archive. Compacted votes no longer count towards
+/// vote limits, so `keep` should be longer than the longest limit window.
+/// Votes that can still be retracted are never compacted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    keep: TimeDelta,
+}
+impl RetentionPolicy {
+    pub fn new(keep: TimeDelta) -> Self {
+        RetentionPolicy { keep }
+    }
+
+    pub fn keep(&self) -> &TimeDelta {
+        &self.keep
+    }
+}
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::new(TimeDelta::days(DEFAULT_RETENTION_DAYS))
+    }
+}
+
+/// What a call to `Engine::compact_votes` did. `compacted_votes` counts every
+/// vote removed, including the `retracted_votes` that were dropped with their
+/// retractions; the others were added to the tallies of `pairs` pairs of
+/// tasks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+    cutoff: DateTime<Utc>,
+    compacted_votes: usize,
+    retracted_votes: usize,
+    pairs: usize,
+    remaining_votes: usize,
+}
+impl CompactionReport {
+    /// The votes cast before this time are compacted.
+    pub fn cutoff(&self) -> &DateTime<Utc> {
+        &self.cutoff
+    }
+    pub fn compacted_votes(&self) -> usize {
+        self.compacted_votes
+    }
+    pub fn retracted_votes(&self) -> usize {
+        self.retracted_votes
+    }
+    pub fn pairs(&self) -> usize {
+        self.pairs
+    }
+    pub fn remaining_votes(&self) -> usize {
+        self.remaining_votes
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Engine {
+    default_limit_policy: LimitPolicy,
+    retraction_policy: RetractionPolicy,
+    retention_policy: RetentionPolicy,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
 
 impl Engine {
-    pub fn get_question(&self, persistence: &impl Persistence) -> Result<(Task, Task), Error> {
-        let tasks: Vec<Task> = persistence
-            .list_tasks()?
-            .iter()
-            .filter(|t| !t.closed())
-            .cloned()
-            .collect();
-        if tasks.len() < 2 {
-            return Err(Error::not_enough_tasks());
+    pub fn new() -> Self {
+        Engine {
+            default_limit_policy: LimitPolicy::new(),
+            retraction_policy: RetractionPolicy::default(),
+            retention_policy: RetentionPolicy::default(),
         }
-        let distribution = Uniform::from(0..tasks.len());
-        let mut rng = thread_rng();
-        let t0 = distribution.sample(&mut rng);
-        let mut t1 = distribution.sample(&mut rng);
-        while t1 == t0 {
-            t1 = distribution.sample(&mut rng);
+    }
+
+    /// Applies `policy` to every user without a limit policy of their own, on
+    /// top of their weekly limit.
+    pub fn with_default_limit_policy(mut self, policy: LimitPolicy) -> Self {
+        self.default_limit_policy = policy;
+        self
+    }
+
+    pub fn with_retraction_policy(mut self, policy: RetractionPolicy) -> Self {
+        self.retraction_policy = policy;
+        self
+    }
+
+    pub fn with_retention_policy(mut self, policy: RetentionPolicy) -> Self {
+        self.retention_policy = policy;
+        self
+    }
+
+    pub fn default_limit_policy(&self) -> &LimitPolicy {
+        &self.default_limit_policy
+    }
+
+    pub fn retraction_policy(&self) -> &RetractionPolicy {
+        &self.retraction_policy
+    }
+
+    pub fn retention_policy(&self) -> &RetentionPolicy {
+        &self.retention_policy
+    }
+
+    /// Returns the limit policy that applies to the votes of `user`.
+    pub fn limit_policy_for(&self, user: &User) -> LimitPolicy {
+        if let Some(policy) = user.limit_policy() {
+            return policy.clone();
+        }
+        let policy = self.default_limit_policy.clone();
+        if user.is_limited() {
+            policy.with_limit(
+                user.limit_votes_per_week() as u32,
+                LimitWindow::RollingDays(7),
+            )
+        } else {
+            policy
+        }
+    }
+
+    /// Picks two different open tasks at random, reading only those two.
+    pub fn get_question(&self, persistence: &impl Persistence) -> Result<(Task, Task), Error> {
+        let open = open_tasks();
+        let pick = |i: usize| {
+            persistence
+                .query_tasks(&open.clone().with_offset(i).with_limit(1))
+                .map(|mut tasks| tasks.pop())
+        };
+        // Tasks closed between the count and the picks move the others, so
+        // the picks are made again when they no longer match the count.
+        let mut attempts = 0;
+        loop {
+            let (i0, i1) = pick_question(persistence.count_tasks(&open)?)?;
+            match picked_question(pick(i0)?, pick(i1)?) {
+                Ok(question) => return Ok(question),
+                Err(e) => check_retry(e, &mut attempts)?,
+            }
         }
-        Ok((tasks[t0].clone(), tasks[t1].clone()))
     }
 
     pub fn answer_question(
@@ -41,118 +195,651 @@ impl Engine {
         t0: &Task,
         t1: &Task,
         outcome: Outcome,
-    ) -> Result<(), Error> {
-        let user = persistence.get_user(u_id)?;
-        if user.is_limited() {
-            let now: DateTime<Utc> = SystemTime::now().into();
-            let last_week = now
-                .checked_sub_days(Days::new(7))
-                .ok_or(Error::generic("date wrap-around"))?;
-            let user_votes = persistence.get_num_votes_for_user_since(u_id, &last_week)? as i32;
-            if user_votes >= user.limit_votes_per_week() {
-                return Err(Error::user_limit_exceeded(u_id));
-            }
+        comment: Option<&str>,
+    ) -> Result<Vote, Error> {
+        let user = self.authorize(persistence, u_id, Role::can_vote, "vote")?;
+        let now: DateTime<Utc> = SystemTime::now().into();
+        if let Some(since) = self.limit_window_start(&user, &now)? {
+            let votes = persistence.list_votes_for_user_since(u_id, &since)?;
+            let retractions = persistence.list_retractions_for_user_since(u_id, &since)?;
+            self.check_vote_limit(&user, &now, &votes, &retractions)?;
         }
         // Optimistic concurrency based on OffsetToken
         let mut attempts = 0;
-        'out: loop {
+        loop {
             let snapshot = persistence.get_snapshot()?;
-            let mut r0 = snapshot
-                .ranking()
-                .iter()
-                .find(|rating| rating.task() == t0.id())
-                .unwrap_or(&Rating::new(t0.id().clone()))
-                .clone();
-            let mut r1 = snapshot
-                .ranking()
-                .iter()
-                .find(|rating| rating.task() == t1.id())
-                .unwrap_or(&Rating::new(t1.id().clone()))
-                .clone();
-            let (new_elo0, new_elo1) = new_elo_pair(r0.elo(), r1.elo(), outcome);
-            let vote = Vote::new(
-                u_id,
-                SystemTime::now().into(),
-                t0.id().clone(),
-                t1.id().clone(),
-                outcome,
-            );
-            r0 = Rating::with_elo(t0.id().clone(), new_elo0);
-            r1 = Rating::with_elo(t1.id().clone(), new_elo1);
+            let (vote, r0, r1) = new_vote(&snapshot, u_id, t0, t1, outcome, comment);
             match persistence.add_vote_and_update_ratings(snapshot.etag(), &vote, &r0, &r1) {
-                Ok(_) => {
-                    break 'out;
-                }
-                Err(e) => {
-                    if e.code() != ErrorCode::OptimisticConcurrencyRetryTransaction {
-                        return Err(e);
-                    }
-                    // check the max attempts
-                    if attempts >= MAX_OPTIMISTIC_CONCURRENCY_ATTEMPTS {
-                        return Err(Error::too_many_retry_attempts());
-                    }
-                    // otherwise retry
-                    attempts += 1;
-                }
+                Ok(_) => return Ok(vote),
+                Err(e) => check_retry(e, &mut attempts)?,
             }
         }
+    }
+
+    /// Retracts a vote of the acting user, as long as the retraction window
+    /// has not expired. The ratings are recomputed without the vote.
+    pub fn retract_vote(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        v_id: &Uuid,
+    ) -> Result<(), Error> {
+        self.replace_vote(persistence, u_id, v_id, None)?;
         Ok(())
     }
 
+    /// Replaces a vote of the acting user with one with a different outcome,
+    /// as long as the retraction window has not expired. The replacement
+    /// keeps the time of the original vote and takes its place in the vote
+    /// limits, so a correction does not count as a new vote.
+    pub fn correct_vote(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        v_id: &Uuid,
+        outcome: Outcome,
+    ) -> Result<Vote, Error> {
+        self.replace_vote(persistence, u_id, v_id, Some(outcome))?
+            .ok_or(Error::generic("correction without a replacement vote"))
+    }
+
     pub fn get_current_ranking(
         &self,
         persistence: &mut impl Persistence,
+        u_id: &str,
     ) -> Result<Vec<Rating>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read the ranking")?;
         let snapshot = persistence.get_snapshot()?;
         Ok(snapshot.ranking().clone())
     }
+
+    /// Lists the changes of the rating of a task, oldest first, each with the
+    /// vote that caused it.
+    pub fn get_rating_history(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<RatingChange>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read the ranking")?;
+        persistence.list_rating_changes(t_id)
+    }
+
+    /// Returns the ranking as it stood at `time`.
+    pub fn get_ranking_at(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        time: &DateTime<Utc>,
+    ) -> Result<Vec<Rating>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read the ranking")?;
+        persistence.get_ranking_at(time)
+    }
+
+    /// Folds the votes older than the retention policy allows into the vote
+    /// archive of the backend, which keeps the ratings they led to and a
+    /// tally per pair of tasks. Retracted votes are dropped with their
+    /// retractions. Recomputing the ratings gives the same result afterwards,
+    /// but the compacted votes can no longer be listed, retracted or counted
+    /// towards limits. Their rating changes are kept.
+    pub fn compact_votes(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+    ) -> Result<CompactionReport, Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "compact votes")?;
+        let cutoff = self.compaction_cutoff(&SystemTime::now().into());
+
+        let mut attempts = 0;
+        loop {
+            let snapshot = persistence.get_snapshot()?;
+            let (archive, report) = compact(
+                &snapshot,
+                &persistence.get_vote_archive()?,
+                &persistence.list_votes()?,
+                retracted_vote_ids(persistence)?,
+                cutoff,
+            );
+            if report.compacted_votes == 0 {
+                return Ok(report);
+            }
+            match persistence.compact_votes(snapshot.etag(), &archive) {
+                Ok(_) => return Ok(report),
+                Err(e) => check_retry(e, &mut attempts)?,
+            }
+        }
+    }
+
+    /// Lists the votes on a task that came with a comment, leaving out the
+    /// retracted ones. `Vote::favours` tells the arguments for the task from
+    /// the ones against it.
+    pub fn get_task_comments(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<Vote>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read comments")?;
+        let retracted = retracted_vote_ids(persistence)?;
+        Ok(persistence
+            .list_votes_for_task(t_id)?
+            .into_iter()
+            .filter(|v| v.comment().is_some() && !retracted.contains(v.id()))
+            .collect())
+    }
+
+    pub fn add_task(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        t: &Task,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "add tasks")?;
+        persistence.upsert_task(t, u_id)
+    }
+
+    /// Adds or updates many tasks in one transaction: if one of them cannot
+    /// be stored, none are. Needs a backend that supports transactions.
+    pub fn add_tasks(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        tasks: &[Task],
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "add tasks")?;
+        persistence.transaction(|p| tasks.iter().try_for_each(|t| p.upsert_task(t, u_id)))
+    }
+
+    /// Changes an existing task. If the summary or the link change, the votes
+    /// already cast on the task refer to an outdated version of it.
+    pub fn edit_task(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        t: &Task,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "edit tasks")?;
+        persistence.get_task(t.id())?;
+        persistence.upsert_task(t, u_id)
+    }
+
+    pub fn get_task_history(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<TaskVersion>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read task history")?;
+        persistence.list_task_versions(t_id)
+    }
+
+    /// Lists the votes on a task that were cast on an older version of it
+    /// than the current one, leaving out the retracted ones. These votes may
+    /// no longer reflect what the voters think if the task was rewritten.
+    pub fn get_outdated_votes(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<Vec<Vote>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "read votes")?;
+        let current = persistence.get_task(t_id)?;
+        let retracted = retracted_vote_ids(persistence)?;
+        Ok(persistence
+            .list_votes_for_task(t_id)?
+            .into_iter()
+            .filter(|v| !retracted.contains(v.id()) && is_outdated(v, &current))
+            .collect())
+    }
+
+    pub fn close_task(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        t_id: &Uuid,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "close tasks")?;
+        persistence.close_task(t_id)
+    }
+
+    /// Closes many tasks in one transaction: if one of them is not found,
+    /// none are closed. Needs a backend that supports transactions.
+    pub fn close_tasks(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        t_ids: &[Uuid],
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_tasks, "close tasks")?;
+        persistence.transaction(|p| t_ids.iter().try_for_each(|t_id| p.close_task(t_id)))
+    }
+
+    pub fn list_users(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+    ) -> Result<Vec<User>, Error> {
+        self.authorize(persistence, u_id, Role::can_manage_users, "list users")?;
+        persistence.list_users()
+    }
+
+    pub fn query_users(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        query: &UserQuery,
+    ) -> Result<Vec<User>, Error> {
+        self.authorize(persistence, u_id, Role::can_manage_users, "list users")?;
+        persistence.query_users(query)
+    }
+
+    pub fn query_tasks(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        query: &TaskQuery,
+    ) -> Result<Vec<Task>, Error> {
+        self.authorize(persistence, u_id, Role::can_view, "list tasks")?;
+        persistence.query_tasks(query)
+    }
+
+    pub fn upsert_user(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        u: &User,
+    ) -> Result<(), Error> {
+        self.authorize(persistence, u_id, Role::can_manage_users, "manage users")?;
+        persistence.upsert_user(u)
+    }
+
+    fn replace_vote(
+        &self,
+        persistence: &mut impl Persistence,
+        u_id: &str,
+        v_id: &Uuid,
+        outcome: Option<Outcome>,
+    ) -> Result<Option<Vote>, Error> {
+        self.authorize(persistence, u_id, Role::can_vote, "retract votes")?;
+        let vote = persistence.get_vote(v_id)?;
+        let (retraction, replacement) = self.prepare_retraction(u_id, &vote, outcome)?;
+
+        let mut attempts = 0;
+        loop {
+            let snapshot = persistence.get_snapshot()?;
+            let ratings = ratings_after_retraction(
+                &snapshot,
+                &persistence.get_vote_archive()?,
+                &persistence.list_votes()?,
+                retracted_vote_ids(persistence)?,
+                &retraction,
+                replacement.as_ref(),
+            )?;
+            match persistence.retract_vote_and_replace_ratings(
+                snapshot.etag(),
+                &retraction,
+                replacement.as_ref(),
+                &ratings,
+            ) {
+                Ok(_) => return Ok(replacement),
+                Err(e) => check_retry(e, &mut attempts)?,
+            }
+        }
+    }
+
+    // Returns the start of the window of votes that the limit policy of the
+    // user looks at, or None if the user is not limited.
+    pub(crate) fn limit_window_start(
+        &self,
+        user: &User,
+        now: &DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, Error> {
+        let policy = self.limit_policy_for(user);
+        if !policy.is_limited() {
+            return Ok(None);
+        }
+        Ok(Some(policy.window_start(now)?))
+    }
+
+    // Checks the limit policy of the user against the votes they cast since
+    // the start of its window, leaving out the ones that do not count. A
+    // corrected vote never counts, since its replacement takes its place.
+    pub(crate) fn check_vote_limit(
+        &self,
+        user: &User,
+        now: &DateTime<Utc>,
+        votes: &[Vote],
+        retractions: &[Retraction],
+    ) -> Result<(), Error> {
+        let count_retracted = self.retraction_policy.count_retracted_votes();
+        let uncounted: HashSet<Uuid> = retractions
+            .iter()
+            .filter(|r| !count_retracted || r.replacement().is_some())
+            .map(|r| *r.vote())
+            .collect();
+        let vote_times: Vec<DateTime<Utc>> = votes
+            .iter()
+            .filter(|v| !uncounted.contains(v.id()))
+            .map(|v| *v.time())
+            .collect();
+        match self.limit_policy_for(user).check(now, &vote_times)? {
+            LimitCheck::Allowed => Ok(()),
+            LimitCheck::BlockedUntil(t) => Err(Error::user_limit_exceeded(user.id(), Some(&t))),
+            LimitCheck::BlockedForever => Err(Error::user_limit_exceeded(user.id(), None)),
+        }
+    }
+
+    // Checks that the acting user may retract the vote, and builds the
+    // retraction and the replacement vote for a correction.
+    pub(crate) fn prepare_retraction(
+        &self,
+        u_id: &str,
+        vote: &Vote,
+        outcome: Option<Outcome>,
+    ) -> Result<(Retraction, Option<Vote>), Error> {
+        if vote.voter() != u_id {
+            return Err(Error::permission_denied(
+                u_id,
+                "retract the votes of other users",
+            ));
+        }
+        let now: DateTime<Utc> = SystemTime::now().into();
+        if now - *vote.time() > *self.retraction_policy.window() {
+            return Err(Error::retraction_not_allowed(
+                vote.id(),
+                "the retraction window has expired",
+            ));
+        }
+        let replacement = outcome.map(|o| {
+            let mut replacement = Vote::new(u_id, *vote.time(), *vote.task0(), *vote.task1(), o);
+            replacement.set_comment(vote.comment());
+            replacement.set_task_versions(vote.task_versions().0, vote.task_versions().1);
+            replacement
+        });
+        let retraction =
+            Retraction::new(*vote.id(), u_id, now, replacement.as_ref().map(|v| *v.id()));
+        Ok((retraction, replacement))
+    }
+
+    // The time before which votes are compacted: as old as the retention
+    // policy allows, but never within the retraction window.
+    pub(crate) fn compaction_cutoff(&self, now: &DateTime<Utc>) -> DateTime<Utc> {
+        let keep = std::cmp::max(
+            self.retention_policy.keep(),
+            self.retraction_policy.window(),
+        );
+        *now - *keep
+    }
+
+    // Fetches the acting user and checks that their role allows the action.
+    fn authorize(
+        &self,
+        persistence: &impl Persistence,
+        u_id: &str,
+        allowed: fn(&Role) -> bool,
+        action: &str,
+    ) -> Result<User, Error> {
+        let user = persistence.get_user(u_id)?;
+        if !allowed(&user.role()) {
+            return Err(Error::permission_denied(u_id, action));
+        }
+        Ok(user)
+    }
+}
+
+// The tasks that questions are asked about, in a stable order.
+pub(crate) fn open_tasks() -> TaskQuery {
+    TaskQuery::new().with_status(TaskStatus::Open)
+}
+
+// Picks the positions of two different tasks among `count` open ones.
+pub(crate) fn pick_question(count: usize) -> Result<(usize, usize), Error> {
+    if count < 2 {
+        return Err(Error::not_enough_tasks());
+    }
+    let distribution = Uniform::from(0..count);
+    let mut rng = thread_rng();
+    let t0 = distribution.sample(&mut rng);
+    let mut t1 = distribution.sample(&mut rng);
+    while t1 == t0 {
+        t1 = distribution.sample(&mut rng);
+    }
+    Ok((t0, t1))
+}
+
+// The two tasks picked for a question, unless the open tasks changed after
+// they were counted, in which case the picks must be made again.
+pub(crate) fn picked_question(t0: Option<Task>, t1: Option<Task>) -> Result<(Task, Task), Error> {
+    match (t0, t1) {
+        (Some(t0), Some(t1)) if t0.id() != t1.id() => Ok((t0, t1)),
+        _ => Err(Error::retry_transaction()),
+    }
+}
+
+// Builds a vote cast now, and the ratings of the two tasks after it, starting
+// from the ratings in the snapshot.
+pub(crate) fn new_vote(
+    snapshot: &Snapshot,
+    u_id: &str,
+    t0: &Task,
+    t1: &Task,
+    outcome: Outcome,
+    comment: Option<&str>,
+) -> (Vote, Rating, Rating) {
+    let elo = |t: &Task| {
+        snapshot
+            .ranking()
+            .iter()
+            .find(|rating| rating.task() == t.id())
+            .unwrap_or(&Rating::new(*t.id()))
+            .elo()
+    };
+    let (new_elo0, new_elo1) = new_elo_pair(elo(t0), elo(t1), outcome);
+    let mut vote = Vote::new(u_id, SystemTime::now().into(), *t0.id(), *t1.id(), outcome);
+    vote.set_comment(comment);
+    vote.set_task_versions(t0.version(), t1.version());
+    (
+        vote,
+        Rating::with_elo(*t0.id(), new_elo0),
+        Rating::with_elo(*t1.id(), new_elo1),
+    )
+}
+
+// Recomputes the ratings of all the tasks in the snapshot as if the retracted
+// vote had never been cast, and its replacement, if any, had been instead.
+pub(crate) fn ratings_after_retraction(
+    snapshot: &Snapshot,
+    archive: &VoteArchive,
+    votes: &[Vote],
+    mut retracted: HashSet<Uuid>,
+    retraction: &Retraction,
+    replacement: Option<&Vote>,
+) -> Result<Vec<Rating>, Error> {
+    if !retracted.insert(*retraction.vote()) {
+        return Err(Error::retraction_not_allowed(
+            retraction.vote(),
+            "already retracted",
+        ));
+    }
+    let mut votes = votes.to_vec();
+    votes.extend(replacement.cloned());
+    Ok(recompute_ratings(
+        snapshot.ranking(),
+        archive,
+        &votes,
+        &retracted,
+    ))
+}
+
+// Builds the archive that the votes cast before `cutoff` are compacted into,
+// with the ratings reached by replaying them from the current archive, and
+// reports what is compacted. Nothing is compacted if the cutoff is not after
+// the one of the archive.
+pub(crate) fn compact(
+    snapshot: &Snapshot,
+    archive: &VoteArchive,
+    votes: &[Vote],
+    retracted: HashSet<Uuid>,
+    cutoff: DateTime<Utc>,
+) -> (VoteArchive, CompactionReport) {
+    let compacted: Vec<Vote> = match archive.cutoff() {
+        Some(archived) if *archived >= cutoff => Vec::new(),
+        _ => votes
+            .iter()
+            .filter(|v| *v.time() < cutoff)
+            .cloned()
+            .collect(),
+    };
+    let mut new_archive = archive.clone();
+    new_archive.advance(
+        cutoff,
+        recompute_ratings(snapshot.ranking(), archive, &compacted, &retracted),
+    );
+    let mut pairs = HashSet::new();
+    for vote in &compacted {
+        new_archive.record(vote, retracted.contains(vote.id()));
+        let (t0, t1) = (*vote.task0(), *vote.task1());
+        pairs.insert(if t0 < t1 { (t0, t1) } else { (t1, t0) });
+    }
+    let report = CompactionReport {
+        cutoff,
+        compacted_votes: compacted.len(),
+        retracted_votes: compacted
+            .iter()
+            .filter(|v| retracted.contains(v.id()))
+            .count(),
+        pairs: pairs.len(),
+        remaining_votes: votes.len() - compacted.len(),
+    };
+    (new_archive, report)
+}
+
+// Tells whether the vote was cast on an older version of the task than the
+// current one. Votes that do not know the version are never outdated.
+pub(crate) fn is_outdated(vote: &Vote, task: &Task) -> bool {
+    let seen = if vote.task0() == task.id() {
+        vote.task_versions().0
+    } else {
+        vote.task_versions().1
+    };
+    seen != 0 && seen < task.version()
+}
+
+// Lets an optimistic concurrency loop go on after a conflict, up to the
+// maximum number of attempts. Any other error is returned as is.
+pub(crate) fn check_retry(e: Error, attempts: &mut i32) -> Result<(), Error> {
+    if e.code() != ErrorCode::OptimisticConcurrencyRetryTransaction {
+        return Err(e);
+    }
+    if *attempts >= MAX_OPTIMISTIC_CONCURRENCY_ATTEMPTS {
+        return Err(Error::too_many_retry_attempts());
+    }
+    *attempts += 1;
+    Ok(())
+}
+
+fn retracted_vote_ids(persistence: &impl Persistence) -> Result<HashSet<Uuid>, Error> {
+    Ok(persistence
+        .list_retractions()?
+        .iter()
+        .map(|r| *r.vote())
+        .collect())
+}
+
+// Replays the votes that have not been retracted in time order, starting from
+// the ratings of the archive, for all the tasks in the given ranking.
+fn recompute_ratings(
+    ranking: &[Rating],
+    archive: &VoteArchive,
+    votes: &[Vote],
+    retracted: &HashSet<Uuid>,
+) -> Vec<Rating> {
+    let mut elos: HashMap<Uuid, f32> = ranking
+        .iter()
+        .map(|r| (*r.task(), archive.rating(r.task()).elo()))
+        .collect();
+    let mut votes: Vec<&Vote> = votes
+        .iter()
+        .filter(|v| !retracted.contains(v.id()))
+        .collect();
+    votes.sort_by_key(|v| *v.time());
+    for vote in votes {
+        let (Some(elo0), Some(elo1)) = (elos.get(vote.task0()), elos.get(vote.task1())) else {
+            continue;
+        };
+        let (new_elo0, new_elo1) = new_elo_pair(*elo0, *elo1, vote.outcome());
+        elos.insert(*vote.task0(), new_elo0);
+        elos.insert(*vote.task1(), new_elo1);
+    }
+    elos.into_iter()
+        .map(|(task, elo)| Rating::with_elo(task, elo))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::data::{Task, User};
+    use crate::data::{Role, Task, User};
     use crate::elo::Outcome;
-    use crate::engine::Engine;
+    use crate::engine::{Engine, RetentionPolicy, RetractionPolicy};
     use crate::errors::ErrorCode;
-    use crate::persistence::{InMemory, Persistence};
+    use crate::fixtures::TempPath;
+    use crate::limits::{LimitPolicy, LimitWindow};
+    use crate::persistence::{InMemory, LoggingPersistence, Persistence, SQLitePersistence};
+    use crate::query::{TaskQuery, TaskStatus, UserQuery};
 
+    use chrono::TimeDelta;
+    use std::sync::{Arc, Mutex};
     use url::Url;
     use uuid::Uuid;
 
     const EPSILON: f32 = 0.000001;
 
-    const TEST_USER_ID: &'static str = "test_user";
+    const TEST_USER_ID: &str = "test_user";
     const TEST_USER_LIMIT: i32 = 2;
+    const TEST_ADMIN_ID: &str = "test_admin";
+    const TEST_VIEWER_ID: &str = "test_viewer";
 
-    const TEST_TASK_SUMMARY_0: &'static str = "task zero";
-    const TEST_TASK_SUMMARY_1: &'static str = "task one";
+    const TEST_TASK_SUMMARY_0: &str = "task zero";
+    const TEST_TASK_SUMMARY_1: &str = "task one";
 
     fn init(database: &mut impl Persistence) {
         database
             .upsert_user(&User::new(TEST_USER_ID, TEST_USER_LIMIT))
             .unwrap();
         database
-            .upsert_task(&Task::new(
-                Uuid::new_v4(),
-                TEST_TASK_SUMMARY_0,
-                Url::parse("https://localhost/0").unwrap(),
-                false,
-            ))
+            .upsert_user(&User::with_role(TEST_ADMIN_ID, -1, Role::Admin))
             .unwrap();
         database
-            .upsert_task(&Task::new(
-                Uuid::new_v4(),
-                TEST_TASK_SUMMARY_1,
-                Url::parse("https://localhost/1").unwrap(),
-                false,
-            ))
+            .upsert_user(&User::with_role(TEST_VIEWER_ID, -1, Role::Viewer))
+            .unwrap();
+        database
+            .upsert_task(
+                &Task::new(
+                    Uuid::new_v4(),
+                    TEST_TASK_SUMMARY_0,
+                    Url::parse("https://localhost/0").unwrap(),
+                    false,
+                ),
+                TEST_ADMIN_ID,
+            )
+            .unwrap();
+        database
+            .upsert_task(
+                &Task::new(
+                    Uuid::new_v4(),
+                    TEST_TASK_SUMMARY_1,
+                    Url::parse("https://localhost/1").unwrap(),
+                    false,
+                ),
+                TEST_ADMIN_ID,
+            )
             .unwrap();
     }
 
     #[test]
     fn test_question() {
         let mut database = InMemory::new();
-        let engine = Engine;
+        let engine = Engine::new();
 
         let result0 = engine.get_question(&database);
         assert!(result0.is_err());
@@ -168,14 +855,119 @@ mod tests {
         assert_ne!(t0.summary(), t1.summary());
     }
 
+    #[test]
+    fn test_question_while_tasks_are_closed() {
+        let path = TempPath::new(".db");
+        // Closes the task set here on another connection, right after the
+        // engine has counted the open tasks.
+        let to_close: Arc<Mutex<Option<Uuid>>> = Arc::default();
+        let (closing, db_path) = (to_close.clone(), path.to_path_buf());
+        let inner = SQLitePersistence::new(path.to_path_buf()).unwrap();
+        let mut database = LoggingPersistence::new(inner, move |call| {
+            if call.method() != "count_tasks" {
+                return;
+            }
+            if let Some(t_id) = closing.lock().unwrap().take() {
+                let mut other = SQLitePersistence::new(db_path.clone()).unwrap();
+                other.close_task(&t_id).unwrap();
+            }
+        });
+        init(&mut database);
+        let engine = Engine::new();
+        let open = TaskQuery::new().with_status(TaskStatus::Open);
+
+        for i in 0..20 {
+            let task = Task::new(
+                Uuid::new_v4(),
+                &format!("task {}", i),
+                Url::parse(&format!("https://localhost/task/{}", i)).unwrap(),
+                false,
+            );
+            database.upsert_task(&task, TEST_ADMIN_ID).unwrap();
+            let tasks = database.query_tasks(&open).unwrap();
+            assert_eq!(tasks.len(), 3);
+            *to_close.lock().unwrap() = Some(*tasks[0].id());
+
+            let (t0, t1) = engine.get_question(&database).unwrap();
+            assert_ne!(t0.id(), t1.id());
+            assert_ne!(t0.id(), tasks[0].id());
+            assert_ne!(t1.id(), tasks[0].id());
+        }
+    }
+
+    #[test]
+    fn test_bulk_operations() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let new_tasks: Vec<Task> = (0..20)
+            .map(|i| {
+                Task::new(
+                    Uuid::new_v4(),
+                    &format!("imported {}", i),
+                    Url::parse(&format!("https://localhost/imported/{}", i)).unwrap(),
+                    false,
+                )
+            })
+            .collect();
+
+        let result = engine.add_tasks(&mut database, TEST_USER_ID, &new_tasks);
+        assert_eq!(result.err().unwrap().code(), ErrorCode::PermissionDenied);
+        engine
+            .add_tasks(&mut database, TEST_ADMIN_ID, &new_tasks)
+            .unwrap();
+        assert_eq!(database.list_tasks().unwrap().len(), 22);
+
+        let mut to_close: Vec<Uuid> = new_tasks.iter().take(5).map(|t| *t.id()).collect();
+        to_close.push(Uuid::new_v4());
+        let result = engine.close_tasks(&mut database, TEST_ADMIN_ID, &to_close);
+        assert_eq!(result.err().unwrap().code(), ErrorCode::TaskNotFound);
+        assert!(database.list_tasks().unwrap().iter().all(|t| !t.closed()));
+
+        to_close.pop();
+        engine
+            .close_tasks(&mut database, TEST_ADMIN_ID, &to_close)
+            .unwrap();
+        let closed = database
+            .list_tasks()
+            .unwrap()
+            .iter()
+            .filter(|t| t.closed())
+            .count();
+        assert_eq!(closed, 5);
+    }
+
+    #[test]
+    fn test_question_skips_closed_tasks() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, _) = engine.get_question(&database).unwrap();
+        engine
+            .close_task(&mut database, TEST_ADMIN_ID, t0.id())
+            .unwrap();
+
+        let result = engine.get_question(&database);
+        assert_eq!(result.err().unwrap().code(), ErrorCode::NotEnoughTasks);
+        let open = TaskQuery::new().with_status(TaskStatus::Open);
+        let tasks = engine
+            .query_tasks(&database, TEST_VIEWER_ID, &open)
+            .unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_ne!(tasks[0].id(), t0.id());
+        let result = engine.query_users(&database, TEST_VIEWER_ID, &UserQuery::new());
+        assert_eq!(result.err().unwrap().code(), ErrorCode::PermissionDenied);
+    }
+
     #[test]
     fn test_answer_no_user() {
         let mut database = InMemory::new();
         init(&mut database);
-        let engine = Engine;
+        let engine = Engine::new();
         let (t0, t1) = engine.get_question(&database).unwrap();
 
-        let result0 = engine.answer_question(&mut database, "not_a_user", &t0, &t1, Outcome::Draw);
+        let result0 =
+            engine.answer_question(&mut database, "not_a_user", &t0, &t1, Outcome::Draw, None);
         assert!(result0.is_err());
         assert_eq!(result0.err().unwrap().code(), ErrorCode::UserNotFound);
     }
@@ -184,16 +976,17 @@ mod tests {
     fn test_answer_user_limit_exceeded() {
         let mut database = InMemory::new();
         init(&mut database);
-        let engine = Engine;
+        let engine = Engine::new();
         let (t0, t1) = engine.get_question(&database).unwrap();
 
         engine
-            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw)
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
             .unwrap();
         engine
-            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw)
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
             .unwrap();
-        let result0 = engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw);
+        let result0 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None);
         assert!(result0.is_err());
         assert_eq!(result0.err().unwrap().code(), ErrorCode::UserLimitExceeded);
     }
@@ -202,11 +995,12 @@ mod tests {
     fn test_answer_task_not_found() {
         let mut database = InMemory::new();
         init(&mut database);
-        let engine = Engine;
+        let engine = Engine::new();
         let (t0, t1) = engine.get_question(&database).unwrap();
         let t2 = Task::new(Uuid::new_v4(), t0.summary(), t0.link().clone(), false);
 
-        let result0 = engine.answer_question(&mut database, TEST_USER_ID, &t2, &t1, Outcome::Draw);
+        let result0 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t2, &t1, Outcome::Draw, None);
         assert!(result0.is_err());
         assert_eq!(result0.err().unwrap().code(), ErrorCode::TaskNotFound);
     }
@@ -215,19 +1009,24 @@ mod tests {
     fn test_answer_success() {
         let mut database = InMemory::new();
         init(&mut database);
-        let engine = Engine;
+        let engine = Engine::new();
 
-        let mut ranking = engine.get_current_ranking(&mut database).unwrap();
+        let mut ranking = engine
+            .get_current_ranking(&mut database, TEST_USER_ID)
+            .unwrap();
         assert_eq!(ranking.len(), 2);
         assert_ne!(ranking[0].task(), ranking[1].task());
         assert!((ranking[0].elo() - 1200.0).abs() < EPSILON);
         assert!((ranking[1].elo() - 1200.0).abs() < EPSILON);
 
         let (t0, t1) = engine.get_question(&database).unwrap();
-        let result0 = engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::P0Win);
+        let result0 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::P0Win, None);
         assert!(result0.is_ok());
 
-        ranking = engine.get_current_ranking(&mut database).unwrap();
+        ranking = engine
+            .get_current_ranking(&mut database, TEST_USER_ID)
+            .unwrap();
         assert_eq!(ranking.len(), 2);
         assert_ne!(ranking[0].task(), ranking[1].task());
         if t0.id() == ranking[0].task() {
@@ -238,4 +1037,472 @@ mod tests {
             assert!((ranking[1].elo() - 1216.0).abs() < EPSILON);
         }
     }
+
+    #[test]
+    fn test_answer_permission_denied() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, t1) = engine.get_question(&database).unwrap();
+
+        let result0 =
+            engine.answer_question(&mut database, TEST_VIEWER_ID, &t0, &t1, Outcome::Draw, None);
+        assert!(result0.is_err());
+        assert_eq!(result0.err().unwrap().code(), ErrorCode::PermissionDenied);
+
+        let result1 =
+            engine.answer_question(&mut database, TEST_ADMIN_ID, &t0, &t1, Outcome::Draw, None);
+        assert!(result1.is_ok());
+
+        let result2 = engine.get_current_ranking(&mut database, TEST_VIEWER_ID);
+        assert!(result2.is_ok());
+    }
+
+    #[test]
+    fn test_manage_tasks_and_users() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let task = Task::new(
+            Uuid::new_v4(),
+            "task two",
+            Url::parse("https://localhost/2").unwrap(),
+            false,
+        );
+
+        let result0 = engine.add_task(&mut database, TEST_USER_ID, &task);
+        assert!(result0.is_err());
+        assert_eq!(result0.err().unwrap().code(), ErrorCode::PermissionDenied);
+        assert!(engine.add_task(&mut database, TEST_ADMIN_ID, &task).is_ok());
+        assert_eq!(database.list_tasks().unwrap().len(), 3);
+
+        let result1 = engine.close_task(&mut database, TEST_VIEWER_ID, task.id());
+        assert!(result1.is_err());
+        assert_eq!(result1.err().unwrap().code(), ErrorCode::PermissionDenied);
+        assert!(engine
+            .close_task(&mut database, TEST_ADMIN_ID, task.id())
+            .is_ok());
+
+        let result2 = engine.list_users(&database, TEST_USER_ID);
+        assert!(result2.is_err());
+        assert_eq!(result2.err().unwrap().code(), ErrorCode::PermissionDenied);
+        assert_eq!(
+            engine.list_users(&database, TEST_ADMIN_ID).unwrap().len(),
+            3
+        );
+
+        let promoted = User::with_role(TEST_USER_ID, TEST_USER_LIMIT, Role::Admin);
+        let result3 = engine.upsert_user(&mut database, TEST_USER_ID, &promoted);
+        assert!(result3.is_err());
+        assert_eq!(result3.err().unwrap().code(), ErrorCode::PermissionDenied);
+        assert!(engine
+            .upsert_user(&mut database, TEST_ADMIN_ID, &promoted)
+            .is_ok());
+        assert_eq!(database.get_user(TEST_USER_ID).unwrap().role(), Role::Admin);
+    }
+
+    #[test]
+    fn test_answer_limit_policies() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let (t0, t1) = Engine::new().get_question(&database).unwrap();
+
+        // The default policy applies on top of the weekly limit.
+        let engine = Engine::new()
+            .with_default_limit_policy(LimitPolicy::new().with_limit(1, LimitWindow::Day));
+        engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        let result0 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None);
+        assert!(result0.is_err());
+        let err = result0.err().unwrap();
+        assert_eq!(err.code(), ErrorCode::UserLimitExceeded);
+        assert!(err.msg().contains("can vote again at"));
+
+        // A user-specific policy replaces both.
+        let mut user = database.get_user(TEST_USER_ID).unwrap();
+        user.set_limit_policy(Some(
+            LimitPolicy::new().with_limit(3, LimitWindow::CalendarMonth),
+        ));
+        database.upsert_user(&user).unwrap();
+        engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        let result1 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None);
+        assert!(result1.is_err());
+        assert_eq!(result1.err().unwrap().code(), ErrorCode::UserLimitExceeded);
+
+        user.set_limit_policy(Some(LimitPolicy::new().with_limit(0, LimitWindow::Day)));
+        database.upsert_user(&user).unwrap();
+        let result2 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None);
+        assert!(result2.err().unwrap().msg().contains("cannot vote again"));
+    }
+
+    #[test]
+    fn test_retract_and_correct_vote() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, t1) = engine.get_question(&database).unwrap();
+
+        let vote0 = engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::P0Win, None)
+            .unwrap();
+        let result0 = engine.retract_vote(&mut database, TEST_ADMIN_ID, vote0.id());
+        assert!(result0.is_err());
+        assert_eq!(result0.err().unwrap().code(), ErrorCode::PermissionDenied);
+
+        let vote1 = engine
+            .correct_vote(&mut database, TEST_USER_ID, vote0.id(), Outcome::P1Win)
+            .unwrap();
+        assert_eq!(vote1.time(), vote0.time());
+        let ranking = engine
+            .get_current_ranking(&mut database, TEST_USER_ID)
+            .unwrap();
+        for rating in ranking {
+            let expected = if rating.task() == t0.id() {
+                1184.0
+            } else {
+                1216.0
+            };
+            assert!((rating.elo() - expected).abs() < EPSILON);
+        }
+
+        let result1 = engine.retract_vote(&mut database, TEST_USER_ID, vote0.id());
+        assert!(result1.is_err());
+        assert_eq!(
+            result1.err().unwrap().code(),
+            ErrorCode::RetractionNotAllowed
+        );
+
+        engine
+            .retract_vote(&mut database, TEST_USER_ID, vote1.id())
+            .unwrap();
+        let ranking = engine
+            .get_current_ranking(&mut database, TEST_USER_ID)
+            .unwrap();
+        for rating in ranking {
+            assert!((rating.elo() - 1200.0).abs() < EPSILON);
+        }
+        assert_eq!(database.list_votes().unwrap().len(), 2);
+        assert_eq!(database.list_retractions().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_compact_votes() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, t1) = engine.get_question(&database).unwrap();
+        let t2 = Task::new(
+            Uuid::new_v4(),
+            "task two",
+            Url::parse("https://localhost/2").unwrap(),
+            false,
+        );
+        engine.add_task(&mut database, TEST_ADMIN_ID, &t2).unwrap();
+        let mut vote = |a: &Task, b: &Task, outcome: Outcome| {
+            engine
+                .answer_question(&mut database, TEST_ADMIN_ID, a, b, outcome, None)
+                .unwrap()
+        };
+        let first = vote(&t0, &t1, Outcome::P0Win);
+        vote(&t1, &t0, Outcome::P0Win);
+        vote(&t0, &t2, Outcome::Draw);
+        let retracted = vote(&t2, &t1, Outcome::P1Win);
+        engine
+            .retract_vote(&mut database, TEST_ADMIN_ID, retracted.id())
+            .unwrap();
+        engine
+            .correct_vote(&mut database, TEST_ADMIN_ID, first.id(), Outcome::P1Win)
+            .unwrap();
+        let ranking = engine
+            .get_current_ranking(&mut database, TEST_VIEWER_ID)
+            .unwrap();
+        let assert_ranking = |database: &mut InMemory| {
+            let current = engine
+                .get_current_ranking(database, TEST_VIEWER_ID)
+                .unwrap();
+            assert_eq!(current.len(), ranking.len());
+            for rating in current {
+                let expected = ranking.iter().find(|r| r.task() == rating.task()).unwrap();
+                assert!((rating.elo() - expected.elo()).abs() < EPSILON);
+            }
+        };
+
+        // Votes that can still be retracted are kept.
+        let report = engine.compact_votes(&mut database, TEST_ADMIN_ID).unwrap();
+        assert_eq!(report.compacted_votes(), 0);
+        assert_eq!(report.remaining_votes(), 5);
+        let result = engine.compact_votes(&mut database, TEST_USER_ID);
+        assert_eq!(result.err().unwrap().code(), ErrorCode::PermissionDenied);
+
+        let compactor = Engine::new()
+            .with_retraction_policy(RetractionPolicy::new(TimeDelta::zero(), true))
+            .with_retention_policy(RetentionPolicy::new(TimeDelta::zero()));
+        let report = compactor
+            .compact_votes(&mut database, TEST_ADMIN_ID)
+            .unwrap();
+        assert_eq!(report.compacted_votes(), 5);
+        assert_eq!(report.retracted_votes(), 2);
+        assert_eq!(report.pairs(), 3);
+        assert_eq!(report.remaining_votes(), 0);
+        assert!(database.list_votes().unwrap().is_empty());
+        assert!(database.list_retractions().unwrap().is_empty());
+        assert_eq!(
+            database.get_vote(first.id()).err().unwrap().code(),
+            ErrorCode::VoteNotFound
+        );
+        assert_ranking(&mut database);
+
+        let archive = database.get_vote_archive().unwrap();
+        assert_eq!(archive.cutoff(), Some(report.cutoff()));
+        assert_eq!(archive.retractions(), 2);
+        let tally = archive.tally(t1.id(), t0.id()).unwrap();
+        assert_eq!(tally.total(), 3);
+        assert_eq!(tally.retracted(), 1);
+        assert_eq!(tally.draws(), 0);
+        // Tallies count from the side of their task0, whichever task it is.
+        let t0_losses = if tally.task0() == t0.id() {
+            tally.losses()
+        } else {
+            tally.wins()
+        };
+        assert_eq!(t0_losses, 2);
+        assert_eq!(archive.tally(t0.id(), t2.id()).unwrap().draws(), 1);
+        assert_eq!(archive.tally(t1.id(), t2.id()).unwrap().retracted(), 1);
+
+        // Ratings recomputed after a retraction start from the archive.
+        let last = engine
+            .answer_question(&mut database, TEST_ADMIN_ID, &t2, &t0, Outcome::P0Win, None)
+            .unwrap();
+        engine
+            .retract_vote(&mut database, TEST_ADMIN_ID, last.id())
+            .unwrap();
+        assert_ranking(&mut database);
+
+        let report = compactor
+            .compact_votes(&mut database, TEST_ADMIN_ID)
+            .unwrap();
+        assert_eq!(report.compacted_votes(), 1);
+        assert_eq!(report.retracted_votes(), 1);
+        let archive = database.get_vote_archive().unwrap();
+        assert_eq!(archive.retractions(), 3);
+        assert_eq!(archive.tally(t0.id(), t2.id()).unwrap().total(), 2);
+        assert_ranking(&mut database);
+    }
+
+    #[test]
+    fn test_rating_history() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, t1) = engine.get_question(&database).unwrap();
+
+        let vote0 = engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::P0Win, None)
+            .unwrap();
+        engine
+            .correct_vote(&mut database, TEST_USER_ID, vote0.id(), Outcome::P1Win)
+            .unwrap();
+        let history = engine
+            .get_rating_history(&database, TEST_VIEWER_ID, t0.id())
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert!((history[0].new_elo() - 1216.0).abs() < EPSILON);
+        assert!(history[1].is_retraction());
+        assert_eq!(history[1].vote(), vote0.id());
+        assert!((history[1].new_elo() - 1184.0).abs() < EPSILON);
+
+        let before = engine
+            .get_ranking_at(&database, TEST_VIEWER_ID, history[0].time())
+            .unwrap();
+        assert_eq!(before[1].task(), t0.id());
+        let result = engine.get_ranking_at(&database, "nobody", history[0].time());
+        assert_eq!(result.err().unwrap().code(), ErrorCode::UserNotFound);
+    }
+
+    #[test]
+    fn test_retraction_window_and_quota() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let (t0, t1) = Engine::new().get_question(&database).unwrap();
+
+        let expired =
+            Engine::new().with_retraction_policy(RetractionPolicy::new(TimeDelta::zero(), true));
+        let vote0 = expired
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let result0 = expired.retract_vote(&mut database, TEST_USER_ID, vote0.id());
+        assert!(result0.is_err());
+        assert_eq!(
+            result0.err().unwrap().code(),
+            ErrorCode::RetractionNotAllowed
+        );
+
+        // By default, retracted votes still count towards the limit.
+        let engine = Engine::new();
+        let vote1 = engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        engine
+            .retract_vote(&mut database, TEST_USER_ID, vote1.id())
+            .unwrap();
+        let result1 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None);
+        assert_eq!(result1.err().unwrap().code(), ErrorCode::UserLimitExceeded);
+
+        let lenient = Engine::new()
+            .with_retraction_policy(RetractionPolicy::new(TimeDelta::minutes(5), false));
+        let result2 =
+            lenient.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None);
+        assert!(result2.is_ok());
+    }
+
+    #[test]
+    fn test_correction_keeps_quota() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, t1) = engine.get_question(&database).unwrap();
+
+        // One vote short of the limit.
+        let vote0 = engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        let vote1 = engine
+            .correct_vote(&mut database, TEST_USER_ID, vote0.id(), Outcome::P0Win)
+            .unwrap();
+        engine
+            .correct_vote(&mut database, TEST_USER_ID, vote1.id(), Outcome::P1Win)
+            .unwrap();
+        engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        let result0 =
+            engine.answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::Draw, None);
+        assert_eq!(result0.err().unwrap().code(), ErrorCode::UserLimitExceeded);
+    }
+
+    #[test]
+    fn test_task_comments() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, t1) = engine.get_question(&database).unwrap();
+
+        let vote0 = engine
+            .answer_question(
+                &mut database,
+                TEST_USER_ID,
+                &t0,
+                &t1,
+                Outcome::P0Win,
+                Some("zero unblocks one"),
+            )
+            .unwrap();
+        engine
+            .answer_question(&mut database, TEST_ADMIN_ID, &t0, &t1, Outcome::Draw, None)
+            .unwrap();
+        engine
+            .answer_question(
+                &mut database,
+                TEST_ADMIN_ID,
+                &t0,
+                &t1,
+                Outcome::P1Win,
+                Some("one is cheaper"),
+            )
+            .unwrap();
+
+        let comments = engine
+            .get_task_comments(&database, TEST_VIEWER_ID, t0.id())
+            .unwrap();
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[0].comment(), Some("zero unblocks one"));
+        assert_eq!(comments[0].favours(t0.id()), Some(true));
+        assert_eq!(comments[1].comment(), Some("one is cheaper"));
+        assert_eq!(comments[1].favours(t0.id()), Some(false));
+        assert_eq!(comments[1].favours(t1.id()), Some(true));
+
+        // Corrections keep the comment, retractions hide it.
+        let vote1 = engine
+            .correct_vote(&mut database, TEST_USER_ID, vote0.id(), Outcome::Draw)
+            .unwrap();
+        assert_eq!(vote1.comment(), Some("zero unblocks one"));
+        engine
+            .retract_vote(&mut database, TEST_USER_ID, vote1.id())
+            .unwrap();
+        let comments = engine
+            .get_task_comments(&database, TEST_VIEWER_ID, t1.id())
+            .unwrap();
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].comment(), Some("one is cheaper"));
+    }
+
+    #[test]
+    fn test_task_versions_and_outdated_votes() {
+        let mut database = InMemory::new();
+        init(&mut database);
+        let engine = Engine::new();
+        let (t0, t1) = engine.get_question(&database).unwrap();
+        assert_eq!(t0.version(), 1);
+
+        let vote0 = engine
+            .answer_question(&mut database, TEST_USER_ID, &t0, &t1, Outcome::P0Win, None)
+            .unwrap();
+        assert_eq!(vote0.task_versions(), (1, 1));
+
+        // Storing the same content again does not make a new version.
+        let same = Task::new(*t0.id(), t0.summary(), t0.link().clone(), false);
+        engine
+            .edit_task(&mut database, TEST_ADMIN_ID, &same)
+            .unwrap();
+        assert!(engine
+            .get_outdated_votes(&database, TEST_USER_ID, t0.id())
+            .unwrap()
+            .is_empty());
+
+        let rewritten = Task::new(
+            *t0.id(),
+            "task zero, but different",
+            t0.link().clone(),
+            false,
+        );
+        let result0 = engine.edit_task(&mut database, TEST_USER_ID, &rewritten);
+        assert_eq!(result0.err().unwrap().code(), ErrorCode::PermissionDenied);
+        engine
+            .edit_task(&mut database, TEST_ADMIN_ID, &rewritten)
+            .unwrap();
+        let missing = Task::new(Uuid::new_v4(), "nope", t0.link().clone(), false);
+        let result1 = engine.edit_task(&mut database, TEST_ADMIN_ID, &missing);
+        assert_eq!(result1.err().unwrap().code(), ErrorCode::TaskNotFound);
+
+        let history = engine
+            .get_task_history(&database, TEST_VIEWER_ID, t0.id())
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].version(), 1);
+        assert_eq!(history[0].summary(), t0.summary());
+        assert_eq!(history[1].version(), 2);
+        assert_eq!(history[1].summary(), "task zero, but different");
+        assert_eq!(history[1].author(), TEST_ADMIN_ID);
+
+        let outdated = engine
+            .get_outdated_votes(&database, TEST_USER_ID, t0.id())
+            .unwrap();
+        assert_eq!(outdated.len(), 1);
+        assert_eq!(outdated[0].id(), vote0.id());
+        assert!(engine
+            .get_outdated_votes(&database, TEST_USER_ID, t1.id())
+            .unwrap()
+            .is_empty());
+    }
 }
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 use std::fmt;
@@ -15,6 +16,14 @@ pub enum ErrorCode {
     OptimisticConcurrencyRetryTransaction,
     OptimisticConcurrencyTooManyRetryAttempts,
     NotEnoughTasks,
+    PermissionDenied,
+    VoteNotFound,
+    RetractionNotAllowed,
+    SchemaTooNew,
+    CorruptData,
+    ReadOnly,
+    WrongKey,
+    KeyRequired,
 }
 impl fmt::Display for ErrorCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -33,6 +42,14 @@ impl fmt::Display for ErrorCode {
                 ErrorCode::OptimisticConcurrencyRetryTransaction => "OCRetryTransaction",
                 ErrorCode::OptimisticConcurrencyTooManyRetryAttempts => "OCTooManyRetryAttempts",
                 ErrorCode::NotEnoughTasks => "NotEnoughTasks",
+                ErrorCode::PermissionDenied => "PermissionDenied",
+                ErrorCode::VoteNotFound => "VoteNotFound",
+                ErrorCode::RetractionNotAllowed => "RetractionNotAllowed",
+                ErrorCode::SchemaTooNew => "SchemaTooNew",
+                ErrorCode::CorruptData => "CorruptData",
+                ErrorCode::ReadOnly => "ReadOnly",
+                ErrorCode::WrongKey => "WrongKey",
+                ErrorCode::KeyRequired => "KeyRequired",
             }
         )
     }
@@ -94,13 +111,20 @@ impl Error {
         }
     }
 
-    pub fn user_limit_exceeded(u_id: &str) -> Self {
+    pub fn user_limit_exceeded(u_id: &str, next_allowed: Option<&DateTime<Utc>>) -> Self {
         Error {
             code: ErrorCode::UserLimitExceeded,
-            msg: format!(
-                "user {} has reached the maximum number of votes per week",
-                u_id
-            ),
+            msg: match next_allowed {
+                Some(t) => format!(
+                    "user {} has reached the maximum number of votes, can vote again at {}",
+                    u_id,
+                    t.to_rfc3339()
+                ),
+                None => format!(
+                    "user {} has reached the maximum number of votes and cannot vote again",
+                    u_id
+                ),
+            },
         }
     }
 
@@ -124,6 +148,79 @@ impl Error {
             msg: "not enough tasks to ask a meaningful question".to_string(),
         }
     }
+
+    pub fn permission_denied(u_id: &str, action: &str) -> Self {
+        Error {
+            code: ErrorCode::PermissionDenied,
+            msg: format!("user {} is not allowed to {}", u_id, action),
+        }
+    }
+
+    pub fn vote_not_found(v_id: &Uuid) -> Self {
+        Error {
+            code: ErrorCode::VoteNotFound,
+            msg: format!("vote {} not found", v_id),
+        }
+    }
+
+    pub fn schema_too_new(found: u32, supported: u32) -> Self {
+        Error {
+            code: ErrorCode::SchemaTooNew,
+            msg: format!(
+                "database schema version {} is newer than the supported version {}",
+                found, supported
+            ),
+        }
+    }
+
+    pub fn corrupt_data(table: &str, row: i64, reason: &str) -> Self {
+        Error {
+            code: ErrorCode::CorruptData,
+            msg: format!("corrupt row {} in table {}: {}", row, table, reason),
+        }
+    }
+
+    pub fn retraction_not_allowed(v_id: &Uuid, reason: &str) -> Self {
+        Error {
+            code: ErrorCode::RetractionNotAllowed,
+            msg: format!("vote {} cannot be retracted: {}", v_id, reason),
+        }
+    }
+
+    pub fn transaction_in_progress() -> Self {
+        Error {
+            code: ErrorCode::GenericError,
+            msg: "a transaction is already in progress".to_string(),
+        }
+    }
+
+    pub fn no_transaction() -> Self {
+        Error {
+            code: ErrorCode::GenericError,
+            msg: "no transaction is in progress".to_string(),
+        }
+    }
+
+    pub fn read_only(action: &str) -> Self {
+        Error {
+            code: ErrorCode::ReadOnly,
+            msg: format!("cannot {}: the persistence is read-only", action),
+        }
+    }
+
+    pub fn wrong_key() -> Self {
+        Error {
+            code: ErrorCode::WrongKey,
+            msg: "the key does not decrypt the database, or it is not a database".to_string(),
+        }
+    }
+
+    pub fn key_required() -> Self {
+        Error {
+            code: ErrorCode::KeyRequired,
+            msg: "the database is encrypted and needs a key, or it is not a database".to_string(),
+        }
+    }
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -131,11 +228,32 @@ impl fmt::Display for Error {
     }
 }
 
-use rusqlite;
-use url;
-
+// Writes to a database opened read-only fail like writes through a
+// `ReadOnlyPersistence`.
 impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Error {
+        match err.sqlite_error_code() {
+            Some(rusqlite::ErrorCode::ReadOnly) => Error::read_only("write to the database"),
+            _ => Error::db_error(&err.to_string()),
+        }
+    }
+}
+// Serialization failures and deadlocks abort a transaction that can succeed
+// when it is run again.
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for Error {
+    fn from(err: postgres::Error) -> Error {
+        use postgres::error::SqlState;
+        match err.code() {
+            Some(&SqlState::T_R_SERIALIZATION_FAILURE) | Some(&SqlState::T_R_DEADLOCK_DETECTED) => {
+                Error::retry_transaction()
+            }
+            _ => Error::db_error(&err.to_string()),
+        }
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
         Error::db_error(&err.to_string())
     }
 }
@@ -0,0 +1,293 @@
+//! Seeded backends for tests, including the tests of crates that use pelo.
+//!
+//! A `Fixture` lists users, tasks and votes, and stores them in any
+//! `Persistence`. Seeding an `InMemory` backend touches no file, so tests can
+//! run in parallel:
+//!
+//! ```
+//! use pelo::fixtures::Fixture;
+//! use pelo::{Outcome, Persistence, Role, User};
+//!
+//! let fixture = Fixture::new()
+//!     .with_user(User::with_role("alice", -1, Role::Admin))
+//!     .with_new_task("first")
+//!     .with_new_task("second")
+//!     .with_vote("alice", 0, 1, Outcome::P0Win);
+//! let database = fixture.in_memory().unwrap();
+//! assert_eq!(database.list_votes().unwrap().len(), 1);
+//! ```
+//!
+//! `TempSQLitePersistence` is a SQLite database in a file of its own under
+//! the temporary directory, which is removed when it is dropped. `TempPath`
+//! does the same for the files of any other backend.
+
+use url::Url;
+use uuid::Uuid;
+
+use crate::data::{Task, User};
+use crate::elo::Outcome;
+use crate::engine::Engine;
+use crate::errors::Error;
+use crate::persistence::{InMemory, Persistence, SQLitePersistence};
+
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+
+/// The author recorded for the tasks stored by a fixture.
+pub const FIXTURE_AUTHOR: &str = "fixture";
+
+/// Users, tasks and votes to store in a backend.
+///
+/// Users and tasks are stored as they are. Votes are cast through an
+/// `Engine`, one after the other, so the ratings of the tasks are those of a
+/// real sequence of answers; their voters must be allowed to vote.
+#[derive(Debug, Clone, Default)]
+pub struct Fixture {
+    engine: Engine,
+    users: Vec<User>,
+    tasks: Vec<Task>,
+    votes: Vec<FixtureVote>,
+}
+
+#[derive(Debug, Clone)]
+struct FixtureVote {
+    voter: String,
+    task0: usize,
+    task1: usize,
+    outcome: Outcome,
+}
+
+impl Fixture {
+    /// An empty fixture, whose votes are cast by `Engine::new()`.
+    pub fn new() -> Self {
+        Fixture::default()
+    }
+
+    /// Casts the votes with `engine`, and so under its limit policy.
+    pub fn with_engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+    pub fn with_user(mut self, user: User) -> Self {
+        self.users.push(user);
+        self
+    }
+    pub fn with_task(mut self, task: Task) -> Self {
+        self.tasks.push(task);
+        self
+    }
+    /// Adds an open task with a random id and a link of its own.
+    pub fn with_new_task(self, summary: &str) -> Self {
+        let link = Url::parse(&format!("https://localhost/{}", self.tasks.len()))
+            .expect("the fixture links are valid");
+        self.with_task(Task::new(Uuid::new_v4(), summary, link, false))
+    }
+    /// Adds a vote of `voter` between the tasks at positions `task0` and
+    /// `task1` in the order they were added.
+    pub fn with_vote(mut self, voter: &str, task0: usize, task1: usize, outcome: Outcome) -> Self {
+        self.votes.push(FixtureVote {
+            voter: voter.to_string(),
+            task0,
+            task1,
+            outcome,
+        });
+        self
+    }
+
+    pub fn users(&self) -> &[User] {
+        &self.users
+    }
+    pub fn tasks(&self) -> &[Task] {
+        &self.tasks
+    }
+
+    /// Stores the users, then the tasks, then casts the votes.
+    pub fn seed(&self, persistence: &mut impl Persistence) -> Result<(), Error> {
+        for user in &self.users {
+            persistence.upsert_user(user)?;
+        }
+        for task in &self.tasks {
+            persistence.upsert_task(task, FIXTURE_AUTHOR)?;
+        }
+        for vote in &self.votes {
+            let task = |i: usize| {
+                self.tasks.get(i).ok_or_else(|| {
+                    Error::generic(&format!("the fixture has no task at position {}", i))
+                })
+            };
+            self.engine.answer_question(
+                persistence,
+                &vote.voter,
+                task(vote.task0)?,
+                task(vote.task1)?,
+                vote.outcome,
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// A new in-memory backend holding the fixture.
+    pub fn in_memory(&self) -> Result<InMemory, Error> {
+        let mut persistence = InMemory::new();
+        self.seed(&mut persistence)?;
+        Ok(persistence)
+    }
+
+    /// A new temporary SQLite database holding the fixture.
+    pub fn temp_sqlite(&self) -> Result<TempSQLitePersistence, Error> {
+        let mut persistence = TempSQLitePersistence::new()?;
+        self.seed(&mut *persistence)?;
+        Ok(persistence)
+    }
+}
+
+// The suffixes of the files that backends keep next to their main file:
+// SQLite journals, lock files, checkpoints and files being written.
+const COMPANION_SUFFIXES: &[&str] = &[
+    "",
+    "-journal",
+    "-wal",
+    "-shm",
+    ".lock",
+    ".tmp",
+    ".checkpoint",
+    ".checkpoint.tmp",
+];
+
+/// A new path in the temporary directory, which is deleted when this is
+/// dropped, along with the files that backends keep next to it.
+///
+/// Temporaries live until the end of the statement that creates them, so
+/// `check_users(JsonFilePersistence::new(TempPath::new(".json").to_path_buf()).unwrap())`
+/// removes the files once the check has returned.
+pub struct TempPath {
+    path: PathBuf,
+}
+impl TempPath {
+    /// A path with a random name ending in `extension`, such as `".db"`.
+    pub fn new(extension: &str) -> Self {
+        TempPath {
+            path: std::env::temp_dir().join(format!("pelo-{}{}", Uuid::new_v4(), extension)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn to_path_buf(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        for suffix in COMPANION_SUFFIXES {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A `SQLitePersistence` on a new file in the temporary directory, which is
+/// deleted, with its journals, when this is dropped.
+///
+/// It dereferences to the `SQLitePersistence`, so it is passed to an engine as
+/// `&mut *database`.
+pub struct TempSQLitePersistence {
+    // Declared first so that the connection is closed before the file is
+    // removed.
+    persistence: SQLitePersistence,
+    path: TempPath,
+}
+impl TempSQLitePersistence {
+    pub fn new() -> Result<Self, Error> {
+        let path = TempPath::new(".db");
+        Ok(TempSQLitePersistence {
+            persistence: SQLitePersistence::new(path.to_path_buf())?,
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.path()
+    }
+}
+impl Deref for TempSQLitePersistence {
+    type Target = SQLitePersistence;
+
+    fn deref(&self) -> &SQLitePersistence {
+        &self.persistence
+    }
+}
+impl DerefMut for TempSQLitePersistence {
+    fn deref_mut(&mut self) -> &mut SQLitePersistence {
+        &mut self.persistence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{Role, User};
+    use crate::elo::Outcome;
+    use crate::errors::ErrorCode;
+    use crate::fixtures::{Fixture, TempSQLitePersistence, FIXTURE_AUTHOR};
+    use crate::persistence::Persistence;
+
+    fn fixture() -> Fixture {
+        Fixture::new()
+            .with_user(User::with_role("test_admin", -1, Role::Admin))
+            .with_user(User::new("test_user", 1))
+            .with_new_task("task zero")
+            .with_new_task("task one")
+            .with_new_task("task two")
+            .with_vote("test_admin", 0, 1, Outcome::P0Win)
+            .with_vote("test_user", 2, 1, Outcome::Draw)
+    }
+
+    #[test]
+    fn test_fixture_in_memory() {
+        let fixture = fixture();
+        let mut database = fixture.in_memory().unwrap();
+        assert_eq!(database.list_users().unwrap().len(), 2);
+        assert_eq!(database.list_tasks().unwrap().len(), 3);
+        assert_eq!(database.list_votes().unwrap().len(), 2);
+        let versions = database
+            .list_task_versions(fixture.tasks()[0].id())
+            .unwrap();
+        assert_eq!(versions[0].author(), FIXTURE_AUTHOR);
+
+        let ranking = database.get_snapshot().unwrap().ranking().clone();
+        let best = ranking.last().unwrap();
+        assert_eq!(best.task(), fixture.tasks()[0].id());
+    }
+
+    #[test]
+    fn test_fixture_temp_sqlite() {
+        let path = {
+            let database = fixture().temp_sqlite().unwrap();
+            assert_eq!(database.list_votes().unwrap().len(), 2);
+            assert!(database.path().exists());
+            database.path().to_path_buf()
+        };
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_fixture_errors() {
+        let result = fixture()
+            .with_vote("test_admin", 0, 3, Outcome::Draw)
+            .in_memory();
+        assert_eq!(result.err().unwrap().code(), ErrorCode::GenericError);
+
+        // The weekly limit of the voter applies.
+        let result = fixture()
+            .with_vote("test_user", 0, 2, Outcome::Draw)
+            .in_memory();
+        assert!(result.is_err());
+
+        let mut database = TempSQLitePersistence::new().unwrap();
+        fixture().seed(&mut *database).unwrap();
+        assert_eq!(database.list_tasks().unwrap().len(), 3);
+    }
+}
@@ -1,18 +1,42 @@
 extern crate chrono;
+extern crate chrono_tz;
+extern crate fs4;
 extern crate rand;
 extern crate rusqlite;
 extern crate serde;
+extern crate serde_json;
 extern crate url;
 extern crate uuid;
 
+#[cfg(feature = "async")]
+mod async_engine;
+pub mod conformance;
 mod data;
 mod elo;
 mod engine;
 mod errors;
+pub mod fixtures;
+mod limits;
 mod persistence;
+mod query;
 
-pub use data::{Rating, Task, User, Vote};
+#[cfg(feature = "async")]
+pub use async_engine::AsyncEngine;
+pub use data::{
+    PairTally, Rating, RatingChange, Retraction, Role, Task, TaskVersion, User, Vote, VoteArchive,
+};
 pub use elo::Outcome;
-pub use engine::Engine;
+pub use engine::{CompactionReport, Engine, RetentionPolicy, RetractionPolicy};
 pub use errors::{Error, ErrorCode};
-pub use persistence::{Persistence, SQLitePersistence};
+pub use limits::{LimitCheck, LimitPolicy, LimitWindow, VoteLimit};
+#[cfg(feature = "async")]
+pub use persistence::{AsyncPersistence, BlockingPersistence};
+pub use persistence::{
+    CachingPersistence, CorruptRow, CorruptRowPolicy, Event, EventLogPersistence, InMemory,
+    JsonFilePersistence, LoggingPersistence, Persistence, PersistenceCall, PooledSQLitePersistence,
+    ReadOnlyPersistence, RecordedEvent, RowProblem, SQLitePersistence, SQLitePersistenceBuilder,
+    SCHEMA_VERSION,
+};
+#[cfg(feature = "postgres")]
+pub use persistence::{PostgresPersistence, POSTGRES_SCHEMA_VERSION};
+pub use query::{TaskOrder, TaskQuery, TaskStatus, UserQuery};
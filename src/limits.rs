@@ -0,0 +1,365 @@
+use chrono::{
+    DateTime, Datelike, Days, FixedOffset, LocalResult, Months, NaiveDate, Offset, TimeDelta,
+    TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
+
+/// The period of time over which a `VoteLimit` counts votes.
+///
+/// Calendar windows (`Day`, `CalendarWeek`, `CalendarMonth`) are aligned to
+/// local midnight in the time zone of the `LimitPolicy`; weeks start on
+/// Monday.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitWindow {
+    Day,
+    RollingDays(u32),
+    CalendarWeek,
+    CalendarMonth,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VoteLimit {
+    max_votes: u32,
+    window: LimitWindow,
+}
+impl VoteLimit {
+    pub fn new(max_votes: u32, window: LimitWindow) -> Self {
+        VoteLimit { max_votes, window }
+    }
+
+    pub fn max_votes(&self) -> u32 {
+        self.max_votes
+    }
+    pub fn window(&self) -> LimitWindow {
+        self.window
+    }
+}
+
+/// The outcome of checking a `LimitPolicy` before casting a vote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitCheck {
+    Allowed,
+    BlockedUntil(DateTime<Utc>),
+    BlockedForever,
+}
+
+/// A set of vote limits that must all be satisfied for a vote to be accepted,
+/// e.g. 5 votes per day and 20 votes per calendar week.
+///
+/// Calendar windows start at local midnight in the time zone of the policy,
+/// which follows daylight saving time, so a day can last 23 or 25 hours. A
+/// policy without a time zone uses a fixed offset from UTC instead, which is
+/// UTC unless set otherwise. Users in different time zones can be given
+/// policies of their own with `User::set_limit_policy`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitPolicy {
+    limits: Vec<VoteLimit>,
+    #[serde(rename = "utc_offset_seconds")]
+    fixed_utc_offset_seconds: i32,
+    #[serde(default)]
+    time_zone: Option<Tz>,
+}
+impl LimitPolicy {
+    /// Creates a policy without any limit, with calendar windows in UTC.
+    pub fn new() -> Self {
+        LimitPolicy::default()
+    }
+
+    pub fn with_limit(mut self, max_votes: u32, window: LimitWindow) -> Self {
+        self.limits.push(VoteLimit::new(max_votes, window));
+        self
+    }
+
+    /// Sets the offset from UTC, in seconds, used to align calendar windows
+    /// when the policy has no time zone. The offset is the same all year
+    /// round.
+    pub fn with_fixed_utc_offset(mut self, seconds: i32) -> Self {
+        self.fixed_utc_offset_seconds = seconds;
+        self
+    }
+
+    /// Sets the time zone used to align calendar windows, e.g.
+    /// `chrono_tz::Europe::Rome`. It takes precedence over the fixed offset.
+    pub fn with_time_zone(mut self, time_zone: Tz) -> Self {
+        self.time_zone = Some(time_zone);
+        self
+    }
+
+    pub fn limits(&self) -> &Vec<VoteLimit> {
+        &self.limits
+    }
+    pub fn fixed_utc_offset_seconds(&self) -> i32 {
+        self.fixed_utc_offset_seconds
+    }
+    pub fn time_zone(&self) -> Option<Tz> {
+        self.time_zone
+    }
+    pub fn is_limited(&self) -> bool {
+        !self.limits.is_empty()
+    }
+
+    /// Returns the earliest point in time that any of the limits looks at.
+    /// Votes cast before this moment never affect the outcome of `check`.
+    pub fn window_start(&self, now: &DateTime<Utc>) -> Result<DateTime<Utc>, Error> {
+        let mut earliest = *now;
+        for limit in &self.limits {
+            let start = self.limit_window(limit.window, now)?.0;
+            if start < earliest {
+                earliest = start;
+            }
+        }
+        Ok(earliest)
+    }
+
+    /// Checks whether one more vote is allowed at `now`, given the times of
+    /// the votes already cast since `window_start(now)`.
+    ///
+    /// When the vote is not allowed, the result says when the user can vote
+    /// again, i.e. when all the exceeded limits will have room for one more.
+    pub fn check(
+        &self,
+        now: &DateTime<Utc>,
+        vote_times: &[DateTime<Utc>],
+    ) -> Result<LimitCheck, Error> {
+        let mut next_allowed: Option<DateTime<Utc>> = None;
+        for limit in &self.limits {
+            let (start, end) = self.limit_window(limit.window, now)?;
+            let mut in_window: Vec<&DateTime<Utc>> =
+                vote_times.iter().filter(|t| **t >= start).collect();
+            if in_window.len() < limit.max_votes as usize {
+                continue;
+            }
+            if limit.max_votes == 0 {
+                return Ok(LimitCheck::BlockedForever);
+            }
+            let allowed_at = match limit.window {
+                LimitWindow::RollingDays(days) => {
+                    // Enough of the oldest votes must fall out of the window
+                    // to make room for one more.
+                    in_window.sort();
+                    let oldest = in_window[in_window.len() - limit.max_votes as usize];
+                    oldest
+                        .checked_add_days(Days::new(days as u64))
+                        .ok_or(Error::generic("date wrap-around"))?
+                }
+                _ => end,
+            };
+            if next_allowed.is_none_or(|t| allowed_at > t) {
+                next_allowed = Some(allowed_at);
+            }
+        }
+        Ok(match next_allowed {
+            Some(t) => LimitCheck::BlockedUntil(t),
+            None => LimitCheck::Allowed,
+        })
+    }
+
+    // Returns the start and end of the window containing `now`.
+    fn limit_window(
+        &self,
+        window: LimitWindow,
+        now: &DateTime<Utc>,
+    ) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+        match self.time_zone {
+            Some(time_zone) => zoned_window(&time_zone, window, now),
+            None => {
+                let offset = FixedOffset::east_opt(self.fixed_utc_offset_seconds)
+                    .ok_or(Error::generic("invalid utc offset in limit policy"))?;
+                zoned_window(&offset, window, now)
+            }
+        }
+    }
+}
+
+// Returns the start and end of the window containing `now`, with calendar
+// days starting at local midnight in `zone`.
+fn zoned_window<Z: TimeZone>(
+    zone: &Z,
+    window: LimitWindow,
+    now: &DateTime<Utc>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+    let today = now.with_timezone(zone).date_naive();
+    let (start, end) = match window {
+        LimitWindow::RollingDays(days) => {
+            let start = now
+                .checked_sub_days(Days::new(days as u64))
+                .ok_or(Error::generic("date wrap-around"))?;
+            return Ok((start, *now));
+        }
+        LimitWindow::Day => (today, today.checked_add_days(Days::new(1))),
+        LimitWindow::CalendarWeek => {
+            let monday = today
+                .checked_sub_days(Days::new(today.weekday().num_days_from_monday() as u64))
+                .ok_or(Error::generic("date wrap-around"))?;
+            (monday, monday.checked_add_days(Days::new(7)))
+        }
+        LimitWindow::CalendarMonth => {
+            let first = NaiveDate::from_ymd_opt(today.year(), today.month(), 1)
+                .ok_or(Error::generic("invalid date"))?;
+            (first, first.checked_add_months(Months::new(1)))
+        }
+    };
+    let end = end.ok_or(Error::generic("date wrap-around"))?;
+    Ok((local_midnight(zone, &start)?, local_midnight(zone, &end)?))
+}
+
+// Returns the moment a day starts in `zone`. When the clocks go back over
+// midnight the day starts at the first midnight; when they skip it, it
+// starts as they move forward, which is midnight at the offset in force
+// before.
+fn local_midnight<Z: TimeZone>(zone: &Z, date: &NaiveDate) -> Result<DateTime<Utc>, Error> {
+    let naive = date
+        .and_hms_opt(0, 0, 0)
+        .ok_or(Error::generic("invalid date"))?;
+    match zone.from_local_datetime(&naive) {
+        LocalResult::Single(t) | LocalResult::Ambiguous(t, _) => Ok(t.with_timezone(&Utc)),
+        LocalResult::None => {
+            let before = zone
+                .offset_from_utc_datetime(&(naive - TimeDelta::days(1)))
+                .fix();
+            Ok(Utc.from_utc_datetime(&(naive - before)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limits::{LimitCheck, LimitPolicy, LimitWindow};
+
+    use chrono::{DateTime, TimeZone, Utc};
+    use chrono_tz::America::Santiago;
+    use chrono_tz::Europe::Rome;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_rolling_limit() {
+        let policy = LimitPolicy::new().with_limit(2, LimitWindow::RollingDays(7));
+        let now = at(2023, 6, 10, 12);
+        assert_eq!(policy.window_start(&now).unwrap(), at(2023, 6, 3, 12));
+
+        let votes = vec![at(2023, 6, 5, 9)];
+        assert_eq!(policy.check(&now, &votes).unwrap(), LimitCheck::Allowed);
+
+        let votes = vec![at(2023, 6, 8, 9), at(2023, 6, 5, 9)];
+        let result = policy.check(&now, &votes).unwrap();
+        assert_eq!(result, LimitCheck::BlockedUntil(at(2023, 6, 12, 9)));
+    }
+
+    #[test]
+    fn test_calendar_limits() {
+        // 2023-06-10 is a Saturday.
+        let now = at(2023, 6, 10, 12);
+        let day = LimitPolicy::new().with_limit(1, LimitWindow::Day);
+        let result = day.check(&now, &[at(2023, 6, 10, 1)]).unwrap();
+        assert_eq!(result, LimitCheck::BlockedUntil(at(2023, 6, 11, 0)));
+        assert_eq!(
+            day.check(&now, &[at(2023, 6, 9, 23)]).unwrap(),
+            LimitCheck::Allowed
+        );
+
+        let week = LimitPolicy::new().with_limit(1, LimitWindow::CalendarWeek);
+        assert_eq!(week.window_start(&now).unwrap(), at(2023, 6, 5, 0));
+        let result = week.check(&now, &[at(2023, 6, 5, 1)]).unwrap();
+        assert_eq!(result, LimitCheck::BlockedUntil(at(2023, 6, 12, 0)));
+
+        let month = LimitPolicy::new().with_limit(1, LimitWindow::CalendarMonth);
+        let result = month.check(&now, &[at(2023, 6, 1, 1)]).unwrap();
+        assert_eq!(result, LimitCheck::BlockedUntil(at(2023, 7, 1, 0)));
+    }
+
+    #[test]
+    fn test_time_zone_and_combined_limits() {
+        // At 23:00 UTC it is already the next day at UTC+2.
+        let now = at(2023, 6, 10, 23);
+        let policy = LimitPolicy::new()
+            .with_limit(1, LimitWindow::Day)
+            .with_limit(3, LimitWindow::CalendarWeek)
+            .with_fixed_utc_offset(2 * 3600);
+        assert_eq!(
+            policy.check(&now, &[at(2023, 6, 10, 12)]).unwrap(),
+            LimitCheck::Allowed
+        );
+        let result = policy.check(&now, &[at(2023, 6, 10, 22)]).unwrap();
+        assert_eq!(result, LimitCheck::BlockedUntil(at(2023, 6, 11, 22)));
+
+        let votes = vec![at(2023, 6, 6, 12), at(2023, 6, 7, 12), at(2023, 6, 8, 12)];
+        let result = policy.check(&now, &votes).unwrap();
+        assert_eq!(result, LimitCheck::BlockedUntil(at(2023, 6, 11, 22)));
+
+        let zero = LimitPolicy::new().with_limit(0, LimitWindow::Day);
+        assert_eq!(zero.check(&now, &[]).unwrap(), LimitCheck::BlockedForever);
+    }
+
+    #[test]
+    fn test_fixed_offset_across_dst_change() {
+        // Central Europe moved from UTC+1 to UTC+2 on 2024-03-31. A vote at
+        // 22:30 UTC that night was cast at 00:30 on April 1st, local time.
+        let now = at(2024, 4, 1, 12);
+        let vote = Utc.with_ymd_and_hms(2024, 3, 31, 22, 30, 0).unwrap();
+
+        // A policy at +01:00 still starts the day at 23:00 UTC.
+        let winter = LimitPolicy::new()
+            .with_limit(1, LimitWindow::Day)
+            .with_fixed_utc_offset(3600);
+        assert_eq!(winter.window_start(&now).unwrap(), at(2024, 3, 31, 23));
+        assert_eq!(winter.check(&now, &[vote]).unwrap(), LimitCheck::Allowed);
+
+        // Following the local time takes a policy at +02:00.
+        let summer = LimitPolicy::new()
+            .with_limit(1, LimitWindow::Day)
+            .with_fixed_utc_offset(2 * 3600);
+        assert_eq!(summer.window_start(&now).unwrap(), at(2024, 3, 31, 22));
+        assert_eq!(
+            summer.check(&now, &[vote]).unwrap(),
+            LimitCheck::BlockedUntil(at(2024, 4, 1, 22))
+        );
+    }
+
+    #[test]
+    fn test_time_zone_across_dst_changes() {
+        let now = at(2024, 4, 1, 12);
+        let vote = Utc.with_ymd_and_hms(2024, 3, 31, 22, 30, 0).unwrap();
+        let day = LimitPolicy::new()
+            .with_limit(1, LimitWindow::Day)
+            .with_time_zone(Rome);
+        assert_eq!(day.time_zone(), Some(Rome));
+
+        // Days start at 23:00 UTC in winter and at 22:00 UTC in summer.
+        assert_eq!(
+            day.window_start(&at(2024, 1, 15, 12)).unwrap(),
+            at(2024, 1, 14, 23)
+        );
+        assert_eq!(day.window_start(&now).unwrap(), at(2024, 3, 31, 22));
+        assert_eq!(
+            day.check(&now, &[vote]).unwrap(),
+            LimitCheck::BlockedUntil(at(2024, 4, 1, 22))
+        );
+
+        // The clocks went back on 2024-10-27, so that week lasted 169 hours.
+        let week = LimitPolicy::new()
+            .with_limit(1, LimitWindow::CalendarWeek)
+            .with_time_zone(Rome);
+        let now = at(2024, 10, 25, 12);
+        assert_eq!(week.window_start(&now).unwrap(), at(2024, 10, 20, 22));
+        assert_eq!(
+            week.check(&now, &[at(2024, 10, 21, 12)]).unwrap(),
+            LimitCheck::BlockedUntil(at(2024, 10, 27, 23))
+        );
+
+        // Chile skipped midnight on 2022-09-11, going from 00:00 at UTC-4
+        // straight to 01:00 at UTC-3.
+        let day = LimitPolicy::new()
+            .with_limit(1, LimitWindow::Day)
+            .with_time_zone(Santiago);
+        assert_eq!(
+            day.window_start(&at(2022, 9, 11, 12)).unwrap(),
+            at(2022, 9, 11, 4)
+        );
+    }
+}
@@ -2,13 +2,85 @@ use chrono::{DateTime, Utc};
 use url::Url;
 use uuid::Uuid;
 
-use crate::data::{Rating, Task, User, Vote};
+use crate::data::{
+    PairTally, Rating, RatingChange, Retraction, Task, TaskVersion, User, Vote, VoteArchive,
+};
 use crate::elo::Outcome;
 use crate::errors::Error;
-
+use crate::query::{TaskOrder, TaskQuery, TaskStatus, UserQuery};
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod builder;
+mod decorators;
+mod events;
+mod json;
+mod migrations;
+mod pool;
+#[cfg(feature = "postgres")]
+mod postgresql;
+
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncPersistence, BlockingPersistence};
+pub use self::builder::SQLitePersistenceBuilder;
+pub use self::decorators::{
+    CachingPersistence, LoggingPersistence, PersistenceCall, ReadOnlyPersistence,
+};
+pub use self::events::{Event, EventLogPersistence, RecordedEvent};
+pub use self::json::JsonFilePersistence;
+use self::migrations::DEFAULT_TABLE_PREFIX;
+pub use self::migrations::SCHEMA_VERSION;
+pub use self::pool::PooledSQLitePersistence;
+#[cfg(feature = "postgres")]
+pub use self::postgresql::{PostgresPersistence, POSTGRES_SCHEMA_VERSION};
+
+/// Identifies the state of the data a snapshot was taken from.
+///
+/// The token changes with every write, and is what retractions are checked
+/// against since they recompute the whole ranking. A vote only depends on the
+/// ratings of its two tasks, so it is checked against their revisions: it is
+/// rejected only if one of them was rated again after the snapshot.
 #[derive(Debug, Clone)]
 pub struct Etag {
     pub token: String,
+    revisions: HashMap<Uuid, u64>,
+}
+impl Etag {
+    pub fn new(token: &str) -> Self {
+        Etag::with_revisions(token, HashMap::new())
+    }
+
+    pub fn with_revisions(token: &str, revisions: HashMap<Uuid, u64>) -> Self {
+        Etag {
+            token: token.to_string(),
+            revisions,
+        }
+    }
+
+    /// The revision of the rating of a task, or None if the task had no
+    /// rating when the snapshot was taken.
+    pub fn revision(&self, t_id: &Uuid) -> Option<u64> {
+        self.revisions.get(t_id).copied()
+    }
+
+    // Fails unless the ratings are based on the current revisions of their
+    // tasks, as given by `current`.
+    fn check_revisions(
+        &self,
+        ratings: [&Rating; 2],
+        mut current: impl FnMut(&Uuid) -> Result<Option<u64>, Error>,
+    ) -> Result<(), Error> {
+        for rating in ratings {
+            match current(rating.task())? {
+                None => return Err(Error::task_not_found(rating.task())),
+                Some(revision) if self.revision(rating.task()) != Some(revision) => {
+                    return Err(Error::retry_transaction())
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,14 +110,38 @@ pub trait Persistence {
         since: &DateTime<Utc>,
     ) -> Result<usize, Error>;
 
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error>;
+
     fn list_tasks(&self) -> Result<Vec<Task>, Error>;
 
-    fn upsert_task(&mut self, t: &Task) -> Result<(), Error>;
+    /// Reads a single task. The default implementation reads every task and
+    /// looks for it among them.
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.list_tasks()?
+            .into_iter()
+            .find(|t| t.id() == t_id)
+            .ok_or(Error::task_not_found(t_id))
+    }
+
+    /// Inserts or updates a task. Whenever the summary or the link change, a
+    /// new version of the task is recorded, attributed to `author`.
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error>;
+
+    /// Lists all the versions of a task, oldest first.
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error>;
 
     fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error>;
 
     fn get_snapshot(&mut self) -> Result<Snapshot, Error>;
 
+    /// The token of the current etag, which changes with every write. It is
+    /// cheaper to read than a snapshot, and is what caches are keyed on.
+    fn get_etag_token(&self) -> Result<String, Error>;
+
     fn add_vote_and_update_ratings(
         &mut self,
         etag: &Etag,
@@ -53,17 +149,157 @@ pub trait Persistence {
         r0: &Rating,
         r1: &Rating,
     ) -> Result<(), Error>;
+
+    /// Lists all the votes that were not compacted, including the retracted
+    /// ones, in time order.
+    fn list_votes(&self) -> Result<Vec<Vote>, Error>;
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error>;
+
+    /// Lists the votes in which the task took part, in time order.
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error>;
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error>;
+
+    /// Lists the retractions a user made since `since`, which include every
+    /// retraction of the votes they cast since then. The default
+    /// implementation reads every retraction and filters them in memory.
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        Ok(self
+            .list_retractions()?
+            .into_iter()
+            .filter(|r| r.voter() == u_id && r.time() >= since)
+            .collect())
+    }
+
+    /// Records a retraction and, if the retraction is a correction, the vote
+    /// that replaces the retracted one. The given ratings replace the stored
+    /// ones, as they are recomputed from the remaining votes.
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error>;
+
+    /// Lists the changes of the rating of a task, oldest first. Every vote on
+    /// the task records one, and so does every retraction that changes it.
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error>;
+
+    /// The ranking as it stood at `time`, sorted like the one of a snapshot:
+    /// every task that existed then, with the rating set by its last change
+    /// at or before `time`. A task exists from the time of its first version.
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error>;
+
+    /// The archive that compacted votes were folded into. The default
+    /// implementation returns an empty archive, for backends that never
+    /// compact votes.
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        Ok(VoteArchive::new())
+    }
+
+    /// Replaces the vote archive, and deletes the votes it covers: the ones
+    /// cast before its cutoff, with their retractions. It is checked against
+    /// the etag token, like a retraction. The default implementation fails
+    /// with `NotImplemented`, for backends that keep every vote.
+    fn compact_votes(&mut self, _etag: &Etag, _archive: &VoteArchive) -> Result<(), Error> {
+        Err(Error::not_implemented(
+            "this backend does not support vote compaction",
+        ))
+    }
+
+    /// Lists the tasks selected by a query. The default implementation reads
+    /// every task and filters them in memory.
+    fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        Ok(query.apply(self.list_tasks()?))
+    }
+
+    /// Counts the tasks selected by a query, regardless of its limit and
+    /// offset.
+    fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+        Ok(self
+            .list_tasks()?
+            .iter()
+            .filter(|t| query.matches(t))
+            .count())
+    }
+
+    /// Lists the users selected by a query. The default implementation reads
+    /// every user and filters them in memory.
+    fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+        Ok(query.apply(self.list_users()?))
+    }
+
+    /// Starts a transaction: the writes that follow are stored together by
+    /// `commit_transaction`, or undone together by `rollback_transaction`.
+    /// Transactions do not nest. The default implementation fails with
+    /// `NotImplemented`, for backends that cannot undo their writes.
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        Err(Error::not_implemented(
+            "this backend does not support transactions",
+        ))
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        Err(Error::not_implemented(
+            "this backend does not support transactions",
+        ))
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        Err(Error::not_implemented(
+            "this backend does not support transactions",
+        ))
+    }
+
+    /// Runs `f` in a transaction, which is committed if `f` succeeds and
+    /// rolled back if it fails.
+    fn transaction<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error>
+    where
+        Self: Sized,
+    {
+        self.begin_transaction()?;
+        match f(self) {
+            Ok(value) => {
+                self.commit_transaction()?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.rollback_transaction()?;
+                Err(e)
+            }
+        }
+    }
 }
 
 // --- Implementations --------------------------------------------------------
 
-use std::collections::HashMap;
+use rusqlite::OptionalExtension;
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
+#[derive(Clone)]
 struct InMemoryInner {
     users: HashMap<String, User>,
     tasks: HashMap<Uuid, Task>,
     current_ranking: HashMap<Uuid, f32>,
     votes: Vec<Vote>,
+    retractions: Vec<Retraction>,
+    task_versions: Vec<TaskVersion>,
+    rating_changes: Vec<RatingChange>,
+    archive: VoteArchive,
+    // Counts the writes, and serves as the etag token.
+    generation: u64,
 }
 impl InMemoryInner {
     fn new() -> Self {
@@ -72,16 +308,164 @@ impl InMemoryInner {
             tasks: HashMap::new(),
             current_ranking: HashMap::new(),
             votes: Vec::new(),
+            retractions: Vec::new(),
+            task_versions: Vec::new(),
+            rating_changes: Vec::new(),
+            archive: VoteArchive::new(),
+            generation: 0,
+        }
+    }
+
+    fn etag(&self) -> Etag {
+        let retractions = self.retractions.len() as u64 + self.archive.retractions() as u64;
+        let mut revisions: HashMap<Uuid, u64> = self
+            .current_ranking
+            .keys()
+            .map(|t| (*t, retractions + self.archive.num_votes_for_task(t)))
+            .collect();
+        for vote in &self.votes {
+            for t in [vote.task0(), vote.task1()] {
+                if let Some(revision) = revisions.get_mut(t) {
+                    *revision += 1;
+                }
+            }
+        }
+        Etag::with_revisions(&self.generation.to_string(), revisions)
+    }
+
+    // A rating changes with every vote on its task, and every retraction
+    // since those recompute all the ratings. Compacted votes and retractions
+    // still count, so that revisions never go back.
+    fn revision(&self, t_id: &Uuid) -> Option<u64> {
+        self.current_ranking.get(t_id)?;
+        let votes = self
+            .votes
+            .iter()
+            .filter(|v| v.task0() == t_id || v.task1() == t_id)
+            .count() as u64;
+        let retractions = self.retractions.len() as u64 + self.archive.retractions() as u64;
+        Some(votes + retractions + self.archive.num_votes_for_task(t_id))
+    }
+
+    // Records a vote as if it was cast at `time`, which is the time of the
+    // rating changes.
+    fn add_vote_at(
+        &mut self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+        time: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        etag.check_revisions([r0, r1], |t| Ok(self.revision(t)))?;
+        for rating in [r0, r1] {
+            self.update_rating(rating, time, vote.id(), false);
+        }
+        self.votes.push(vote.clone());
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Records a retraction as if it was made at `time`, which is the time
+    // of the rating changes.
+    fn retract_vote_at(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+        time: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        if etag.token != self.etag().token {
+            return Err(Error::retry_transaction());
+        }
+        self.get_vote(retraction.vote())?;
+        if self
+            .retractions
+            .iter()
+            .any(|r| r.vote() == retraction.vote())
+        {
+            return Err(Error::retraction_not_allowed(
+                retraction.vote(),
+                "already retracted",
+            ));
+        }
+        for rating in ratings {
+            if !self.tasks.contains_key(rating.task()) {
+                return Err(Error::task_not_found(rating.task()));
+            }
+        }
+        for rating in ratings {
+            self.update_rating(rating, time, retraction.vote(), true);
+        }
+        self.retractions.push(retraction.clone());
+        if let Some(vote) = replacement {
+            self.votes.push(vote.clone());
+        }
+        self.generation += 1;
+        Ok(())
+    }
+
+    // Stores a rating, recording the change unless a retraction left it as
+    // it was.
+    fn update_rating(
+        &mut self,
+        rating: &Rating,
+        time: DateTime<Utc>,
+        vote: &Uuid,
+        retraction: bool,
+    ) {
+        let old_elo = self
+            .current_ranking
+            .insert(*rating.task(), rating.elo())
+            .unwrap_or(Rating::new(*rating.task()).elo());
+        if !retraction || old_elo != rating.elo() {
+            self.rating_changes.push(RatingChange::new(
+                *rating.task(),
+                time,
+                old_elo,
+                rating.elo(),
+                *vote,
+                retraction,
+            ));
+        }
+    }
+
+    // Stores a task as if it was upserted at `time`, which is the time of
+    // the new version if one is recorded.
+    fn upsert_task_at(&mut self, t: &Task, author: &str, time: DateTime<Utc>) -> Result<(), Error> {
+        let current = self.tasks.get(t.id());
+        let mut version = current.map(|c| c.version()).unwrap_or(0);
+        if let Some(v) = next_task_version(current, t) {
+            version = v;
+            self.task_versions.push(TaskVersion::new(
+                *t.id(),
+                version,
+                time,
+                author,
+                t.summary(),
+                t.link().clone(),
+            ));
         }
+        let mut stored =
+            Task::with_version(*t.id(), t.summary(), t.link().clone(), t.closed(), version);
+        stored.set_tags(t.tags());
+        self.tasks.insert(*t.id(), stored);
+        self.current_ranking
+            .entry(*t.id())
+            .or_insert(Rating::new(*t.id()).elo());
+        self.generation += 1;
+        Ok(())
     }
 }
 impl Persistence for InMemoryInner {
     fn list_users(&self) -> Result<Vec<User>, Error> {
-        Ok(self.users.iter().map(|(_, v)| v).cloned().collect())
+        Ok(self.users.values().cloned().collect())
     }
 
     fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
         self.users.insert(u.id().to_string(), u.clone());
+        self.generation += 1;
         Ok(())
     }
 
@@ -106,15 +490,43 @@ impl Persistence for InMemoryInner {
             .count())
     }
 
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        Ok(self
+            .votes
+            .iter()
+            .filter(|v| v.time() >= since)
+            .filter(|v| v.voter() == u_id)
+            .cloned()
+            .collect())
+    }
+
     fn list_tasks(&self) -> Result<Vec<Task>, Error> {
-        Ok(self.tasks.iter().map(|(_, v)| v).cloned().collect())
+        Ok(self.tasks.values().cloned().collect())
     }
 
-    fn upsert_task(&mut self, t: &Task) -> Result<(), Error> {
-        self.tasks.insert(t.id().clone(), t.clone());
-        self.current_ranking
-            .insert(t.id().clone(), Rating::new(t.id().clone()).elo());
-        Ok(())
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        Ok(self
+            .tasks
+            .get(t_id)
+            .ok_or(Error::task_not_found(t_id))?
+            .clone())
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        self.upsert_task_at(t, author, SystemTime::now().into())
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        Ok(self
+            .task_versions
+            .iter()
+            .filter(|v| v.task() == t_id)
+            .cloned()
+            .collect())
     }
 
     fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
@@ -122,32 +534,26 @@ impl Persistence for InMemoryInner {
             .get_mut(t_id)
             .ok_or(Error::task_not_found(t_id))?
             .close();
+        self.generation += 1;
         Ok(())
     }
 
     fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
-        let mut has_nans = false;
-        let mut ranking: Vec<Rating> = self
+        let ranking = self
             .current_ranking
             .iter()
-            .map(|(k, v)| {
-                if v.is_nan() {
-                    has_nans = true;
-                }
-                Rating::with_elo(k.clone(), v.clone())
-            })
+            .map(|(k, v)| Rating::with_elo(*k, *v))
             .collect();
-        if !has_nans {
-            ranking.sort_by(|a, b| a.elo().partial_cmp(&b.elo()).unwrap());
-        }
         Ok(Snapshot {
-            ranking: ranking,
-            etag: Etag {
-                token: format!("{}", self.votes.len()),
-            },
+            ranking: sorted_ranking(ranking),
+            etag: self.etag(),
         })
     }
 
+    fn get_etag_token(&self) -> Result<String, Error> {
+        Ok(self.generation.to_string())
+    }
+
     fn add_vote_and_update_ratings(
         &mut self,
         etag: &Etag,
@@ -155,36 +561,162 @@ impl Persistence for InMemoryInner {
         r0: &Rating,
         r1: &Rating,
     ) -> Result<(), Error> {
-        let etag_usize: usize = etag
-            .token
-            .parse()
-            .map_err(|_| Error::generic("etag parse error"))?;
-        if etag_usize != self.votes.len() {
+        self.add_vote_at(etag, vote, r0, r1, SystemTime::now().into())
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        let mut votes = self.votes.clone();
+        votes.sort_by_key(|v| *v.time());
+        Ok(votes)
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        Ok(self
+            .votes
+            .iter()
+            .find(|v| v.id() == v_id)
+            .ok_or(Error::vote_not_found(v_id))?
+            .clone())
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        let mut votes: Vec<Vote> = self
+            .votes
+            .iter()
+            .filter(|v| v.task0() == t_id || v.task1() == t_id)
+            .cloned()
+            .collect();
+        votes.sort_by_key(|v| *v.time());
+        Ok(votes)
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        Ok(self.retractions.clone())
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        Ok(self
+            .retractions
+            .iter()
+            .filter(|r| r.time() >= since)
+            .filter(|r| r.voter() == u_id)
+            .cloned()
+            .collect())
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        self.retract_vote_at(
+            etag,
+            retraction,
+            replacement,
+            ratings,
+            SystemTime::now().into(),
+        )
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        Ok(self
+            .rating_changes
+            .iter()
+            .filter(|c| c.task() == t_id)
+            .cloned()
+            .collect())
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        Ok(self.archive.clone())
+    }
+
+    fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        if etag.token != self.etag().token {
             return Err(Error::retry_transaction());
         }
-        if !self.tasks.contains_key(r0.task()) {
-            return Err(Error::task_not_found(r0.task()));
-        }
-        if !self.tasks.contains_key(r1.task()) {
-            return Err(Error::task_not_found(r1.task()));
-        }
-        self.current_ranking.insert(r0.task().clone(), r0.elo());
-        self.current_ranking.insert(r1.task().clone(), r1.elo());
-        self.votes.push(vote.clone());
+        let cutoff = archive_cutoff(archive)?;
+        let compacted: HashSet<Uuid> = self
+            .votes
+            .iter()
+            .filter(|v| v.time() < cutoff)
+            .map(|v| *v.id())
+            .collect();
+        self.votes.retain(|v| !compacted.contains(v.id()));
+        self.retractions.retain(|r| !compacted.contains(r.vote()));
+        self.archive = archive.clone();
+        self.generation += 1;
         Ok(())
     }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        let mut ranking: HashMap<Uuid, f32> = self
+            .current_ranking
+            .keys()
+            .filter(|t| {
+                !self
+                    .task_versions
+                    .iter()
+                    .any(|v| v.task() == *t && v.version() == 1 && v.time() > time)
+            })
+            .map(|t| (*t, Rating::new(*t).elo()))
+            .collect();
+        for change in self.rating_changes.iter().filter(|c| c.time() <= time) {
+            if let Some(elo) = ranking.get_mut(change.task()) {
+                *elo = change.new_elo();
+            }
+        }
+        Ok(sorted_ranking(
+            ranking
+                .into_iter()
+                .map(|(t, elo)| Rating::with_elo(t, elo))
+                .collect(),
+        ))
+    }
+}
+
+// The cutoff of an archive that votes are compacted into, which must have one.
+fn archive_cutoff(archive: &VoteArchive) -> Result<&DateTime<Utc>, Error> {
+    archive
+        .cutoff()
+        .ok_or_else(|| Error::generic("votes cannot be compacted into an empty archive"))
+}
+
+// Sorts a ranking from the lowest to the highest rating, unless some ratings
+// are not numbers.
+fn sorted_ranking(mut ranking: Vec<Rating>) -> Vec<Rating> {
+    if !ranking.iter().any(|r| r.elo().is_nan()) {
+        ranking.sort_by(|a, b| a.elo().partial_cmp(&b.elo()).unwrap());
+    }
+    ranking
 }
 
+/// A backend that keeps everything in memory, for tests and short-lived
+/// rankings. Nothing is written to disk, and it is lost when dropped.
 pub struct InMemory {
     data: std::sync::Mutex<InMemoryInner>,
+    // The data as it was when the transaction in progress began.
+    before_transaction: Option<InMemoryInner>,
 }
 impl InMemory {
     pub fn new() -> Self {
         InMemory {
             data: std::sync::Mutex::new(InMemoryInner::new()),
+            before_transaction: None,
         }
     }
 }
+impl Default for InMemory {
+    fn default() -> Self {
+        InMemory::new()
+    }
+}
 impl Persistence for InMemory {
     fn list_users(&self) -> Result<Vec<User>, Error> {
         self.data.lock().unwrap().list_users()
@@ -209,12 +741,31 @@ impl Persistence for InMemory {
             .get_num_votes_for_user_since(u_id, since)
     }
 
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        self.data
+            .lock()
+            .unwrap()
+            .list_votes_for_user_since(u_id, since)
+    }
+
     fn list_tasks(&self) -> Result<Vec<Task>, Error> {
         self.data.lock().unwrap().list_tasks()
     }
 
-    fn upsert_task(&mut self, t: &Task) -> Result<(), Error> {
-        self.data.lock().unwrap().upsert_task(t)
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.data.lock().unwrap().get_task(t_id)
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        self.data.lock().unwrap().upsert_task(t, author)
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        self.data.lock().unwrap().list_task_versions(t_id)
     }
 
     fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
@@ -225,6 +776,10 @@ impl Persistence for InMemory {
         self.data.lock().unwrap().get_snapshot()
     }
 
+    fn get_etag_token(&self) -> Result<String, Error> {
+        self.data.lock().unwrap().get_etag_token()
+    }
+
     fn add_vote_and_update_ratings(
         &mut self,
         etag: &Etag,
@@ -237,310 +792,1726 @@ impl Persistence for InMemory {
             .unwrap()
             .add_vote_and_update_ratings(etag, vote, r0, r1)
     }
-}
-
-use rusqlite;
-
-pub struct SQLitePersistence {
-    connection: rusqlite::Connection,
-}
-impl SQLitePersistence {
-    pub fn new(db_path: std::path::PathBuf) -> Result<Self, Error> {
-        let mut conn = rusqlite::Connection::open(&db_path)?;
-        conn.execute(
-            "create table if not exists pelo_global_etag (
-                 id integer primary key,
-                 token text not null
-             )",
-            (),
-        )?;
-        conn.execute(
-            "create table if not exists pelo_users (
-                 id text primary key,
-                 limit_votes_per_week integer not null
-             )",
-            (),
-        )?;
-        conn.execute(
-            "create table if not exists pelo_tasks (
-                 id text primary key,
-                 summary text not null,
-                 link text,
-                 closed integer
-             )",
-            (),
-        )?;
-        conn.execute(
-            "create table if not exists pelo_ratings (
-                 task text not null,
-                 elo real not null
-             )",
-            (),
-        )?;
-        conn.execute(
-            "create table if not exists pelo_votes (
-                 voter text not null,
-                 time text not null,
-                 task0 text not null,
-                 task1 text not null,
-                 outcome integer
-             )",
-            (),
-        )?;
-        conn.execute(
-            "create index if not exists pelo_votes_by_user_and_time 
-                 on pelo_votes(voter, time)",
-            (),
-        )?;
-
-        let tx = conn.transaction()?;
-        tx.execute(
-            "insert into pelo_global_etag (id, token)
-                    values (0, ?1)
-                    on conflict(id) do update set token = ?1",
-            (Uuid::new_v4().to_string(),),
-        )?;
-        tx.commit()?;
 
-        Ok(SQLitePersistence { connection: conn })
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.data.lock().unwrap().list_votes()
     }
-}
 
-impl Persistence for SQLitePersistence {
-    fn list_users(&self) -> Result<Vec<User>, Error> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT id, limit_votes_per_week FROM pelo_users")?;
-        let mut result = Vec::new();
-        stmt.query_map([], |row| {
-            let id: String = row.get(0)?;
-            let limit: i32 = row.get(1)?;
-            Ok(User::new(&id, limit))
-        })?
-        .try_for_each(|maybe_user| -> Result<(), Error> {
-            result.push(maybe_user?);
-            Ok(())
-        })?;
-        Ok(result)
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        self.data.lock().unwrap().get_vote(v_id)
     }
 
-    fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
-        self.connection.execute(
-            "insert into pelo_users(id, limit_votes_per_week)
-             values (?1, ?2)
-             on conflict(id) do update set limit_votes_per_week = ?2",
-            (u.id(), u.limit_votes_per_week()),
-        )?;
-        Ok(())
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        self.data.lock().unwrap().list_votes_for_task(t_id)
     }
 
-    fn get_user(&self, u_id: &str) -> Result<User, Error> {
-        let mut stmt = self.connection.prepare(
-            "SELECT id, limit_votes_per_week FROM pelo_users
-             WHERE id = ?1",
-        )?;
-        let mut result = Vec::new();
-        stmt.query_map(rusqlite::params![u_id], |row| {
-            let id: String = row.get(0)?;
-            let limit: i32 = row.get(1)?;
-            Ok(User::new(&id, limit))
-        })?
-        .try_for_each(|maybe_user| -> Result<(), Error> {
-            result.push(maybe_user?);
-            Ok(())
-        })?;
-        if result.len() != 1 {
-            return Err(Error::db_error("more than one user with same primary key"));
-        }
-        Ok(result[0].clone())
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.data.lock().unwrap().list_retractions()
     }
 
-    fn get_num_votes_for_user_since(
+    fn list_retractions_for_user_since(
         &self,
         u_id: &str,
         since: &DateTime<Utc>,
-    ) -> Result<usize, Error> {
-        let result: usize = self.connection.query_row(
-            "SELECT COUNT(*) FROM pelo_votes where voter = ?1 AND time > ?2",
-            [u_id, &since.to_rfc3339()],
-            |row| row.get(0),
-        )?;
-        Ok(result)
+    ) -> Result<Vec<Retraction>, Error> {
+        self.data
+            .lock()
+            .unwrap()
+            .list_retractions_for_user_since(u_id, since)
     }
 
-    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
-        let mut stmt = self
-            .connection
-            .prepare("SELECT id, summary, link, closed FROM pelo_tasks")?;
-        let mut result = Vec::new();
-        stmt.query_map([], |row| {
-            let id_: String = row.get(0)?;
-            let id: Uuid = Uuid::parse_str(&id_).unwrap();
-            let summary: String = row.get(1)?;
-            let link_: String = row.get(2)?;
-            let link: Url = Url::parse(&link_).unwrap();
-            let closed: i32 = row.get(3)?;
-            Ok(Task::new(id, &summary, link, closed != 0))
-        })?
-        .try_for_each(|maybe_task| -> Result<(), Error> {
-            result.push(maybe_task?);
-            Ok(())
-        })?;
-        Ok(result)
-    }
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        self.data.lock().unwrap().retract_vote_and_replace_ratings(
+            etag,
+            retraction,
+            replacement,
+            ratings,
+        )
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        self.data.lock().unwrap().list_rating_changes(t_id)
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        self.data.lock().unwrap().get_ranking_at(time)
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        self.data.lock().unwrap().get_vote_archive()
+    }
+
+    fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        self.data.lock().unwrap().compact_votes(etag, archive)
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        if self.before_transaction.is_some() {
+            return Err(Error::transaction_in_progress());
+        }
+        self.before_transaction = Some(self.data.lock().unwrap().clone());
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        self.before_transaction
+            .take()
+            .ok_or_else(Error::no_transaction)?;
+        Ok(())
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        let before = self
+            .before_transaction
+            .take()
+            .ok_or_else(Error::no_transaction)?;
+        *self.data.lock().unwrap() = before;
+        Ok(())
+    }
+}
+
+/// What `SQLitePersistence` does when a row cannot be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptRowPolicy {
+    /// Fail the whole read with `ErrorCode::CorruptData`.
+    Fail,
+    /// Leave the row out of the result and report it through
+    /// `SQLitePersistence::take_skipped_rows`.
+    Skip,
+}
+
+/// The reason why a row is reported by an integrity check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowProblem {
+    /// The row cannot be decoded, e.g. because of an invalid uuid or url.
+    Malformed(String),
+    /// A rating for a task that does not exist.
+    OrphanedRating,
+    /// A vote that refers to a task that does not exist.
+    UnknownTask(String),
+    /// A vote cast by a user that does not exist.
+    UnknownUser(String),
+    /// A retraction of a vote that does not exist.
+    UnknownVote(String),
+}
+
+impl fmt::Display for RowProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowProblem::Malformed(reason) => write!(f, "malformed row: {}", reason),
+            RowProblem::OrphanedRating => write!(f, "rating of an unknown task"),
+            RowProblem::UnknownTask(t_id) => write!(f, "refers to unknown task {}", t_id),
+            RowProblem::UnknownUser(u_id) => write!(f, "refers to unknown user {}", u_id),
+            RowProblem::UnknownVote(v_id) => write!(f, "refers to unknown vote {}", v_id),
+        }
+    }
+}
+
+/// A row of the database that is malformed or refers to missing data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRow {
+    table: String,
+    row: i64,
+    problem: RowProblem,
+}
+impl CorruptRow {
+    pub fn new(table: &str, row: i64, problem: RowProblem) -> Self {
+        CorruptRow {
+            table: table.to_string(),
+            row,
+            problem,
+        }
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+    /// The SQLite rowid of the row.
+    pub fn row(&self) -> i64 {
+        self.row
+    }
+    pub fn problem(&self) -> &RowProblem {
+        &self.problem
+    }
+}
+
+/// A SQLite database, which may hold the data of several tenants.
+///
+/// Every row belongs to a tenant, and a `SQLitePersistence` only reads and
+/// writes the rows of its own, with an etag of their own. Ids are keys within
+/// a tenant, so the same user may belong to several tenants, with a role and
+/// a limit in each. `new` opens the default tenant, whose id is empty.
+///
+/// `SQLitePersistenceBuilder` opens databases with other options.
+pub struct SQLitePersistence {
+    connection: rusqlite::Connection,
+    namespace: Namespace,
+    corrupt_row_policy: CorruptRowPolicy,
+    skipped_rows: RefCell<Vec<CorruptRow>>,
+    #[cfg(feature = "sqlcipher")]
+    encrypted: bool,
+}
+impl SQLitePersistence {
+    /// Opens or creates a database, upgrading its schema if necessary.
+    pub fn new(db_path: std::path::PathBuf) -> Result<Self, Error> {
+        SQLitePersistenceBuilder::new(db_path).open()
+    }
+
+    /// Opens or creates a database like `new`, to read and write the data of
+    /// `tenant`.
+    pub fn with_tenant(db_path: std::path::PathBuf, tenant: &str) -> Result<Self, Error> {
+        SQLitePersistenceBuilder::new(db_path)
+            .with_tenant(tenant)
+            .open()
+    }
+
+    /// Opens or creates a database encrypted with SQLCipher, like `new`.
+    /// Fails with `WrongKey` if `key` is not the key of an existing database,
+    /// or if the file is not encrypted. Opening an encrypted database with
+    /// `new` fails with `KeyRequired`.
+    #[cfg(feature = "sqlcipher")]
+    pub fn with_key(db_path: std::path::PathBuf, key: &str) -> Result<Self, Error> {
+        SQLitePersistenceBuilder::new(db_path).with_key(key).open()
+    }
 
-    fn upsert_task(&mut self, t: &Task) -> Result<(), Error> {
-        let rating = Rating::new(t.id().clone());
-        let transaction = self.connection.transaction()?;
+    /// Encrypts the database with a new key, which is needed to open it from
+    /// then on. The database must have been opened with a key.
+    #[cfg(feature = "sqlcipher")]
+    pub fn rekey(&mut self, key: &str) -> Result<(), Error> {
+        self.connection.pragma_update(None, "rekey", key)?;
+        Ok(())
+    }
+
+    fn with_connection(
+        mut conn: rusqlite::Connection,
+        options: &SQLitePersistenceBuilder,
+    ) -> Result<Self, Error> {
+        let namespace = Namespace {
+            prefix: options.table_prefix.clone(),
+            tenant: options.tenant.clone(),
+        };
+        if options.read_only || !options.create_schema {
+            migrations::check(&conn, &namespace.prefix)?;
+        } else {
+            migrations::migrate(&mut conn, &namespace.prefix)?;
+        }
 
+        if options.reset_etag && !options.read_only {
+            let tx = conn.transaction()?;
+            rotate_etag(&tx, &namespace)?;
+            tx.commit()?;
+        }
+
+        Ok(SQLitePersistence {
+            connection: conn,
+            namespace,
+            corrupt_row_policy: CorruptRowPolicy::Fail,
+            skipped_rows: RefCell::new(Vec::new()),
+            #[cfg(feature = "sqlcipher")]
+            encrypted: options.key.is_some(),
+        })
+    }
+
+    pub fn tenant(&self) -> &str {
+        &self.namespace.tenant
+    }
+
+    pub fn table_prefix(&self) -> &str {
+        &self.namespace.prefix
+    }
+
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        migrations::schema_version(&self.connection, &self.namespace.prefix)
+    }
+
+    pub fn corrupt_row_policy(&self) -> CorruptRowPolicy {
+        self.corrupt_row_policy
+    }
+    pub fn set_corrupt_row_policy(&mut self, policy: CorruptRowPolicy) {
+        self.corrupt_row_policy = policy;
+    }
+
+    /// Returns the rows that were left out of reads since the last call,
+    /// when the policy is `CorruptRowPolicy::Skip`.
+    pub fn take_skipped_rows(&self) -> Vec<CorruptRow> {
+        std::mem::take(&mut *self.skipped_rows.borrow_mut())
+    }
+
+    /// Copies the data of the tenant into a new database at `path`, replacing
+    /// the file if it exists. The copy is read in a single transaction, so it
+    /// reflects the tenant as it was at one moment, and other connections can
+    /// keep writing while it runs. The data of other tenants is left out; see
+    /// `backup_database` for a copy of the whole database. Like the backups
+    /// of the whole database, backups of an encrypted database are not
+    /// supported, so that their data is never written out unencrypted.
+    pub fn backup(&self, path: &Path) -> Result<(), Error> {
+        #[cfg(feature = "sqlcipher")]
+        if self.encrypted {
+            return Err(Error::not_implemented(
+                "backups of encrypted databases are not supported",
+            ));
+        }
+        let mut copy = rusqlite::Connection::open_in_memory()?;
+        migrations::migrate(&mut copy, &self.namespace.prefix)?;
+        let destination = copy.transaction()?;
+        // Within an explicit transaction, the reads already see one moment.
+        let source = if self.connection.is_autocommit() {
+            Some(self.connection.unchecked_transaction()?)
+        } else {
+            None
+        };
+        copy_tenant(&self.connection, &destination, &self.namespace)?;
+        drop(source);
+        destination.commit()?;
+        copy.backup(
+            rusqlite::DatabaseName::Main,
+            path,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the data of the tenant with its data in the backup at `path`,
+    /// which may have been made by `backup` or `backup_database`. The data of
+    /// other tenants is left untouched.
+    ///
+    /// Backups of an older schema are upgraded, and backups of a newer one
+    /// are refused with `SchemaTooNew`. The etag of the tenant is rotated and
+    /// its rating revisions are moved past the ones handed out before the
+    /// restore, so that votes based on an earlier snapshot are rejected and
+    /// retried.
+    pub fn restore(&mut self, path: &Path) -> Result<(), Error> {
+        check_backup_schema(path, &self.namespace.prefix)?;
+        let mut backup = rusqlite::Connection::open_in_memory()?;
+        backup.restore(
+            rusqlite::DatabaseName::Main,
+            path,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        migrations::migrate(&mut backup, &self.namespace.prefix)?;
+
+        let ns = &self.namespace;
+        let transaction = write_transaction(&mut self.connection)?;
+        let floor: u64 = transaction.query_row(
+            &ns.sql("SELECT coalesce(max(revision), 0) FROM pelo_ratings WHERE tenant = ?1"),
+            [&ns.tenant],
+            |row| row.get(0),
+        )?;
+        for table in TENANT_TABLES {
+            transaction.execute(
+                &ns.sql(&format!("delete from {} WHERE tenant = ?1", table)),
+                [&ns.tenant],
+            )?;
+        }
+        copy_tenant(&backup, &transaction, ns)?;
         transaction.execute(
-            "insert into pelo_tasks(id, summary, link, closed) 
-             values (?1, ?2, ?3, ?4)
-             on conflict(id) do update set (summary, link, closed) = (?2, ?3, ?4)",
+            &ns.sql("update pelo_ratings set revision = revision + ?1 WHERE tenant = ?2"),
+            (floor + 1, &ns.tenant),
+        )?;
+        rotate_etag(&transaction, ns)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Copies the whole database, with the data of every tenant, into the
+    /// file at `path`, replacing it if it exists. The copy is made with
+    /// SQLite's online backup, a few pages at a time, so other connections
+    /// can keep writing while it runs; it is restarted when they do, and
+    /// reflects the database as it was when the copy completed.
+    pub fn backup_database(&self, path: &Path) -> Result<(), Error> {
+        let mut destination = rusqlite::Connection::open(path)?;
+        let backup = rusqlite::backup::Backup::new(&self.connection, &mut destination)?;
+        backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_PAUSE, None)?;
+        Ok(())
+    }
+
+    /// Replaces the whole database, for every tenant, with the backup at
+    /// `path`, which should have been made by `backup_database`.
+    ///
+    /// Backups are upgraded and refused like in `restore`. The etag of every
+    /// tenant is rotated and every rating revision is moved past the ones
+    /// handed out before the restore. Writes made by other connections while
+    /// the restore runs are lost.
+    pub fn restore_database(&mut self, path: &Path) -> Result<(), Error> {
+        check_backup_schema(path, &self.namespace.prefix)?;
+        let floor: u64 = self.connection.query_row(
+            &self
+                .namespace
+                .sql("SELECT coalesce(max(revision), 0) FROM pelo_ratings"),
+            [],
+            |row| row.get(0),
+        )?;
+        self.connection.restore(
+            rusqlite::DatabaseName::Main,
+            path,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        migrations::migrate(&mut self.connection, &self.namespace.prefix)?;
+
+        let transaction = write_transaction(&mut self.connection)?;
+        transaction.execute(
+            &self
+                .namespace
+                .sql("update pelo_ratings set revision = revision + ?1"),
+            (floor + 1,),
+        )?;
+        transaction.execute(
+            &self
+                .namespace
+                .sql("update pelo_global_etag set token = lower(hex(randomblob(16)))"),
+            (),
+        )?;
+        rotate_etag(&transaction, &self.namespace)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Creates a database at `db_path` from the backup at `backup_path`, and
+    /// opens its default tenant. Fails if `db_path` already exists.
+    pub fn restore_into(backup_path: &Path, db_path: PathBuf) -> Result<Self, Error> {
+        if db_path.exists() {
+            return Err(Error::generic(&format!(
+                "cannot restore into {}: the file already exists",
+                db_path.display()
+            )));
+        }
+        check_backup_schema(backup_path, DEFAULT_TABLE_PREFIX)?;
+        let mut conn = rusqlite::Connection::open(&db_path)?;
+        conn.restore(
+            rusqlite::DatabaseName::Main,
+            backup_path,
+            None::<fn(rusqlite::backup::Progress)>,
+        )?;
+        Self::with_connection(conn, &SQLitePersistenceBuilder::new(db_path))
+    }
+
+    /// Lists every row of the tenant that cannot be decoded, every rating or
+    /// archived rating without a task, every vote or vote tally that refers
+    /// to an unknown task, every vote by an unknown user, and every
+    /// retraction of an unknown vote. The policy for corrupt rows does not
+    /// apply here: nothing is skipped and nothing fails.
+    pub fn check_integrity(&self) -> Result<Vec<CorruptRow>, Error> {
+        let mut report = Vec::new();
+        report.extend(self.malformed_rows("pelo_users", USER_COLUMNS, user_from_row)?);
+        report.extend(self.malformed_rows("pelo_tasks", TASK_COLUMNS, task_from_row)?);
+        report.extend(self.malformed_rows(
+            "pelo_task_versions",
+            TASK_VERSION_COLUMNS,
+            task_version_from_row,
+        )?);
+        report.extend(self.malformed_rows("pelo_ratings", RATING_COLUMNS, rating_from_row)?);
+        report.extend(self.malformed_rows("pelo_votes", VOTE_COLUMNS, vote_from_row)?);
+        report.extend(self.malformed_rows(
+            "pelo_vote_retractions",
+            RETRACTION_COLUMNS,
+            retraction_from_row,
+        )?);
+        report.extend(self.malformed_rows(
+            "pelo_rating_changes",
+            RATING_CHANGE_COLUMNS,
+            rating_change_from_row,
+        )?);
+        report.extend(self.malformed_rows(
+            "pelo_vote_archive",
+            ARCHIVE_COLUMNS,
+            archive_from_row,
+        )?);
+        report.extend(self.malformed_rows(
+            "pelo_archived_ratings",
+            RATING_COLUMNS,
+            rating_from_row,
+        )?);
+        report.extend(self.malformed_rows("pelo_vote_tallies", TALLY_COLUMNS, tally_from_row)?);
+
+        for (table, sql, problem) in ORPHAN_QUERIES {
+            let table = self.namespace.sql(table);
+            let mut stmt = self.connection.prepare(&self.namespace.sql(sql))?;
+            stmt.query_map([&self.namespace.tenant], |row| {
+                let rowid: i64 = row.get(0)?;
+                let reference: String = row.get(1)?;
+                Ok((rowid, reference))
+            })?
+            .try_for_each(|maybe_orphan| -> Result<(), Error> {
+                let (rowid, reference) = maybe_orphan?;
+                report.push(CorruptRow::new(&table, rowid, problem(reference)));
+                Ok(())
+            })?;
+        }
+        Ok(report)
+    }
+}
+
+impl Persistence for SQLitePersistence {
+    fn list_users(&self) -> Result<Vec<User>, Error> {
+        // The rows are listed in the order they were first stored.
+        self.query_rows(
+            "pelo_users",
+            USER_COLUMNS,
+            "ORDER BY rowid",
+            &[],
+            user_from_row,
+        )
+    }
+
+    fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
+        let transaction = write_transaction(&mut self.connection)?;
+        transaction.execute(
+            &self.namespace.sql(
+                "insert into pelo_users(tenant, id, limit_votes_per_week, role, limit_policy)
+             values (?1, ?2, ?3, ?4, ?5)
+             on conflict(tenant, id) do update set
+                 (limit_votes_per_week, role, limit_policy) = (?3, ?4, ?5)",
+            ),
             (
+                &self.namespace.tenant,
+                u.id(),
+                u.limit_votes_per_week(),
+                u.role().as_str(),
+                u.limit_policy()
+                    .map(serde_json::to_string)
+                    .transpose()
+                    .map_err(|e| Error::generic(&e.to_string()))?,
+            ),
+        )?;
+        rotate_etag(&transaction, &self.namespace)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        let result = self.query_rows(
+            "pelo_users",
+            USER_COLUMNS,
+            "AND id = ?2",
+            &[&u_id],
+            user_from_row,
+        )?;
+        match result.len() {
+            0 => Err(Error::user_not_found(u_id)),
+            1 => Ok(result[0].clone()),
+            _ => Err(Error::db_error("more than one user with same primary key")),
+        }
+    }
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        let result: usize = self.connection.query_row(
+            &self.namespace.sql(
+                "SELECT COUNT(*) FROM pelo_votes
+             WHERE tenant = ?1 AND voter = ?2 AND time >= ?3",
+            ),
+            [&self.namespace.tenant, u_id, &since.to_rfc3339()],
+            |row| row.get(0),
+        )?;
+        Ok(result)
+    }
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        self.query_votes(
+            "AND voter = ?2 AND time >= ?3",
+            &[&u_id, &since.to_rfc3339()],
+        )
+    }
+
+    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        self.query_rows(
+            "pelo_tasks",
+            TASK_COLUMNS,
+            "ORDER BY rowid",
+            &[],
+            task_from_row,
+        )
+    }
+
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.query_rows(
+            "pelo_tasks",
+            TASK_COLUMNS,
+            "AND id = ?2",
+            &[&t_id.to_string()],
+            task_from_row,
+        )?
+        .pop()
+        .ok_or(Error::task_not_found(t_id))
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        let rating = Rating::new(*t.id());
+        let transaction = write_transaction(&mut self.connection)?;
+        // The current version is read under the write lock, so that no other
+        // connection can record the same next version.
+        let (mut current, corrupt) = decode_rows(
+            &transaction,
+            &self.namespace,
+            "pelo_tasks",
+            TASK_COLUMNS,
+            "AND id = ?2",
+            &[&t.id().to_string()],
+            task_from_row,
+        )?;
+        apply_corrupt_row_policy(self.corrupt_row_policy, &self.skipped_rows, corrupt)?;
+        let current = current.pop();
+
+        let mut version = current.as_ref().map(|c| c.version()).unwrap_or(0);
+        if let Some(v) = next_task_version(current.as_ref(), t) {
+            version = v;
+            transaction.execute(
+                &self.namespace.sql(
+                    "insert into pelo_task_versions(
+                     tenant, task, version, time, author, summary, link
+                 )
+                 values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                ),
+                (
+                    &self.namespace.tenant,
+                    &t.id().to_string(),
+                    version,
+                    &DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
+                    author,
+                    t.summary(),
+                    &t.link().to_string(),
+                ),
+            )?;
+        }
+        transaction.execute(
+            &self.namespace.sql(
+                "insert into pelo_tasks(tenant, id, summary, link, closed, version)
+             values (?1, ?2, ?3, ?4, ?5, ?6)
+             on conflict(tenant, id) do update set
+                 (summary, link, closed, version) = (?3, ?4, ?5, ?6)",
+            ),
+            (
+                &self.namespace.tenant,
                 &t.id().to_string(),
                 t.summary(),
                 &t.link().to_string(),
                 if t.closed() { 1 } else { 0 },
+                version,
             ),
         )?;
         transaction.execute(
-            "insert into pelo_ratings(task, elo)
-             values (?1, ?2)",
-            (&rating.task().to_string(), rating.elo()),
+            &self
+                .namespace
+                .sql("delete from pelo_task_tags where tenant = ?1 and task = ?2"),
+            [&self.namespace.tenant, &t.id().to_string()],
+        )?;
+        for tag in t.tags() {
+            transaction.execute(
+                &self
+                    .namespace
+                    .sql("insert into pelo_task_tags(tenant, task, tag) values (?1, ?2, ?3)"),
+                (&self.namespace.tenant, &t.id().to_string(), tag),
+            )?;
+        }
+        transaction.execute(
+            &self.namespace.sql(
+                "insert into pelo_ratings(tenant, task, elo)
+             values (?1, ?2, ?3)
+             on conflict(tenant, task) do nothing",
+            ),
+            (
+                &self.namespace.tenant,
+                &rating.task().to_string(),
+                rating.elo(),
+            ),
+        )?;
+        rotate_etag(&transaction, &self.namespace)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        self.query_rows(
+            "pelo_task_versions",
+            TASK_VERSION_COLUMNS,
+            "AND task = ?2 ORDER BY version",
+            &[&t_id.to_string()],
+            task_version_from_row,
+        )
+    }
+
+    fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
+        let transaction = write_transaction(&mut self.connection)?;
+        let changed = transaction.execute(
+            &self
+                .namespace
+                .sql("update pelo_tasks set closed = 1 where tenant = ?1 and id = ?2"),
+            (&self.namespace.tenant, &t_id.to_string()),
+        )?;
+        if changed == 0 {
+            return Err(Error::task_not_found(t_id));
+        }
+        rotate_etag(&transaction, &self.namespace)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+        // The etag is read first: if another connection commits before the
+        // ranking is read, the etag is stale and writes based on it are
+        // rejected.
+        let token = read_etag(&self.connection, &self.namespace)?;
+        let revised = self.query_rows(
+            "pelo_ratings",
+            REVISED_RATING_COLUMNS,
+            "ORDER BY elo",
+            &[],
+            revised_rating_from_row,
+        )?;
+        let revisions = revised.iter().map(|(r, rev)| (*r.task(), *rev)).collect();
+        let ranking = revised.into_iter().map(|(r, _)| r).collect();
+        Ok(Snapshot {
+            ranking,
+            etag: Etag::with_revisions(&token, revisions),
+        })
+    }
+
+    fn get_etag_token(&self) -> Result<String, Error> {
+        read_etag(&self.connection, &self.namespace)
+    }
+
+    fn add_vote_and_update_ratings(
+        &mut self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> Result<(), Error> {
+        let transaction = write_transaction(&mut self.connection)?;
+        etag.check_revisions([r0, r1], |t| {
+            Ok(transaction
+                .query_row(
+                    &self
+                        .namespace
+                        .sql("SELECT revision FROM pelo_ratings WHERE tenant = ?1 AND task = ?2"),
+                    [&self.namespace.tenant, &t.to_string()],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })?;
+
+        let now = SystemTime::now().into();
+        for rating in [r0, r1] {
+            update_rating(
+                &transaction,
+                &self.namespace,
+                rating,
+                &now,
+                vote.id(),
+                false,
+            )?;
+        }
+        insert_vote(&transaction, &self.namespace, vote)?;
+        rotate_etag(&transaction, &self.namespace)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.query_votes("ORDER BY time", &[])
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        self.query_votes("AND id = ?2", &[&v_id.to_string()])?
+            .pop()
+            .ok_or(Error::vote_not_found(v_id))
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        self.query_votes(
+            "AND (task0 = ?2 OR task1 = ?2) ORDER BY time",
+            &[&t_id.to_string()],
+        )
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.query_rows(
+            "pelo_vote_retractions",
+            RETRACTION_COLUMNS,
+            "ORDER BY rowid",
+            &[],
+            retraction_from_row,
+        )
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        self.query_rows(
+            "pelo_vote_retractions",
+            RETRACTION_COLUMNS,
+            "AND voter = ?2 AND time >= ?3 ORDER BY rowid",
+            &[&u_id, &since.to_rfc3339()],
+            retraction_from_row,
+        )
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        self.get_vote(retraction.vote())?;
+        let transaction = write_transaction(&mut self.connection)?;
+        let token = read_etag(&transaction, &self.namespace)?;
+
+        if token != etag.token {
+            return Err(Error::retry_transaction());
+        }
+
+        let retracted: usize = transaction.query_row(
+            &self
+                .namespace
+                .sql("SELECT COUNT(*) FROM pelo_vote_retractions WHERE tenant = ?1 AND vote = ?2"),
+            [&self.namespace.tenant, &retraction.vote().to_string()],
+            |row| row.get(0),
+        )?;
+        if retracted > 0 {
+            return Err(Error::retraction_not_allowed(
+                retraction.vote(),
+                "already retracted",
+            ));
+        }
+        transaction.execute(
+            &self.namespace.sql(
+                "insert into pelo_vote_retractions(tenant, vote, voter, time, replacement)
+             values (?1, ?2, ?3, ?4, ?5)",
+            ),
+            (
+                &self.namespace.tenant,
+                &retraction.vote().to_string(),
+                retraction.voter(),
+                &retraction.time().to_rfc3339(),
+                retraction.replacement().map(|r| r.to_string()),
+            ),
+        )?;
+        if let Some(vote) = replacement {
+            insert_vote(&transaction, &self.namespace, vote)?;
+        }
+        let now = SystemTime::now().into();
+        for rating in ratings {
+            update_rating(
+                &transaction,
+                &self.namespace,
+                rating,
+                &now,
+                retraction.vote(),
+                true,
+            )?;
+        }
+        rotate_etag(&transaction, &self.namespace)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        self.query_rows(
+            "pelo_rating_changes",
+            RATING_CHANGE_COLUMNS,
+            "AND task = ?2 ORDER BY time, rowid",
+            &[&t_id.to_string()],
+            rating_change_from_row,
+        )
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        let ranking = self.query_rows(
+            "pelo_ratings",
+            "task, coalesce(
+                 (SELECT new_elo FROM pelo_rating_changes c
+                  WHERE c.tenant = pelo_ratings.tenant AND c.task = pelo_ratings.task
+                      AND c.time <= ?2
+                  ORDER BY c.time DESC, c.rowid DESC LIMIT 1),
+                 ?3
+             )",
+            "AND NOT EXISTS (
+                 SELECT 1 FROM pelo_task_versions v
+                 WHERE v.tenant = pelo_ratings.tenant AND v.task = pelo_ratings.task
+                     AND v.version = 1 AND v.time > ?2
+             )",
+            &[&time.to_rfc3339(), &Rating::new(Uuid::nil()).elo()],
+            rating_from_row,
+        )?;
+        Ok(sorted_ranking(ranking))
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        let archive = self
+            .query_rows(
+                "pelo_vote_archive",
+                ARCHIVE_COLUMNS,
+                "",
+                &[],
+                archive_from_row,
+            )?
+            .pop();
+        let Some((cutoff, retractions)) = archive else {
+            return Ok(VoteArchive::new());
+        };
+        Ok(VoteArchive::with_contents(
+            cutoff,
+            self.query_rows(
+                "pelo_archived_ratings",
+                RATING_COLUMNS,
+                "ORDER BY rowid",
+                &[],
+                rating_from_row,
+            )?,
+            self.query_rows(
+                "pelo_vote_tallies",
+                TALLY_COLUMNS,
+                "ORDER BY rowid",
+                &[],
+                tally_from_row,
+            )?,
+            retractions,
+        ))
+    }
+
+    fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        let cutoff = archive_cutoff(archive)?.to_rfc3339();
+        let transaction = write_transaction(&mut self.connection)?;
+        let ns = &self.namespace;
+        if read_etag(&transaction, ns)? != etag.token {
+            return Err(Error::retry_transaction());
+        }
+
+        transaction.execute(
+            &ns.sql(
+                "delete from pelo_vote_retractions
+             WHERE tenant = ?1 AND vote IN (
+                 SELECT id FROM pelo_votes WHERE tenant = ?1 AND time < ?2
+             )",
+            ),
+            (&ns.tenant, &cutoff),
+        )?;
+        transaction.execute(
+            &ns.sql("delete from pelo_votes WHERE tenant = ?1 AND time < ?2"),
+            (&ns.tenant, &cutoff),
+        )?;
+        transaction.execute(
+            &ns.sql(
+                "insert into pelo_vote_archive(tenant, cutoff, retractions)
+             values (?1, ?2, ?3)
+             on conflict(tenant) do update set (cutoff, retractions) = (?2, ?3)",
+            ),
+            (&ns.tenant, &cutoff, archive.retractions()),
+        )?;
+        for table in ["pelo_archived_ratings", "pelo_vote_tallies"] {
+            transaction.execute(
+                &ns.sql(&format!("delete from {} WHERE tenant = ?1", table)),
+                [&ns.tenant],
+            )?;
+        }
+        for rating in archive.ratings() {
+            transaction.execute(
+                &ns.sql("insert into pelo_archived_ratings(tenant, task, elo) values (?1, ?2, ?3)"),
+                (&ns.tenant, &rating.task().to_string(), rating.elo()),
+            )?;
+        }
+        for tally in archive.tallies() {
+            transaction.execute(
+                &ns.sql(
+                    "insert into pelo_vote_tallies(
+                     tenant, task0, task1, wins, draws, losses, retracted
+                 )
+                 values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                ),
+                (
+                    &ns.tenant,
+                    &tally.task0().to_string(),
+                    &tally.task1().to_string(),
+                    tally.wins(),
+                    tally.draws(),
+                    tally.losses(),
+                    tally.retracted(),
+                ),
+            )?;
+        }
+        rotate_etag(&transaction, ns)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        let (filter, params) = task_filter(query);
+        let direction = if query.descending() { "DESC" } else { "ASC" };
+        let order = match query.order() {
+            TaskOrder::Id => format!("ORDER BY id {}", direction),
+            TaskOrder::Summary => format!("ORDER BY summary {0}, id {0}", direction),
+        };
+        self.query_rows(
+            "pelo_tasks",
+            TASK_COLUMNS,
+            &format!(
+                "{} {} {}",
+                filter,
+                order,
+                page_clause(query.offset(), query.limit())
+            ),
+            &sql_params(&params),
+            task_from_row,
+        )
+    }
+
+    fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+        let (filter, params) = task_filter(query);
+        let result: usize = self.connection.query_row(
+            &self.namespace.sql(&format!(
+                "SELECT COUNT(*) FROM pelo_tasks WHERE tenant = ?1 {}",
+                filter
+            )),
+            rusqlite::params_from_iter(std::iter::once(&self.namespace.tenant).chain(&params)),
+            |row| row.get(0),
+        )?;
+        Ok(result)
+    }
+
+    fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        if let Some(role) = query.role() {
+            conditions.push("role = ?");
+            params.push(role.as_str().to_string());
+        }
+        if let Some(text) = query.text() {
+            conditions.push("id LIKE ? ESCAPE '\\'");
+            params.push(like_pattern(text));
+        }
+        self.query_rows(
+            "pelo_users",
+            USER_COLUMNS,
+            &format!(
+                "{} ORDER BY id {}",
+                and_clause(&conditions),
+                page_clause(query.offset(), query.limit())
+            ),
+            &sql_params(&params),
+            user_from_row,
+        )
+    }
+
+    // The explicit transaction takes the write lock at once, like the
+    // transaction of a single write.
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        if !self.connection.is_autocommit() {
+            return Err(Error::transaction_in_progress());
+        }
+        self.connection.execute_batch("BEGIN IMMEDIATE")?;
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        if self.connection.is_autocommit() {
+            return Err(Error::no_transaction());
+        }
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        if self.connection.is_autocommit() {
+            return Err(Error::no_transaction());
+        }
+        self.connection.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+}
+
+impl SQLitePersistence {
+    fn query_votes(
+        &self,
+        filter: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<Vote>, Error> {
+        self.query_rows("pelo_votes", VOTE_COLUMNS, filter, params, vote_from_row)
+    }
+
+    // Reads the rows of the tenant in a table and applies the corrupt row
+    // policy to the ones that cannot be decoded. The filter follows the
+    // condition on the tenant, which is the first parameter, so it starts
+    // with AND or ORDER BY and numbers its own parameters from 2.
+    fn query_rows<T>(
+        &self,
+        table: &str,
+        columns: &str,
+        filter: &str,
+        params: &[&dyn rusqlite::ToSql],
+        decode: fn(&rusqlite::Row) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let (result, corrupt) = self.decode_rows(table, columns, filter, params, decode)?;
+        apply_corrupt_row_policy(self.corrupt_row_policy, &self.skipped_rows, corrupt)?;
+        Ok(result)
+    }
+
+    fn malformed_rows<T>(
+        &self,
+        table: &str,
+        columns: &str,
+        decode: fn(&rusqlite::Row) -> Result<T, Error>,
+    ) -> Result<Vec<CorruptRow>, Error> {
+        Ok(self.decode_rows(table, columns, "", &[], decode)?.1)
+    }
+
+    fn decode_rows<T>(
+        &self,
+        table: &str,
+        columns: &str,
+        filter: &str,
+        params: &[&dyn rusqlite::ToSql],
+        decode: fn(&rusqlite::Row) -> Result<T, Error>,
+    ) -> Result<(Vec<T>, Vec<CorruptRow>), Error> {
+        decode_rows(
+            &self.connection,
+            &self.namespace,
+            table,
+            columns,
+            filter,
+            params,
+            decode,
+        )
+    }
+}
+
+// Decodes the rows of the tenant in a table, separating the ones that cannot
+// be decoded. The decoder sees the selected columns after the rowid. Within a
+// write transaction, the rows are read through the transaction.
+fn decode_rows<T>(
+    connection: &rusqlite::Connection,
+    namespace: &Namespace,
+    table: &str,
+    columns: &str,
+    filter: &str,
+    params: &[&dyn rusqlite::ToSql],
+    decode: fn(&rusqlite::Row) -> Result<T, Error>,
+) -> Result<(Vec<T>, Vec<CorruptRow>), Error> {
+    let mut stmt = connection.prepare(&namespace.sql(&format!(
+        "SELECT rowid, {} FROM {} WHERE tenant = ?1 {}",
+        columns, table, filter
+    )))?;
+    let table = namespace.sql(table);
+    let mut tenant_and_params: Vec<&dyn rusqlite::ToSql> = vec![&namespace.tenant];
+    tenant_and_params.extend_from_slice(params);
+    let mut rows = stmt.query(tenant_and_params.as_slice())?;
+    let mut result = Vec::new();
+    let mut corrupt = Vec::new();
+    while let Some(row) = rows.next()? {
+        let rowid: i64 = row.get(0)?;
+        match decode(row) {
+            Ok(value) => result.push(value),
+            Err(e) => corrupt.push(CorruptRow::new(
+                &table,
+                rowid,
+                RowProblem::Malformed(e.msg().to_string()),
+            )),
+        }
+    }
+    Ok((result, corrupt))
+}
+
+// Fails on the first corrupt row, or sets the corrupt rows aside, as the
+// policy says.
+fn apply_corrupt_row_policy(
+    policy: CorruptRowPolicy,
+    skipped_rows: &RefCell<Vec<CorruptRow>>,
+    corrupt: Vec<CorruptRow>,
+) -> Result<(), Error> {
+    if let Some(first) = corrupt.first() {
+        match policy {
+            CorruptRowPolicy::Fail => {
+                return Err(Error::corrupt_data(
+                    first.table(),
+                    first.row(),
+                    &first.problem().to_string(),
+                ));
+            }
+            CorruptRowPolicy::Skip => skipped_rows.borrow_mut().extend(corrupt),
+        }
+    }
+    Ok(())
+}
+
+// Write transactions take the write lock when they begin, so that the etag
+// they read cannot be changed by another connection before they commit, and
+// waiting for that lock is subject to the busy timeout. Within an explicit
+// transaction, which already holds the lock, a write is a savepoint instead.
+fn write_transaction(connection: &mut rusqlite::Connection) -> Result<WriteTransaction<'_>, Error> {
+    if connection.is_autocommit() {
+        Ok(WriteTransaction::Own(
+            connection.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?,
+        ))
+    } else {
+        Ok(WriteTransaction::Nested(connection.savepoint()?))
+    }
+}
+
+// The conditions of a task query, following the one on the tenant, and their
+// parameters.
+fn task_filter(query: &TaskQuery) -> (String, Vec<String>) {
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    match query.status() {
+        TaskStatus::Any => {}
+        TaskStatus::Open => conditions.push("closed = 0"),
+        TaskStatus::Closed => conditions.push("closed <> 0"),
+    }
+    for tag in query.tags() {
+        conditions.push(
+            "EXISTS (SELECT 1 FROM pelo_task_tags
+                     WHERE tenant = pelo_tasks.tenant AND task = pelo_tasks.id AND tag = ?)",
+        );
+        params.push(tag.clone());
+    }
+    if let Some(text) = query.text() {
+        conditions.push("summary LIKE ? ESCAPE '\\'");
+        params.push(like_pattern(text));
+    }
+    (and_clause(&conditions), params)
+}
+
+fn and_clause(conditions: &[&str]) -> String {
+    conditions
+        .iter()
+        .map(|condition| format!("AND {} ", condition))
+        .collect()
+}
+
+fn sql_params(values: &[String]) -> Vec<&dyn rusqlite::ToSql> {
+    values.iter().map(|v| v as &dyn rusqlite::ToSql).collect()
+}
+
+fn page_clause(offset: usize, limit: Option<usize>) -> String {
+    match limit {
+        Some(limit) => format!("LIMIT {} OFFSET {}", limit, offset),
+        None if offset > 0 => format!("LIMIT -1 OFFSET {}", offset),
+        None => String::new(),
+    }
+}
+
+// A LIKE pattern matching any text that contains `text`.
+fn like_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+// Queries that find rows of a tenant, the first parameter, referring to data
+// missing from that tenant, as the table, a query returning the rowid and the
+// missing reference, and the problem to report.
+type OrphanQuery = (&'static str, &'static str, fn(String) -> RowProblem);
+const ORPHAN_QUERIES: &[OrphanQuery] = &[
+    (
+        "pelo_ratings",
+        "SELECT rowid, task FROM pelo_ratings
+         WHERE tenant = ?1 AND task NOT IN (SELECT id FROM pelo_tasks WHERE tenant = ?1)",
+        |_| RowProblem::OrphanedRating,
+    ),
+    (
+        "pelo_votes",
+        "SELECT rowid, task0 FROM pelo_votes
+         WHERE tenant = ?1 AND task0 NOT IN (SELECT id FROM pelo_tasks WHERE tenant = ?1)",
+        RowProblem::UnknownTask,
+    ),
+    (
+        "pelo_votes",
+        "SELECT rowid, task1 FROM pelo_votes
+         WHERE tenant = ?1 AND task1 NOT IN (SELECT id FROM pelo_tasks WHERE tenant = ?1)",
+        RowProblem::UnknownTask,
+    ),
+    (
+        "pelo_votes",
+        "SELECT rowid, voter FROM pelo_votes
+         WHERE tenant = ?1 AND voter NOT IN (SELECT id FROM pelo_users WHERE tenant = ?1)",
+        RowProblem::UnknownUser,
+    ),
+    (
+        "pelo_vote_retractions",
+        "SELECT rowid, vote FROM pelo_vote_retractions
+         WHERE tenant = ?1 AND vote NOT IN (SELECT id FROM pelo_votes WHERE tenant = ?1)",
+        RowProblem::UnknownVote,
+    ),
+    (
+        "pelo_archived_ratings",
+        "SELECT rowid, task FROM pelo_archived_ratings
+         WHERE tenant = ?1 AND task NOT IN (SELECT id FROM pelo_tasks WHERE tenant = ?1)",
+        |_| RowProblem::OrphanedRating,
+    ),
+    (
+        "pelo_vote_tallies",
+        "SELECT rowid, task0 FROM pelo_vote_tallies
+         WHERE tenant = ?1 AND task0 NOT IN (SELECT id FROM pelo_tasks WHERE tenant = ?1)",
+        RowProblem::UnknownTask,
+    ),
+    (
+        "pelo_vote_tallies",
+        "SELECT rowid, task1 FROM pelo_vote_tallies
+         WHERE tenant = ?1 AND task1 NOT IN (SELECT id FROM pelo_tasks WHERE tenant = ?1)",
+        RowProblem::UnknownTask,
+    ),
+];
+
+// The tenant of the data stored before tenants were introduced, and of
+// databases opened with `SQLitePersistence::new`.
+const DEFAULT_TENANT: &str = "";
+
+// The tables a connection uses, named with a prefix, and the tenant whose
+// rows it reads and writes.
+struct Namespace {
+    prefix: String,
+    tenant: String,
+}
+impl Namespace {
+    // The statements of this module name the tables with the default prefix.
+    fn sql<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        migrations::prefixed(&self.prefix, sql)
+    }
+}
+
+// The tables that hold the rows of the tenants, in their `tenant` column.
+const TENANT_TABLES: &[&str] = &[
+    "pelo_global_etag",
+    "pelo_users",
+    "pelo_tasks",
+    "pelo_task_versions",
+    "pelo_task_tags",
+    "pelo_ratings",
+    "pelo_rating_changes",
+    "pelo_votes",
+    "pelo_vote_retractions",
+    "pelo_vote_archive",
+    "pelo_archived_ratings",
+    "pelo_vote_tallies",
+];
+
+// An online backup copies this many pages at a time, and pauses between steps
+// so that other connections can write.
+const BACKUP_PAGES_PER_STEP: std::os::raw::c_int = 64;
+const BACKUP_PAUSE: Duration = Duration::from_millis(10);
+
+// The columns read by the decoders below, which skip the leading rowid.
+
+const USER_COLUMNS: &str = "id, limit_votes_per_week, role, limit_policy";
+const TASK_COLUMNS: &str = "id, summary, link, closed, version,
+     (SELECT group_concat(tag, char(31)) FROM pelo_task_tags
+      WHERE tenant = pelo_tasks.tenant AND task = pelo_tasks.id)";
+const TASK_VERSION_COLUMNS: &str = "task, version, time, author, summary, link";
+const RATING_COLUMNS: &str = "task, elo";
+const REVISED_RATING_COLUMNS: &str = "task, elo, revision";
+const VOTE_COLUMNS: &str =
+    "id, voter, time, task0, task1, outcome, comment, task0_version, task1_version";
+const RETRACTION_COLUMNS: &str = "vote, voter, time, replacement";
+const RATING_CHANGE_COLUMNS: &str = "task, time, old_elo, new_elo, vote, retraction";
+const TALLY_COLUMNS: &str = "task0, task1, wins, draws, losses, retracted";
+const ARCHIVE_COLUMNS: &str = "cutoff, retractions";
+
+// A write that is atomic on its own, or part of the explicit transaction in
+// progress. It is rolled back when dropped without being committed.
+enum WriteTransaction<'c> {
+    Own(rusqlite::Transaction<'c>),
+    Nested(rusqlite::Savepoint<'c>),
+}
+impl WriteTransaction<'_> {
+    fn commit(self) -> Result<(), Error> {
+        match self {
+            WriteTransaction::Own(transaction) => transaction.commit()?,
+            WriteTransaction::Nested(savepoint) => savepoint.commit()?,
+        }
+        Ok(())
+    }
+}
+impl std::ops::Deref for WriteTransaction<'_> {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &rusqlite::Connection {
+        match self {
+            WriteTransaction::Own(transaction) => transaction,
+            WriteTransaction::Nested(savepoint) => savepoint,
+        }
+    }
+}
+
+fn insert_vote(
+    transaction: &rusqlite::Connection,
+    ns: &Namespace,
+    vote: &Vote,
+) -> Result<(), Error> {
+    transaction.execute(
+        &ns.sql(
+            "insert into pelo_votes(
+             tenant, id, voter, time, task0, task1, outcome, comment,
+             task0_version, task1_version
+         )
+         values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        ),
+        (
+            &ns.tenant,
+            &vote.id().to_string(),
+            vote.voter(),
+            &vote.time().to_rfc3339(),
+            &vote.task0().to_string(),
+            &vote.task1().to_string(),
+            outcome_to_column(vote.outcome()),
+            vote.comment(),
+            vote.task_versions().0,
+            vote.task_versions().1,
+        ),
+    )?;
+    Ok(())
+}
+
+// Stores a rating, recording the change unless a retraction left it as it
+// was.
+fn update_rating(
+    transaction: &rusqlite::Connection,
+    ns: &Namespace,
+    rating: &Rating,
+    time: &DateTime<Utc>,
+    vote: &Uuid,
+    retraction: bool,
+) -> Result<(), Error> {
+    let old_elo: f32 = transaction
+        .query_row(
+            &ns.sql("SELECT elo FROM pelo_ratings WHERE tenant = ?1 AND task = ?2"),
+            [&ns.tenant, &rating.task().to_string()],
+            |row| row.get(0),
+        )
+        .optional()?
+        .ok_or(Error::task_not_found(rating.task()))?;
+    transaction.execute(
+        &ns.sql(
+            "update pelo_ratings set elo = ?3, revision = revision + 1
+         where tenant = ?1 and task = ?2",
+        ),
+        (&ns.tenant, &rating.task().to_string(), &rating.elo()),
+    )?;
+    if !retraction || old_elo != rating.elo() {
+        transaction.execute(
+            &ns.sql(
+                "insert into pelo_rating_changes(
+                 tenant, task, time, old_elo, new_elo, vote, retraction
+             )
+             values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            ),
+            (
+                &ns.tenant,
+                &rating.task().to_string(),
+                &time.to_rfc3339(),
+                old_elo,
+                rating.elo(),
+                &vote.to_string(),
+                retraction,
+            ),
         )?;
+    }
+    Ok(())
+}
 
-        transaction.commit()?;
-        Ok(())
+// Copies the rows of the tenant of `ns` from `source` to `destination`, whose
+// tables have the same columns, keeping their order.
+fn copy_tenant(
+    source: &rusqlite::Connection,
+    destination: &rusqlite::Connection,
+    ns: &Namespace,
+) -> Result<(), Error> {
+    for table in TENANT_TABLES {
+        let mut select = source.prepare(&ns.sql(&format!(
+            "SELECT * FROM {} WHERE tenant = ?1 ORDER BY rowid",
+            table
+        )))?;
+        let columns: Vec<String> = select
+            .column_names()
+            .iter()
+            .map(|c| c.to_string())
+            .collect();
+        let mut insert = destination.prepare(&ns.sql(&format!(
+            "insert into {}({}) values ({})",
+            table,
+            columns.join(", "),
+            vec!["?"; columns.len()].join(", ")
+        )))?;
+        let mut rows = select.query([&ns.tenant])?;
+        while let Some(row) = rows.next()? {
+            let values = (0..columns.len())
+                .map(|i| row.get::<_, rusqlite::types::Value>(i))
+                .collect::<Result<Vec<_>, _>>()?;
+            insert.execute(rusqlite::params_from_iter(values))?;
+        }
     }
+    Ok(())
+}
 
-    fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
-        self.connection.execute(
-            "update pelo_tasks set closed = 1 where id = ?1",
-            (&t_id.to_string(),),
-        )?;
-        Ok(())
+// Refuses backups made by a newer version of the schema before anything is
+// overwritten.
+fn check_backup_schema(path: &Path, prefix: &str) -> Result<(), Error> {
+    let conn =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let version = migrations::schema_version(&conn, prefix)?;
+    if version > SCHEMA_VERSION {
+        return Err(Error::schema_too_new(version, SCHEMA_VERSION));
     }
+    Ok(())
+}
 
-    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
-        let token: String =
-            self.connection
-                .query_row("SELECT token FROM pelo_global_etag", [], |row| row.get(0))?;
+fn rotate_etag(transaction: &rusqlite::Connection, ns: &Namespace) -> Result<(), Error> {
+    transaction.execute(
+        &ns.sql(
+            "insert into pelo_global_etag (tenant, token)
+                values (?1, ?2)
+                on conflict(tenant) do update set token = ?2",
+        ),
+        (&ns.tenant, Uuid::new_v4().to_string()),
+    )?;
+    Ok(())
+}
 
-        let mut stmt = self
-            .connection
-            .prepare("SELECT task, elo FROM pelo_ratings")?;
-        let mut ranking = Vec::new();
-        stmt.query_map([], |row| {
-            let id_: String = row.get(0)?;
-            let id: Uuid = Uuid::parse_str(&id_).unwrap();
-            let elo: f32 = row.get(1)?;
-            Ok(Rating::with_elo(id, elo))
-        })?
-        .try_for_each(|maybe_rating| -> Result<(), Error> {
-            ranking.push(maybe_rating?);
-            Ok(())
-        })?;
-        Ok(Snapshot {
-            ranking: ranking,
-            etag: Etag { token: token },
-        })
+// The etag of a tenant is empty until its first write, when the tenant only
+// appears in a database restored by another connection.
+fn read_etag(connection: &rusqlite::Connection, ns: &Namespace) -> Result<String, Error> {
+    Ok(connection
+        .query_row(
+            &ns.sql("SELECT token FROM pelo_global_etag WHERE tenant = ?1"),
+            [&ns.tenant],
+            |row| row.get(0),
+        )
+        .optional()?
+        .unwrap_or_default())
+}
+
+// Returns the version to record when `t` is stored over `current`, or None if
+// neither the summary nor the link have changed.
+fn next_task_version(current: Option<&Task>, t: &Task) -> Option<u32> {
+    match current {
+        Some(c) if c.summary() == t.summary() && c.link() == t.link() => None,
+        Some(c) => Some(c.version() + 1),
+        None => Some(1),
     }
+}
 
-    fn add_vote_and_update_ratings(
-        &mut self,
-        etag: &Etag,
-        vote: &Vote,
-        r0: &Rating,
-        r1: &Rating,
-    ) -> Result<(), Error> {
-        let transaction = self.connection.transaction()?;
-        let token: String =
-            transaction.query_row("SELECT token FROM pelo_global_etag", [], |row| row.get(0))?;
+fn user_from_row(row: &rusqlite::Row) -> Result<User, Error> {
+    let id: String = row.get(1)?;
+    let limit: i32 = row.get(2)?;
+    let role: String = row.get(3)?;
+    let policy: Option<String> = row.get(4)?;
+    let mut user = User::with_role(&id, limit, role.parse()?);
+    if let Some(p) = policy {
+        user.set_limit_policy(Some(
+            serde_json::from_str(&p).map_err(|e| Error::db_error(&e.to_string()))?,
+        ));
+    }
+    Ok(user)
+}
 
-        if token != etag.token {
-            return Err(Error::retry_transaction());
-        }
+fn task_from_row(row: &rusqlite::Row) -> Result<Task, Error> {
+    let id: String = row.get(1)?;
+    let summary: String = row.get(2)?;
+    let link: String = row.get(3)?;
+    let closed: i32 = row.get(4)?;
+    let version: u32 = row.get(5)?;
+    let tags: Option<String> = row.get(6)?;
+    let mut task = Task::with_version(
+        parse_uuid(&id)?,
+        &summary,
+        Url::parse(&link)?,
+        closed != 0,
+        version,
+    );
+    if let Some(tags) = tags {
+        task.set_tags(&tags.split('\u{1f}').collect::<Vec<_>>());
+    }
+    Ok(task)
+}
 
-        transaction.execute(
-            "update pelo_ratings set elo = ?2 where task = ?1",
-            (&r0.task().to_string(), &r0.elo()),
-        )?;
-        transaction.execute(
-            "update pelo_ratings set elo = ?2 where task = ?1",
-            (&r1.task().to_string(), &r1.elo()),
-        )?;
-        transaction.execute(
-            "insert into pelo_votes values(?1, ?2, ?3, ?4, ?5)",
-            (
-                vote.voter(),
-                &vote.time().to_rfc3339(),
-                &vote.task0().to_string(),
-                &vote.task1().to_string(),
-                match vote.outcome() {
-                    Outcome::P0Win => -1,
-                    Outcome::Draw => 0,
-                    Outcome::P1Win => 1,
-                },
-            ),
-        )?;
-        transaction.execute(
-            "insert into pelo_global_etag (id, token)
-                    values (0, ?1)
-                    on conflict(id) do update set token = ?1",
-            (Uuid::new_v4().to_string(),),
-        )?;
+fn task_version_from_row(row: &rusqlite::Row) -> Result<TaskVersion, Error> {
+    let task: String = row.get(1)?;
+    let version: u32 = row.get(2)?;
+    let time: String = row.get(3)?;
+    let author: String = row.get(4)?;
+    let summary: String = row.get(5)?;
+    let link: String = row.get(6)?;
+    Ok(TaskVersion::new(
+        parse_uuid(&task)?,
+        version,
+        parse_time(&time)?,
+        &author,
+        &summary,
+        Url::parse(&link)?,
+    ))
+}
 
-        transaction.commit()?;
-        Ok(())
+fn rating_from_row(row: &rusqlite::Row) -> Result<Rating, Error> {
+    let task: String = row.get(1)?;
+    let elo: f32 = row.get(2)?;
+    Ok(Rating::with_elo(parse_uuid(&task)?, elo))
+}
+
+fn revised_rating_from_row(row: &rusqlite::Row) -> Result<(Rating, u64), Error> {
+    Ok((rating_from_row(row)?, row.get(3)?))
+}
+
+fn vote_from_row(row: &rusqlite::Row) -> Result<Vote, Error> {
+    let id: String = row.get(1)?;
+    let voter: String = row.get(2)?;
+    let time: String = row.get(3)?;
+    let task0: String = row.get(4)?;
+    let task1: String = row.get(5)?;
+    let outcome: i32 = row.get(6)?;
+    let comment: Option<String> = row.get(7)?;
+    let versions: (u32, u32) = (row.get(8)?, row.get(9)?);
+    let mut vote = Vote::with_id(
+        parse_uuid(&id)?,
+        &voter,
+        parse_time(&time)?,
+        parse_uuid(&task0)?,
+        parse_uuid(&task1)?,
+        match outcome {
+            -1 => Outcome::P0Win,
+            0 => Outcome::Draw,
+            1 => Outcome::P1Win,
+            _ => return Err(Error::db_error(&format!("invalid outcome {}", outcome))),
+        },
+    );
+    vote.set_comment(comment.as_deref());
+    vote.set_task_versions(versions.0, versions.1);
+    Ok(vote)
+}
+
+fn retraction_from_row(row: &rusqlite::Row) -> Result<Retraction, Error> {
+    let vote: String = row.get(1)?;
+    let voter: String = row.get(2)?;
+    let time: String = row.get(3)?;
+    let replacement: Option<String> = row.get(4)?;
+    Ok(Retraction::new(
+        parse_uuid(&vote)?,
+        &voter,
+        parse_time(&time)?,
+        replacement.as_deref().map(parse_uuid).transpose()?,
+    ))
+}
+
+fn rating_change_from_row(row: &rusqlite::Row) -> Result<RatingChange, Error> {
+    let task: String = row.get(1)?;
+    let time: String = row.get(2)?;
+    let old_elo: f32 = row.get(3)?;
+    let new_elo: f32 = row.get(4)?;
+    let vote: String = row.get(5)?;
+    let retraction: bool = row.get(6)?;
+    Ok(RatingChange::new(
+        parse_uuid(&task)?,
+        parse_time(&time)?,
+        old_elo,
+        new_elo,
+        parse_uuid(&vote)?,
+        retraction,
+    ))
+}
+
+fn outcome_to_column(outcome: Outcome) -> i32 {
+    match outcome {
+        Outcome::P0Win => -1,
+        Outcome::Draw => 0,
+        Outcome::P1Win => 1,
     }
 }
 
+// The cutoff and the number of compacted retractions of a vote archive.
+fn archive_from_row(row: &rusqlite::Row) -> Result<(DateTime<Utc>, u32), Error> {
+    let cutoff: String = row.get(1)?;
+    Ok((parse_time(&cutoff)?, row.get(2)?))
+}
+
+fn tally_from_row(row: &rusqlite::Row) -> Result<PairTally, Error> {
+    let task0: String = row.get(1)?;
+    let task1: String = row.get(2)?;
+    Ok(PairTally::with_counts(
+        parse_uuid(&task0)?,
+        parse_uuid(&task1)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+        row.get(6)?,
+    ))
+}
+
+fn parse_uuid(s: &str) -> Result<Uuid, Error> {
+    Uuid::parse_str(s).map_err(|e| Error::db_error(&e.to_string()))
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, Error> {
+    Ok(DateTime::parse_from_rfc3339(s)
+        .map_err(|e| Error::db_error(&e.to_string()))?
+        .into())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::data::{Rating, Task, User, Vote};
+    use crate::data::{Rating, Retraction, Role, Task, User, Vote};
     use crate::elo::Outcome;
     use crate::errors::ErrorCode;
-    use crate::persistence::{Persistence, SQLitePersistence};
+    use crate::fixtures::{TempPath, TempSQLitePersistence};
+    use crate::limits::{LimitPolicy, LimitWindow};
+    use crate::persistence::{CorruptRowPolicy, Persistence, RowProblem, SQLitePersistence};
+    use crate::query::TaskQuery;
 
     use url::Url;
     use uuid::Uuid;
 
     const EPSILON: f32 = 0.000001;
 
-    const TEST_USER_ID: &'static str = "test_user";
+    const TEST_USER_ID: &str = "test_user";
     const TEST_USER_LIMIT: i32 = 2;
 
-    const TEST_TASK_SUMMARY_0: &'static str = "task zero";
-    const TEST_TASK_SUMMARY_1: &'static str = "task one";
-
-    const TEST_SQLITE_PATH: &'static str = "/tmp/pelo-test-sqlite.db";
+    const TEST_TASK_SUMMARY_0: &str = "task zero";
+    const TEST_TASK_SUMMARY_1: &str = "task one";
 
-    static TEST_MUTEX: std::sync::Mutex<()> = std::sync::Mutex::new(());
-
-    fn init_sqlite() -> SQLitePersistence {
-        // Delete a file if it was present
-        let _ = std::fs::remove_file(TEST_SQLITE_PATH);
-
-        let mut database = SQLitePersistence::new(TEST_SQLITE_PATH.into()).unwrap();
+    fn init_sqlite() -> TempSQLitePersistence {
+        let mut database = TempSQLitePersistence::new().unwrap();
         database
             .upsert_user(&User::new(TEST_USER_ID, TEST_USER_LIMIT))
             .unwrap();
         database
-            .upsert_task(&Task::new(
-                Uuid::new_v4(),
-                TEST_TASK_SUMMARY_0,
-                Url::parse("https://localhost/0").unwrap(),
-                false,
-            ))
+            .upsert_task(
+                &Task::new(
+                    Uuid::new_v4(),
+                    TEST_TASK_SUMMARY_0,
+                    Url::parse("https://localhost/0").unwrap(),
+                    false,
+                ),
+                TEST_USER_ID,
+            )
             .unwrap();
         database
-            .upsert_task(&Task::new(
-                Uuid::new_v4(),
-                TEST_TASK_SUMMARY_1,
-                Url::parse("https://localhost/1").unwrap(),
-                false,
-            ))
+            .upsert_task(
+                &Task::new(
+                    Uuid::new_v4(),
+                    TEST_TASK_SUMMARY_1,
+                    Url::parse("https://localhost/1").unwrap(),
+                    false,
+                ),
+                TEST_USER_ID,
+            )
             .unwrap();
 
         database
@@ -549,23 +2520,45 @@ mod tests {
     fn destroy_sqlite(s: &mut SQLitePersistence) {
         s.connection.execute("drop table pelo_users", ()).unwrap();
         s.connection.execute("drop table pelo_tasks", ()).unwrap();
+        s.connection
+            .execute("drop table pelo_task_versions", ())
+            .unwrap();
         s.connection.execute("drop table pelo_ratings", ()).unwrap();
         s.connection.execute("drop table pelo_votes", ()).unwrap();
+        s.connection
+            .execute("drop table pelo_vote_retractions", ())
+            .unwrap();
         s.connection
             .execute("drop table pelo_global_etag", ())
             .unwrap();
+        s.connection
+            .execute("drop table pelo_task_tags", ())
+            .unwrap();
+        s.connection
+            .execute("drop table pelo_rating_changes", ())
+            .unwrap();
+        s.connection
+            .execute("drop table pelo_vote_archive", ())
+            .unwrap();
+        s.connection
+            .execute("drop table pelo_archived_ratings", ())
+            .unwrap();
+        s.connection
+            .execute("drop table pelo_vote_tallies", ())
+            .unwrap();
+        s.connection
+            .execute("drop table pelo_schema_version", ())
+            .unwrap();
     }
 
     #[test]
     fn test_sqlite_creation() {
-        let _guard = TEST_MUTEX.lock().unwrap();
         let mut database = init_sqlite();
         destroy_sqlite(&mut database);
     }
 
     #[test]
     fn test_sqlite_read_users() {
-        let _guard = TEST_MUTEX.lock().unwrap();
         let mut database = init_sqlite();
 
         let result0 = database.list_users();
@@ -579,13 +2572,29 @@ mod tests {
         let user = result1.unwrap();
         assert_eq!(user.id(), TEST_USER_ID);
         assert_eq!(user.limit_votes_per_week(), TEST_USER_LIMIT);
+        assert_eq!(user.role(), Role::Voter);
+
+        let result2 =
+            database.upsert_user(&User::with_role(TEST_USER_ID, TEST_USER_LIMIT, Role::Admin));
+        assert!(result2.is_ok());
+        let mut user = database.get_user(TEST_USER_ID).unwrap();
+        assert_eq!(user.role(), Role::Admin);
+        assert!(user.limit_policy().is_none());
+
+        let policy = LimitPolicy::new()
+            .with_limit(5, LimitWindow::Day)
+            .with_limit(20, LimitWindow::CalendarWeek)
+            .with_time_zone(chrono_tz::Europe::Rome);
+        user.set_limit_policy(Some(policy.clone()));
+        assert!(database.upsert_user(&user).is_ok());
+        let user = database.get_user(TEST_USER_ID).unwrap();
+        assert_eq!(user.limit_policy(), Some(&policy));
 
         destroy_sqlite(&mut database);
     }
 
     #[test]
     fn test_sqlite_handle_tasks() {
-        let _guard = TEST_MUTEX.lock().unwrap();
         let mut database = init_sqlite();
 
         let result0 = database.list_tasks();
@@ -600,7 +2609,7 @@ mod tests {
         );
         assert_ne!(tasks[0].summary(), tasks[1].summary());
 
-        let changed_task_id = tasks[0].id().clone();
+        let changed_task_id = *tasks[0].id();
         let result1 = database.close_task(&changed_task_id);
         assert!(result1.is_ok());
 
@@ -622,6 +2631,31 @@ mod tests {
             assert!(tasks[1].closed());
         }
 
+        let mut edited = tasks[0].clone();
+        assert_eq!(edited.version(), 1);
+        edited = Task::new(
+            *edited.id(),
+            "edited summary",
+            edited.link().clone(),
+            edited.closed(),
+        );
+        assert!(database.upsert_task(&edited, "editor").is_ok());
+        let task = database
+            .list_tasks()
+            .unwrap()
+            .into_iter()
+            .find(|t| t.id() == edited.id())
+            .unwrap();
+        assert_eq!(task.version(), 2);
+        assert_eq!(task.summary(), "edited summary");
+        let versions = database.list_task_versions(edited.id()).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].version(), 1);
+        assert_eq!(versions[0].author(), TEST_USER_ID);
+        assert_eq!(versions[1].version(), 2);
+        assert_eq!(versions[1].author(), "editor");
+        assert_eq!(versions[1].summary(), "edited summary");
+
         destroy_sqlite(&mut database);
     }
 
@@ -629,7 +2663,6 @@ mod tests {
 
     #[test]
     fn test_sqlite_handle_votes() {
-        let _guard = TEST_MUTEX.lock().unwrap();
         let mut database = init_sqlite();
 
         let result0 = database.list_tasks();
@@ -655,19 +2688,32 @@ mod tests {
 
         let old_etag = snapshot0.etag().clone();
         let now: DateTime<Utc> = std::time::SystemTime::now().into();
+        let mut vote = Vote::new(TEST_USER_ID, now, *t0.id(), *t1.id(), Outcome::P1Win);
+        vote.set_comment(Some("one first"));
+        vote.set_task_versions(1, 1);
         let result3 = database.add_vote_and_update_ratings(
             &old_etag,
-            &Vote::new(
-                TEST_USER_ID,
-                now,
-                t0.id().clone(),
-                t1.id().clone(),
-                Outcome::P1Win,
-            ),
-            &Rating::with_elo(t0.id().clone(), 1184.0),
-            &Rating::with_elo(t1.id().clone(), 1216.0),
+            &vote,
+            &Rating::with_elo(*t0.id(), 1184.0),
+            &Rating::with_elo(*t1.id(), 1216.0),
         );
         assert!(result3.is_ok());
+        assert_eq!(
+            database.get_vote(vote.id()).unwrap().comment(),
+            Some("one first")
+        );
+        assert_eq!(
+            database.get_vote(vote.id()).unwrap().task_versions(),
+            (1, 1)
+        );
+
+        let votes = database
+            .list_votes_for_user_since(TEST_USER_ID, &last_week)
+            .unwrap();
+        assert_eq!(votes.len(), 1);
+        assert_eq!(votes[0].task0(), t0.id());
+        assert_eq!(votes[0].task1(), t1.id());
+        assert!(matches!(votes[0].outcome(), Outcome::P1Win));
 
         let result4 = database.get_snapshot();
         assert!(result4.is_ok());
@@ -687,15 +2733,9 @@ mod tests {
         // and this should be rejected.
         let result5 = database.add_vote_and_update_ratings(
             &old_etag,
-            &Vote::new(
-                TEST_USER_ID,
-                now,
-                t0.id().clone(),
-                t1.id().clone(),
-                Outcome::P1Win,
-            ),
-            &Rating::with_elo(t0.id().clone(), 1184.0),
-            &Rating::with_elo(t1.id().clone(), 1216.0),
+            &Vote::new(TEST_USER_ID, now, *t0.id(), *t1.id(), Outcome::P1Win),
+            &Rating::with_elo(*t0.id(), 1184.0),
+            &Rating::with_elo(*t1.id(), 1216.0),
         );
         assert!(result5.is_err());
         assert_eq!(
@@ -703,4 +2743,607 @@ mod tests {
             ErrorCode::OptimisticConcurrencyRetryTransaction
         );
     }
+
+    #[test]
+    fn test_sqlite_handle_retractions() {
+        let mut database = init_sqlite();
+
+        let tasks = database.list_tasks().unwrap();
+        let t0 = tasks[0].clone();
+        let t1 = tasks[1].clone();
+        let now: DateTime<Utc> = std::time::SystemTime::now().into();
+        let vote0 = Vote::new(TEST_USER_ID, now, *t0.id(), *t1.id(), Outcome::P1Win);
+        let snapshot0 = database.get_snapshot().unwrap();
+        database
+            .add_vote_and_update_ratings(
+                snapshot0.etag(),
+                &vote0,
+                &Rating::with_elo(*t0.id(), 1184.0),
+                &Rating::with_elo(*t1.id(), 1216.0),
+            )
+            .unwrap();
+        assert_eq!(database.get_vote(vote0.id()).unwrap().voter(), TEST_USER_ID);
+        let result0 = database.get_vote(&Uuid::new_v4());
+        assert_eq!(result0.err().unwrap().code(), ErrorCode::VoteNotFound);
+
+        let vote1 = Vote::new(TEST_USER_ID, now, *t0.id(), *t1.id(), Outcome::P0Win);
+        let retraction = Retraction::new(*vote0.id(), TEST_USER_ID, now, Some(*vote1.id()));
+        let snapshot1 = database.get_snapshot().unwrap();
+        let ratings = vec![
+            Rating::with_elo(*t0.id(), 1216.0),
+            Rating::with_elo(*t1.id(), 1184.0),
+        ];
+        let result1 = database.retract_vote_and_replace_ratings(
+            snapshot0.etag(),
+            &retraction,
+            Some(&vote1),
+            &ratings,
+        );
+        assert_eq!(
+            result1.err().unwrap().code(),
+            ErrorCode::OptimisticConcurrencyRetryTransaction
+        );
+        database
+            .retract_vote_and_replace_ratings(snapshot1.etag(), &retraction, Some(&vote1), &ratings)
+            .unwrap();
+
+        let votes = database.list_votes().unwrap();
+        assert_eq!(votes.len(), 2);
+        assert_eq!(database.list_votes_for_task(t1.id()).unwrap().len(), 2);
+        assert!(database
+            .list_votes_for_task(&Uuid::new_v4())
+            .unwrap()
+            .is_empty());
+        let retractions = database.list_retractions().unwrap();
+        assert_eq!(retractions.len(), 1);
+        assert_eq!(retractions[0].vote(), vote0.id());
+        assert_eq!(retractions[0].replacement(), Some(vote1.id()));
+        for rating in database.get_snapshot().unwrap().ranking() {
+            let expected = if rating.task() == t0.id() {
+                1216.0
+            } else {
+                1184.0
+            };
+            assert!((rating.elo() - expected).abs() < EPSILON);
+        }
+
+        let snapshot2 = database.get_snapshot().unwrap();
+        let result2 =
+            database.retract_vote_and_replace_ratings(snapshot2.etag(), &retraction, None, &[]);
+        assert_eq!(
+            result2.err().unwrap().code(),
+            ErrorCode::RetractionNotAllowed
+        );
+    }
+
+    #[test]
+    fn test_sqlite_upsert_keeps_rating() {
+        let mut database = init_sqlite();
+
+        let tasks = database.list_tasks().unwrap();
+        let t0 = tasks[0].clone();
+        let t1 = tasks[1].clone();
+        let snapshot = database.get_snapshot().unwrap();
+        database
+            .add_vote_and_update_ratings(
+                snapshot.etag(),
+                &Vote::new(
+                    TEST_USER_ID,
+                    std::time::SystemTime::now().into(),
+                    *t0.id(),
+                    *t1.id(),
+                    Outcome::P0Win,
+                ),
+                &Rating::with_elo(*t0.id(), 1216.0),
+                &Rating::with_elo(*t1.id(), 1184.0),
+            )
+            .unwrap();
+
+        let renamed = Task::new(*t0.id(), "renamed task", t0.link().clone(), false);
+        database.upsert_task(&renamed, TEST_USER_ID).unwrap();
+        database.upsert_task(&renamed, TEST_USER_ID).unwrap();
+
+        let ranking = database.get_snapshot().unwrap().ranking().clone();
+        assert_eq!(ranking.len(), 2);
+        let r0 = ranking.iter().find(|r| r.task() == t0.id()).unwrap();
+        assert!((r0.elo() - 1216.0).abs() < EPSILON);
+        let count: usize = database
+            .connection
+            .query_row("SELECT COUNT(*) FROM pelo_ratings", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn test_sqlite_concurrent_task_versions() {
+        let database = init_sqlite();
+        let task = database.list_tasks().unwrap()[0].clone();
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(4));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let path = database.path().to_path_buf();
+                let barrier = barrier.clone();
+                let task = task.clone();
+                std::thread::spawn(move || {
+                    let mut connection = SQLitePersistence::new(path).unwrap();
+                    barrier.wait();
+                    for j in 0..5 {
+                        let summary = format!("edit {} of writer {}", j, i);
+                        let edited = Task::new(*task.id(), &summary, task.link().clone(), false);
+                        connection.upsert_task(&edited, TEST_USER_ID).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let versions = database.list_task_versions(task.id()).unwrap();
+        let numbers: Vec<u32> = versions.iter().map(|v| v.version()).collect();
+        assert_eq!(numbers, (1..=21).collect::<Vec<u32>>());
+        assert_eq!(database.list_tasks().unwrap()[0].version(), 21);
+    }
+
+    #[test]
+    fn test_sqlite_corrupt_rows() {
+        let mut database = SQLitePersistence::new(":memory:".into()).unwrap();
+        database
+            .upsert_user(&User::new(TEST_USER_ID, TEST_USER_LIMIT))
+            .unwrap();
+        let task = Task::new(
+            Uuid::new_v4(),
+            TEST_TASK_SUMMARY_0,
+            Url::parse("https://localhost/0").unwrap(),
+            false,
+        );
+        database.upsert_task(&task, TEST_USER_ID).unwrap();
+        database
+            .connection
+            .execute(
+                "insert into pelo_tasks(id, summary, link, closed, version)
+                 values ('not-a-uuid', 'bad', 'https://localhost/', 0, 1)",
+                (),
+            )
+            .unwrap();
+        let orphan = Uuid::new_v4().to_string();
+        database
+            .connection
+            .execute(
+                "insert into pelo_ratings(task, elo) values (?1, 1200.0)",
+                (&orphan,),
+            )
+            .unwrap();
+        let vote = Vote::new(
+            "ghost",
+            std::time::SystemTime::now().into(),
+            *task.id(),
+            Uuid::new_v4(),
+            Outcome::Draw,
+        );
+        let namespace = crate::persistence::Namespace {
+            prefix: crate::persistence::DEFAULT_TABLE_PREFIX.to_string(),
+            tenant: crate::persistence::DEFAULT_TENANT.to_string(),
+        };
+        let tx = database.connection.transaction().unwrap();
+        crate::persistence::insert_vote(&tx, &namespace, &vote).unwrap();
+        tx.commit().unwrap();
+
+        let result = database.list_tasks();
+        let error = result.err().unwrap();
+        assert_eq!(error.code(), ErrorCode::CorruptData);
+        assert!(error.msg().contains("pelo_tasks"));
+
+        database.set_corrupt_row_policy(CorruptRowPolicy::Skip);
+        let tasks = database.list_tasks().unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].id(), task.id());
+        let skipped = database.take_skipped_rows();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].table(), "pelo_tasks");
+        assert!(matches!(skipped[0].problem(), RowProblem::Malformed(_)));
+        assert!(database.take_skipped_rows().is_empty());
+
+        let report = database.check_integrity().unwrap();
+        assert_eq!(report.len(), 4);
+        assert!(report
+            .iter()
+            .any(|r| r.table() == "pelo_tasks" && matches!(r.problem(), RowProblem::Malformed(_))));
+        assert!(report
+            .iter()
+            .any(|r| r.table() == "pelo_ratings" && r.problem() == &RowProblem::OrphanedRating));
+        assert!(report.iter().any(
+            |r| r.table() == "pelo_votes" && matches!(r.problem(), RowProblem::UnknownTask(_))
+        ));
+        assert!(report
+            .iter()
+            .any(|r| r.problem() == &RowProblem::UnknownUser("ghost".to_string())));
+
+        database
+            .connection
+            .execute(
+                "insert into pelo_vote_archive(tenant, cutoff, retractions)
+                 values ('', 'last week', 0)",
+                (),
+            )
+            .unwrap();
+        let archive = database.get_vote_archive().unwrap();
+        assert!(archive.cutoff().is_none());
+        let skipped = database.take_skipped_rows();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].table(), "pelo_vote_archive");
+        database.set_corrupt_row_policy(CorruptRowPolicy::Fail);
+        let error = database.get_vote_archive().err().unwrap();
+        assert_eq!(error.code(), ErrorCode::CorruptData);
+        assert!(error.msg().contains("pelo_vote_archive"));
+        let report = database.check_integrity().unwrap();
+        assert_eq!(report.len(), 5);
+        assert!(report.iter().any(|r| r.table() == "pelo_vote_archive"));
+    }
+
+    #[test]
+    fn test_sqlite_integrity_of_vote_archive() {
+        let database = init_sqlite();
+        let tasks = database.list_tasks().unwrap();
+        let (known, unknown) = (tasks[0].id().to_string(), Uuid::new_v4().to_string());
+        database
+            .connection
+            .execute(
+                "insert into pelo_archived_ratings(tenant, task, elo) values ('', ?1, 1200.0)",
+                (&unknown,),
+            )
+            .unwrap();
+        for (task0, task1) in [(&known, &unknown), (&unknown, &known)] {
+            database
+                .connection
+                .execute(
+                    "insert into pelo_vote_tallies(
+                         tenant, task0, task1, wins, draws, losses, retracted
+                     )
+                     values ('', ?1, ?2, 1, 0, 0, 0)",
+                    (task0, task1),
+                )
+                .unwrap();
+        }
+
+        let report = database.check_integrity().unwrap();
+        assert_eq!(report.len(), 3);
+        assert!(report
+            .iter()
+            .any(|r| r.table() == "pelo_archived_ratings"
+                && r.problem() == &RowProblem::OrphanedRating));
+        assert_eq!(
+            report
+                .iter()
+                .filter(|r| r.table() == "pelo_vote_tallies"
+                    && r.problem() == &RowProblem::UnknownTask(unknown.clone()))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_sqlite_snapshot_corrupt_rating() {
+        let mut database = init_sqlite();
+        database
+            .connection
+            .execute(
+                "insert into pelo_ratings(task, elo, revision) values ('not-a-uuid', 1200.0, 3)",
+                (),
+            )
+            .unwrap();
+        let error = database.get_snapshot().err().unwrap();
+        assert_eq!(error.code(), ErrorCode::CorruptData);
+        assert!(error.msg().contains("pelo_ratings"));
+
+        // The corrupt row is dropped from both the ranking and the revisions.
+        database.set_corrupt_row_policy(CorruptRowPolicy::Skip);
+        let snapshot = database.get_snapshot().unwrap();
+        assert_eq!(snapshot.ranking().len(), 2);
+        assert_eq!(snapshot.etag().revisions.len(), 2);
+        assert!(snapshot
+            .ranking()
+            .iter()
+            .all(|r| snapshot.etag().revision(r.task()).is_some()));
+        assert_eq!(database.take_skipped_rows().len(), 1);
+    }
+
+    fn backup_path() -> TempPath {
+        TempPath::new(".db")
+    }
+
+    #[test]
+    fn test_sqlite_backup_and_restore() {
+        let mut database = init_sqlite();
+        let tasks = database.list_tasks().unwrap();
+        let (t0, t1) = (*tasks[0].id(), *tasks[1].id());
+        let now: DateTime<Utc> = std::time::SystemTime::now().into();
+        let vote = |database: &mut SQLitePersistence, etag| {
+            database.add_vote_and_update_ratings(
+                etag,
+                &Vote::new(TEST_USER_ID, now, t0, t1, Outcome::P1Win),
+                &Rating::with_elo(t0, 1184.0),
+                &Rating::with_elo(t1, 1216.0),
+            )
+        };
+
+        let snapshot = database.get_snapshot().unwrap();
+        vote(&mut database, snapshot.etag()).unwrap();
+        let backup = backup_path();
+        database.backup(backup.path()).unwrap();
+
+        let snapshot = database.get_snapshot().unwrap();
+        vote(&mut database, snapshot.etag()).unwrap();
+        let stale = database.get_snapshot().unwrap();
+        assert_eq!(database.list_votes().unwrap().len(), 2);
+
+        database.restore(backup.path()).unwrap();
+        assert_eq!(database.list_votes().unwrap().len(), 1);
+        assert_eq!(database.list_tasks().unwrap().len(), 2);
+        assert_eq!(
+            vote(&mut database, stale.etag()).err().unwrap().code(),
+            ErrorCode::OptimisticConcurrencyRetryTransaction
+        );
+        let fresh = database.get_snapshot().unwrap();
+        assert_ne!(fresh.etag().token, stale.etag().token);
+        vote(&mut database, fresh.etag()).unwrap();
+        assert_eq!(database.list_votes().unwrap().len(), 2);
+
+        let copy_path = backup_path();
+        let copy = SQLitePersistence::restore_into(backup.path(), copy_path.to_path_buf()).unwrap();
+        assert_eq!(copy.list_votes().unwrap().len(), 1);
+        assert!(copy.check_integrity().unwrap().is_empty());
+        assert_eq!(
+            SQLitePersistence::restore_into(backup.path(), copy_path.to_path_buf())
+                .err()
+                .unwrap()
+                .code(),
+            ErrorCode::GenericError
+        );
+    }
+
+    #[test]
+    fn test_sqlite_restore_refuses_newer_schema() {
+        let mut database = init_sqlite();
+        let backup = backup_path();
+        database.backup(backup.path()).unwrap();
+        rusqlite::Connection::open(backup.path())
+            .unwrap()
+            .execute(
+                "update pelo_schema_version set version = ?1",
+                (crate::persistence::SCHEMA_VERSION + 1,),
+            )
+            .unwrap();
+        database.upsert_user(&User::new("other", 1)).unwrap();
+
+        assert_eq!(
+            database.restore(backup.path()).err().unwrap().code(),
+            ErrorCode::SchemaTooNew
+        );
+        assert_eq!(database.list_users().unwrap().len(), 2);
+        assert_eq!(
+            SQLitePersistence::restore_into(backup.path(), backup_path().to_path_buf())
+                .err()
+                .unwrap()
+                .code(),
+            ErrorCode::SchemaTooNew
+        );
+    }
+
+    #[test]
+    fn test_sqlite_tenants() {
+        let mut default = init_sqlite();
+        let mut other =
+            SQLitePersistence::with_tenant(default.path().to_path_buf(), "other").unwrap();
+        assert_eq!(default.tenant(), "");
+        assert_eq!(other.tenant(), "other");
+        assert!(other.list_tasks().unwrap().is_empty());
+        assert!(other.list_users().unwrap().is_empty());
+
+        // The same user, with another role and limit, and a task with the
+        // same id as one of the default tenant.
+        other
+            .upsert_user(&User::with_role(TEST_USER_ID, -1, Role::Admin))
+            .unwrap();
+        let tasks = default.list_tasks().unwrap();
+        let (t0, t1) = (*tasks[0].id(), *tasks[1].id());
+        let mut shared = Task::new(
+            t0,
+            "other zero",
+            Url::parse("https://other/0").unwrap(),
+            false,
+        );
+        shared.set_tags(&["other"]);
+        other.upsert_task(&shared, TEST_USER_ID).unwrap();
+        other
+            .upsert_task(
+                &Task::new(
+                    t1,
+                    "other one",
+                    Url::parse("https://other/1").unwrap(),
+                    false,
+                ),
+                TEST_USER_ID,
+            )
+            .unwrap();
+        assert_eq!(default.get_user(TEST_USER_ID).unwrap().role(), Role::Voter);
+        assert_eq!(other.get_user(TEST_USER_ID).unwrap().role(), Role::Admin);
+        assert_eq!(default.list_task_versions(&t0).unwrap().len(), 1);
+        assert_eq!(
+            default.list_tasks().unwrap()[0].summary(),
+            TEST_TASK_SUMMARY_0
+        );
+        assert!(default.list_tasks().unwrap()[0].tags().is_empty());
+        let tagged = TaskQuery::new().with_tag("other");
+        assert_eq!(default.count_tasks(&tagged).unwrap(), 0);
+        assert_eq!(other.count_tasks(&tagged).unwrap(), 1);
+
+        // Each tenant has an etag of its own.
+        let default_token = default.get_etag_token().unwrap();
+        let snapshot = other.get_snapshot().unwrap();
+        let vote = Vote::new(
+            TEST_USER_ID,
+            std::time::SystemTime::now().into(),
+            t0,
+            t1,
+            Outcome::P0Win,
+        );
+        other
+            .add_vote_and_update_ratings(
+                snapshot.etag(),
+                &vote,
+                &Rating::with_elo(t0, 1216.0),
+                &Rating::with_elo(t1, 1184.0),
+            )
+            .unwrap();
+        assert_eq!(default.get_etag_token().unwrap(), default_token);
+        assert_ne!(other.get_etag_token().unwrap(), snapshot.etag().token);
+
+        assert!(default.list_votes().unwrap().is_empty());
+        assert_eq!(
+            default.get_vote(vote.id()).err().unwrap().code(),
+            ErrorCode::VoteNotFound
+        );
+        assert!(default.list_rating_changes(&t0).unwrap().is_empty());
+        assert_eq!(
+            default
+                .get_num_votes_for_user_since(TEST_USER_ID, vote.time())
+                .unwrap(),
+            0
+        );
+        let ranking = default.get_snapshot().unwrap().ranking().clone();
+        assert!(ranking.iter().all(|r| (r.elo() - 1200.0).abs() < EPSILON));
+        assert_eq!(other.list_votes_for_task(&t0).unwrap().len(), 1);
+
+        default.close_task(&t0).unwrap();
+        assert!(!other.list_tasks().unwrap().iter().any(|t| t.closed()));
+        assert!(default.check_integrity().unwrap().is_empty());
+        assert!(other.check_integrity().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sqlite_tenant_backup_and_restore() {
+        let mut default = init_sqlite();
+        let mut other =
+            SQLitePersistence::with_tenant(default.path().to_path_buf(), "other").unwrap();
+        other.upsert_user(&User::new("other_user", 1)).unwrap();
+        let backup = backup_path();
+        other.backup(backup.path()).unwrap();
+        let count_users = |tenant: &str| -> usize {
+            rusqlite::Connection::open(backup.path())
+                .unwrap()
+                .query_row(
+                    "SELECT COUNT(*) FROM pelo_users WHERE tenant = ?1",
+                    [tenant],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(count_users("other"), 1);
+        assert_eq!(count_users(""), 0);
+
+        // Restoring a tenant leaves the others, and their etags, untouched.
+        other.upsert_user(&User::new("late_user", 1)).unwrap();
+        default.upsert_user(&User::new("late_user", 1)).unwrap();
+        let snapshot = default.get_snapshot().unwrap();
+        other.restore(backup.path()).unwrap();
+        assert_eq!(other.list_users().unwrap().len(), 1);
+        assert_eq!(
+            other.get_user("late_user").err().unwrap().code(),
+            ErrorCode::UserNotFound
+        );
+        assert_eq!(default.list_users().unwrap().len(), 2);
+        assert_eq!(default.get_etag_token().unwrap(), snapshot.etag().token);
+        let ranking = snapshot.ranking();
+        default
+            .add_vote_and_update_ratings(
+                snapshot.etag(),
+                &Vote::new(
+                    TEST_USER_ID,
+                    std::time::SystemTime::now().into(),
+                    *ranking[0].task(),
+                    *ranking[1].task(),
+                    Outcome::Draw,
+                ),
+                &ranking[0],
+                &ranking[1],
+            )
+            .unwrap();
+
+        // The whole database is backed up and restored for every tenant.
+        let whole = backup_path();
+        default.backup_database(whole.path()).unwrap();
+        other.upsert_user(&User::new("later_user", 1)).unwrap();
+        default.upsert_user(&User::new("later_user", 1)).unwrap();
+        default.restore_database(whole.path()).unwrap();
+        assert_eq!(default.list_users().unwrap().len(), 2);
+        assert_eq!(other.list_users().unwrap().len(), 1);
+        assert_eq!(default.list_votes().unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_sqlite_encryption() {
+        let file = TempPath::new(".db");
+        let path = file.to_path_buf();
+        let mut database = SQLitePersistence::with_key(path.clone(), "first key").unwrap();
+        database
+            .upsert_user(&User::new(TEST_USER_ID, TEST_USER_LIMIT))
+            .unwrap();
+        drop(database);
+        let content = std::fs::read(&path).unwrap();
+        assert!(!content
+            .windows(TEST_USER_ID.len())
+            .any(|w| w == TEST_USER_ID.as_bytes()));
+
+        let code =
+            |result: Result<SQLitePersistence, crate::errors::Error>| result.err().unwrap().code();
+        assert_eq!(
+            code(SQLitePersistence::with_key(path.clone(), "other key")),
+            ErrorCode::WrongKey
+        );
+        assert_eq!(
+            code(SQLitePersistence::new(path.clone())),
+            ErrorCode::KeyRequired
+        );
+
+        let mut database = SQLitePersistence::with_key(path.clone(), "first key").unwrap();
+        assert_eq!(database.list_users().unwrap().len(), 1);
+        database.rekey("second key").unwrap();
+        drop(database);
+        assert_eq!(
+            code(SQLitePersistence::with_key(path.clone(), "first key")),
+            ErrorCode::WrongKey
+        );
+        let database = SQLitePersistence::with_key(path, "second key").unwrap();
+        assert_eq!(database.list_users().unwrap().len(), 1);
+        let backup = TempPath::new(".db");
+        assert_eq!(
+            database.backup(backup.path()).err().unwrap().code(),
+            ErrorCode::NotImplemented
+        );
+        assert!(!backup.path().exists());
+
+        // A plain database cannot be opened with a key.
+        let plain = TempPath::new(".db");
+        drop(SQLitePersistence::new(plain.to_path_buf()).unwrap());
+        assert_eq!(
+            code(SQLitePersistence::with_key(
+                plain.to_path_buf(),
+                "first key"
+            )),
+            ErrorCode::WrongKey
+        );
+    }
+
+    mod conformance_in_memory {
+        crate::persistence_conformance_tests!(crate::persistence::InMemory::new());
+    }
+
+    mod conformance_sqlite {
+        crate::persistence_conformance_tests!(crate::persistence::SQLitePersistence::new(
+            ":memory:".into()
+        )
+        .unwrap());
+    }
 }
@@ -0,0 +1,319 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::data::{Rating, RatingChange, Retraction, Task, TaskVersion, User, Vote, VoteArchive};
+use crate::errors::Error;
+use crate::persistence::{Etag, Persistence, Snapshot};
+use crate::query::{TaskQuery, UserQuery};
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// The asynchronous counterpart of `Persistence`, for use with `AsyncEngine`.
+///
+/// Methods take `&self`, so that a backend can be shared by concurrent
+/// requests; each call is expected to be atomic on its own, with the etag
+/// protecting the writes that depend on an earlier read.
+pub trait AsyncPersistence {
+    fn list_users(&self) -> impl Future<Output = Result<Vec<User>, Error>> + Send;
+
+    fn upsert_user(&self, u: &User) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn get_user(&self, u_id: &str) -> impl Future<Output = Result<User, Error>> + Send;
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> impl Future<Output = Result<usize, Error>> + Send;
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> impl Future<Output = Result<Vec<Vote>, Error>> + Send;
+
+    fn list_tasks(&self) -> impl Future<Output = Result<Vec<Task>, Error>> + Send;
+
+    fn get_task(&self, t_id: &Uuid) -> impl Future<Output = Result<Task, Error>> + Send;
+
+    fn upsert_task(&self, t: &Task, author: &str)
+        -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn list_task_versions(
+        &self,
+        t_id: &Uuid,
+    ) -> impl Future<Output = Result<Vec<TaskVersion>, Error>> + Send;
+
+    fn close_task(&self, t_id: &Uuid) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn get_snapshot(&self) -> impl Future<Output = Result<Snapshot, Error>> + Send;
+
+    fn get_etag_token(&self) -> impl Future<Output = Result<String, Error>> + Send;
+
+    fn add_vote_and_update_ratings(
+        &self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn list_votes(&self) -> impl Future<Output = Result<Vec<Vote>, Error>> + Send;
+
+    fn get_vote(&self, v_id: &Uuid) -> impl Future<Output = Result<Vote, Error>> + Send;
+
+    fn list_votes_for_task(
+        &self,
+        t_id: &Uuid,
+    ) -> impl Future<Output = Result<Vec<Vote>, Error>> + Send;
+
+    fn list_retractions(&self) -> impl Future<Output = Result<Vec<Retraction>, Error>> + Send;
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> impl Future<Output = Result<Vec<Retraction>, Error>> + Send;
+
+    fn retract_vote_and_replace_ratings(
+        &self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    fn list_rating_changes(
+        &self,
+        t_id: &Uuid,
+    ) -> impl Future<Output = Result<Vec<RatingChange>, Error>> + Send;
+
+    fn get_ranking_at(
+        &self,
+        time: &DateTime<Utc>,
+    ) -> impl Future<Output = Result<Vec<Rating>, Error>> + Send;
+
+    /// See `Persistence::get_vote_archive`.
+    fn get_vote_archive(&self) -> impl Future<Output = Result<VoteArchive, Error>> + Send {
+        async { Ok(VoteArchive::new()) }
+    }
+
+    /// See `Persistence::compact_votes`.
+    fn compact_votes(
+        &self,
+        _etag: &Etag,
+        _archive: &VoteArchive,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async {
+            Err(Error::not_implemented(
+                "this backend does not support vote compaction",
+            ))
+        }
+    }
+
+    fn query_tasks(
+        &self,
+        query: &TaskQuery,
+    ) -> impl Future<Output = Result<Vec<Task>, Error>> + Send;
+
+    fn count_tasks(&self, query: &TaskQuery) -> impl Future<Output = Result<usize, Error>> + Send;
+
+    fn query_users(
+        &self,
+        query: &UserQuery,
+    ) -> impl Future<Output = Result<Vec<User>, Error>> + Send;
+}
+
+/// Makes a synchronous `Persistence` usable from async code. Every call runs
+/// on the blocking thread pool of the Tokio runtime, while holding a lock on
+/// the backend for the duration of that call only.
+pub struct BlockingPersistence<P> {
+    inner: Arc<Mutex<P>>,
+}
+impl<P> Clone for BlockingPersistence<P> {
+    fn clone(&self) -> Self {
+        BlockingPersistence {
+            inner: self.inner.clone(),
+        }
+    }
+}
+impl<P: Persistence + Send + 'static> BlockingPersistence<P> {
+    pub fn new(persistence: P) -> Self {
+        BlockingPersistence {
+            inner: Arc::new(Mutex::new(persistence)),
+        }
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut P) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut persistence = inner
+                .lock()
+                .map_err(|_| Error::generic("a previous call panicked while using the backend"))?;
+            f(&mut persistence)
+        })
+        .await
+        .map_err(|e| Error::generic(&e.to_string()))?
+    }
+}
+
+impl<P: Persistence + Send + 'static> AsyncPersistence for BlockingPersistence<P> {
+    async fn list_users(&self) -> Result<Vec<User>, Error> {
+        self.run(|p| p.list_users()).await
+    }
+
+    async fn upsert_user(&self, u: &User) -> Result<(), Error> {
+        let u = u.clone();
+        self.run(move |p| p.upsert_user(&u)).await
+    }
+
+    async fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        let u_id = u_id.to_string();
+        self.run(move |p| p.get_user(&u_id)).await
+    }
+
+    async fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        let (u_id, since) = (u_id.to_string(), *since);
+        self.run(move |p| p.get_num_votes_for_user_since(&u_id, &since))
+            .await
+    }
+
+    async fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        let (u_id, since) = (u_id.to_string(), *since);
+        self.run(move |p| p.list_votes_for_user_since(&u_id, &since))
+            .await
+    }
+
+    async fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        self.run(|p| p.list_tasks()).await
+    }
+
+    async fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        let t_id = *t_id;
+        self.run(move |p| p.get_task(&t_id)).await
+    }
+
+    async fn upsert_task(&self, t: &Task, author: &str) -> Result<(), Error> {
+        let (t, author) = (t.clone(), author.to_string());
+        self.run(move |p| p.upsert_task(&t, &author)).await
+    }
+
+    async fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        let t_id = *t_id;
+        self.run(move |p| p.list_task_versions(&t_id)).await
+    }
+
+    async fn close_task(&self, t_id: &Uuid) -> Result<(), Error> {
+        let t_id = *t_id;
+        self.run(move |p| p.close_task(&t_id)).await
+    }
+
+    async fn get_snapshot(&self) -> Result<Snapshot, Error> {
+        self.run(|p| p.get_snapshot()).await
+    }
+
+    async fn get_etag_token(&self) -> Result<String, Error> {
+        self.run(|p| p.get_etag_token()).await
+    }
+
+    async fn add_vote_and_update_ratings(
+        &self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> Result<(), Error> {
+        let (etag, vote, r0, r1) = (etag.clone(), vote.clone(), r0.clone(), r1.clone());
+        self.run(move |p| p.add_vote_and_update_ratings(&etag, &vote, &r0, &r1))
+            .await
+    }
+
+    async fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.run(|p| p.list_votes()).await
+    }
+
+    async fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        let v_id = *v_id;
+        self.run(move |p| p.get_vote(&v_id)).await
+    }
+
+    async fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        let t_id = *t_id;
+        self.run(move |p| p.list_votes_for_task(&t_id)).await
+    }
+
+    async fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.run(|p| p.list_retractions()).await
+    }
+
+    async fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        let (u_id, since) = (u_id.to_string(), *since);
+        self.run(move |p| p.list_retractions_for_user_since(&u_id, &since))
+            .await
+    }
+
+    async fn retract_vote_and_replace_ratings(
+        &self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        let (etag, retraction) = (etag.clone(), retraction.clone());
+        let (replacement, ratings) = (replacement.cloned(), ratings.to_vec());
+        self.run(move |p| {
+            p.retract_vote_and_replace_ratings(&etag, &retraction, replacement.as_ref(), &ratings)
+        })
+        .await
+    }
+
+    async fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        let t_id = *t_id;
+        self.run(move |p| p.list_rating_changes(&t_id)).await
+    }
+
+    async fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        let time = *time;
+        self.run(move |p| p.get_ranking_at(&time)).await
+    }
+
+    async fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        self.run(|p| p.get_vote_archive()).await
+    }
+
+    async fn compact_votes(&self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        let (etag, archive) = (etag.clone(), archive.clone());
+        self.run(move |p| p.compact_votes(&etag, &archive)).await
+    }
+
+    async fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        let query = query.clone();
+        self.run(move |p| p.query_tasks(&query)).await
+    }
+
+    async fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+        let query = query.clone();
+        self.run(move |p| p.count_tasks(&query)).await
+    }
+
+    async fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+        let query = query.clone();
+        self.run(move |p| p.query_users(&query)).await
+    }
+}
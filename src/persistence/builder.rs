@@ -0,0 +1,333 @@
+use rusqlite::OptionalExtension;
+
+use crate::errors::Error;
+use crate::persistence::{SQLitePersistence, DEFAULT_TABLE_PREFIX, DEFAULT_TENANT};
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Opens a `SQLitePersistence` with options beyond those of
+/// `SQLitePersistence::new`, for instance to keep pelo's tables in the
+/// database of an existing application.
+///
+/// Unless told otherwise, the builder opens the database as `new` does: the
+/// file is created if it does not exist, the schema is created or upgraded,
+/// the etag of the tenant is reset, and SQLite's defaults apply.
+///
+/// ```
+/// use pelo::{Persistence, SQLitePersistenceBuilder};
+///
+/// let database = SQLitePersistenceBuilder::in_memory()
+///     .with_table_prefix("app_pelo_")
+///     .with_pragma("foreign_keys", "ON")
+///     .open()
+///     .unwrap();
+/// assert!(database.list_tasks().unwrap().is_empty());
+/// ```
+#[derive(Clone)]
+pub struct SQLitePersistenceBuilder {
+    pub(super) path: PathBuf,
+    pub(super) read_only: bool,
+    pub(super) create_file: bool,
+    pub(super) create_schema: bool,
+    pub(super) reset_etag: bool,
+    pub(super) journal_mode: Option<String>,
+    pub(super) busy_timeout: Option<Duration>,
+    pub(super) pragmas: Vec<(String, String)>,
+    pub(super) table_prefix: String,
+    pub(super) tenant: String,
+    #[cfg(feature = "sqlcipher")]
+    pub(super) key: Option<String>,
+}
+impl SQLitePersistenceBuilder {
+    pub fn new(db_path: PathBuf) -> Self {
+        SQLitePersistenceBuilder {
+            path: db_path,
+            read_only: false,
+            create_file: true,
+            create_schema: true,
+            reset_etag: true,
+            journal_mode: None,
+            busy_timeout: None,
+            pragmas: Vec::new(),
+            table_prefix: DEFAULT_TABLE_PREFIX.to_string(),
+            tenant: DEFAULT_TENANT.to_string(),
+            #[cfg(feature = "sqlcipher")]
+            key: None,
+        }
+    }
+
+    /// A database that lives in memory, and is lost when it is dropped.
+    pub fn in_memory() -> Self {
+        Self::new(":memory:".into())
+    }
+
+    /// Opens the database read-only. Its schema must be up to date, since it
+    /// cannot be upgraded, the etag is left as it is, and writes fail with
+    /// `ReadOnly`.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    /// Whether to create the file if it does not exist.
+    pub fn with_create_file(mut self, create_file: bool) -> Self {
+        self.create_file = create_file;
+        self
+    }
+    /// Whether to create or upgrade the schema. Without, the tables must
+    /// exist at the current `SCHEMA_VERSION`, as when another process manages
+    /// them.
+    pub fn with_create_schema(mut self, create_schema: bool) -> Self {
+        self.create_schema = create_schema;
+        self
+    }
+    /// Whether to give the tenant a new etag, which makes the snapshots taken
+    /// through other connections stale. Without, the etag stays valid across
+    /// reopenings.
+    pub fn with_reset_etag(mut self, reset_etag: bool) -> Self {
+        self.reset_etag = reset_etag;
+        self
+    }
+    /// Sets the journal mode, such as `WAL`, which is stored in the file.
+    pub fn with_journal_mode(mut self, mode: &str) -> Self {
+        self.journal_mode = Some(mode.to_string());
+        self
+    }
+    /// How long to wait for other connections to release their locks before
+    /// failing.
+    pub fn with_busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+    /// Runs `PRAGMA name = value` when the database is opened, before the
+    /// schema is looked at. Pragmas are run in the order they were added.
+    pub fn with_pragma(mut self, name: &str, value: &str) -> Self {
+        self.pragmas.push((name.to_string(), value.to_string()));
+        self
+    }
+    /// Names the tables and indexes with `prefix` instead of `pelo_`. Tables
+    /// with different prefixes are independent of each other, schema version
+    /// included. The prefix may only contain ASCII letters, digits and
+    /// underscores.
+    pub fn with_table_prefix(mut self, prefix: &str) -> Self {
+        self.table_prefix = prefix.to_string();
+        self
+    }
+    /// Reads and writes the data of `tenant` instead of the default tenant.
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = tenant.to_string();
+        self
+    }
+    /// Opens or creates a database encrypted with SQLCipher. A key that does
+    /// not decrypt the database fails with `WrongKey`. Without a key, opening
+    /// an encrypted database fails with `KeyRequired`.
+    #[cfg(feature = "sqlcipher")]
+    pub fn with_key(mut self, key: &str) -> Self {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    pub fn open(&self) -> Result<SQLitePersistence, Error> {
+        let valid_prefix = !self.table_prefix.is_empty()
+            && self
+                .table_prefix
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_prefix {
+            return Err(Error::generic(&format!(
+                "invalid table prefix {:?}",
+                self.table_prefix
+            )));
+        }
+
+        let mut flags =
+            rusqlite::OpenFlags::SQLITE_OPEN_URI | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        if self.read_only {
+            flags |= rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY;
+        } else {
+            flags |= rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE;
+            if self.create_file {
+                flags |= rusqlite::OpenFlags::SQLITE_OPEN_CREATE;
+            }
+        }
+        let connection = rusqlite::Connection::open_with_flags(&self.path, flags)?;
+
+        #[cfg(feature = "sqlcipher")]
+        {
+            if let Some(key) = &self.key {
+                connection.pragma_update(None, "key", key)?;
+            }
+            // The key is only checked when the first page is read, and an
+            // encrypted database cannot be read without one.
+            connection
+                .query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .map_err(|e| match e.sqlite_error_code() {
+                    Some(rusqlite::ErrorCode::NotADatabase) if self.key.is_some() => {
+                        Error::wrong_key()
+                    }
+                    Some(rusqlite::ErrorCode::NotADatabase) => Error::key_required(),
+                    _ => Error::from(e),
+                })?;
+        }
+        if let Some(timeout) = self.busy_timeout {
+            connection.busy_timeout(timeout)?;
+        }
+        if let Some(mode) = &self.journal_mode {
+            set_pragma(&connection, "journal_mode", mode)?;
+        }
+        for (name, value) in &self.pragmas {
+            set_pragma(&connection, name, value)?;
+        }
+        SQLitePersistence::with_connection(connection, self)
+    }
+}
+
+// Some pragmas return their new value, and others nothing.
+fn set_pragma(connection: &rusqlite::Connection, name: &str, value: &str) -> Result<(), Error> {
+    connection
+        .query_row(&format!("PRAGMA {} = {}", name, value), [], |_| Ok(()))
+        .optional()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{Task, User};
+    use crate::errors::ErrorCode;
+    use crate::fixtures::{TempPath, TempSQLitePersistence};
+    use crate::persistence::{Persistence, SQLitePersistenceBuilder, SCHEMA_VERSION};
+
+    use url::Url;
+    use uuid::Uuid;
+
+    use std::time::Duration;
+
+    fn new_task() -> Task {
+        Task::new(
+            Uuid::new_v4(),
+            "task",
+            Url::parse("https://localhost/").unwrap(),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_builder_table_prefix() {
+        let database = TempSQLitePersistence::new().unwrap();
+        let builder = SQLitePersistenceBuilder::new(database.path().to_path_buf());
+        let mut app = builder.clone().with_table_prefix("app_").open().unwrap();
+        assert_eq!(app.table_prefix(), "app_");
+        assert_eq!(app.schema_version().unwrap(), SCHEMA_VERSION);
+        app.upsert_task(&new_task(), "author").unwrap();
+        app.upsert_user(&User::new("voter", 1)).unwrap();
+
+        let tables: Vec<String> = {
+            let mut stmt = app
+                .connection
+                .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+                .unwrap();
+            let names = stmt.query_map([], |row| row.get(0)).unwrap();
+            names.map(|name| name.unwrap()).collect()
+        };
+        assert!(tables.contains(&"app_tasks".to_string()));
+        assert!(tables.contains(&"pelo_tasks".to_string()));
+
+        assert!(database.list_tasks().unwrap().is_empty());
+        assert_eq!(app.list_tasks().unwrap().len(), 1);
+        assert!(app.check_integrity().unwrap().is_empty());
+
+        for prefix in ["", "app;", "a b"] {
+            let result = builder.clone().with_table_prefix(prefix).open();
+            assert_eq!(result.err().unwrap().code(), ErrorCode::GenericError);
+        }
+    }
+
+    #[test]
+    fn test_builder_read_only() {
+        let mut database = TempSQLitePersistence::new().unwrap();
+        database.upsert_task(&new_task(), "author").unwrap();
+        let token = database.get_etag_token().unwrap();
+
+        let builder = SQLitePersistenceBuilder::new(database.path().to_path_buf());
+        let mut reader = builder.clone().with_read_only(true).open().unwrap();
+        assert_eq!(reader.list_tasks().unwrap().len(), 1);
+        assert_eq!(reader.get_etag_token().unwrap(), token);
+        assert_eq!(
+            reader
+                .upsert_task(&new_task(), "author")
+                .err()
+                .unwrap()
+                .code(),
+            ErrorCode::ReadOnly
+        );
+
+        // The tables with another prefix do not exist, and cannot be created.
+        let result = builder
+            .with_read_only(true)
+            .with_table_prefix("app_")
+            .open();
+        assert_eq!(result.err().unwrap().code(), ErrorCode::DatabaseError);
+    }
+
+    #[test]
+    fn test_builder_schema_and_etag() {
+        let file = TempPath::new(".db");
+        let path = file.to_path_buf();
+        let builder = SQLitePersistenceBuilder::new(path.clone());
+        let result = builder.clone().with_create_file(false).open();
+        assert_eq!(result.err().unwrap().code(), ErrorCode::DatabaseError);
+        assert!(!path.exists());
+
+        let result = builder.clone().with_create_schema(false).open();
+        assert_eq!(result.err().unwrap().code(), ErrorCode::DatabaseError);
+
+        let database = builder.open().unwrap();
+        let token = database.get_etag_token().unwrap();
+        drop(database);
+        let database = builder
+            .clone()
+            .with_create_schema(false)
+            .with_reset_etag(false)
+            .open()
+            .unwrap();
+        assert_eq!(database.get_etag_token().unwrap(), token);
+        drop(database);
+        let database = builder.open().unwrap();
+        assert_ne!(database.get_etag_token().unwrap(), token);
+    }
+
+    #[test]
+    fn test_builder_pragmas() {
+        let database = TempSQLitePersistence::new().unwrap();
+        let opened = SQLitePersistenceBuilder::new(database.path().to_path_buf())
+            .with_journal_mode("WAL")
+            .with_busy_timeout(Duration::from_millis(250))
+            .with_pragma("synchronous", "NORMAL")
+            .open()
+            .unwrap();
+        let pragma = |name: &str| -> String {
+            opened
+                .connection
+                .query_row(&format!("PRAGMA {}", name), [], |row| {
+                    row.get::<_, rusqlite::types::Value>(0)
+                })
+                .map(|value| format!("{:?}", value))
+                .unwrap()
+        };
+        assert_eq!(pragma("journal_mode"), "Text(\"wal\")");
+        assert_eq!(pragma("busy_timeout"), "Integer(250)");
+        // NORMAL is 1.
+        assert_eq!(pragma("synchronous"), "Integer(1)");
+    }
+
+    mod conformance_prefixed {
+        crate::persistence_conformance_tests!(
+            crate::persistence::SQLitePersistenceBuilder::in_memory()
+                .with_table_prefix("app_pelo_")
+                .open()
+                .unwrap()
+        );
+    }
+}
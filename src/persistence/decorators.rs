@@ -0,0 +1,754 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::data::{Rating, RatingChange, Retraction, Task, TaskVersion, User, Vote, VoteArchive};
+use crate::errors::{Error, ErrorCode};
+use crate::persistence::{Etag, Persistence, Snapshot};
+use crate::query::{TaskQuery, UserQuery};
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Caches the tasks and the latest snapshot of another backend.
+///
+/// Each read of either compares the current etag token of the backend with
+/// the one the cached value was read under, and only reads the value again
+/// when the token has changed. Since the token changes with every write,
+/// writes made through other instances or processes are seen as well.
+pub struct CachingPersistence<P> {
+    inner: P,
+    tasks: Mutex<Option<(String, Vec<Task>)>>,
+    snapshot: Option<Snapshot>,
+}
+impl<P: Persistence> CachingPersistence<P> {
+    pub fn new(inner: P) -> Self {
+        CachingPersistence {
+            inner,
+            tasks: Mutex::new(None),
+            snapshot: None,
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+impl<P: Persistence> Persistence for CachingPersistence<P> {
+    fn list_users(&self) -> Result<Vec<User>, Error> {
+        self.inner.list_users()
+    }
+
+    fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
+        self.inner.upsert_user(u)
+    }
+
+    fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        self.inner.get_user(u_id)
+    }
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        self.inner.get_num_votes_for_user_since(u_id, since)
+    }
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        self.inner.list_votes_for_user_since(u_id, since)
+    }
+
+    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        // The token is read before the tasks, so that a write in between
+        // makes the cached tasks look older than they are, not newer.
+        let token = self.inner.get_etag_token()?;
+        let mut cached = self.tasks.lock().unwrap();
+        if let Some((cached_token, tasks)) = &*cached {
+            if *cached_token == token {
+                return Ok(tasks.clone());
+            }
+        }
+        let tasks = self.inner.list_tasks()?;
+        *cached = Some((token, tasks.clone()));
+        Ok(tasks)
+    }
+
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.inner.get_task(t_id)
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        self.inner.upsert_task(t, author)
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        self.inner.list_task_versions(t_id)
+    }
+
+    fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
+        self.inner.close_task(t_id)
+    }
+
+    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+        let token = self.inner.get_etag_token()?;
+        if let Some(snapshot) = &self.snapshot {
+            if snapshot.etag().token == token {
+                return Ok(snapshot.clone());
+            }
+        }
+        let snapshot = self.inner.get_snapshot()?;
+        self.snapshot = Some(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    fn get_etag_token(&self) -> Result<String, Error> {
+        self.inner.get_etag_token()
+    }
+
+    fn add_vote_and_update_ratings(
+        &mut self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> Result<(), Error> {
+        self.inner.add_vote_and_update_ratings(etag, vote, r0, r1)
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.inner.list_votes()
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        self.inner.get_vote(v_id)
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        self.inner.list_votes_for_task(t_id)
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.inner.list_retractions()
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        self.inner.list_retractions_for_user_since(u_id, since)
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        self.inner
+            .retract_vote_and_replace_ratings(etag, retraction, replacement, ratings)
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        self.inner.list_rating_changes(t_id)
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        self.inner.get_ranking_at(time)
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        self.inner.get_vote_archive()
+    }
+
+    fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        self.inner.compact_votes(etag, archive)
+    }
+
+    fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        self.inner.query_tasks(query)
+    }
+
+    fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+        self.inner.count_tasks(query)
+    }
+
+    fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+        self.inner.query_users(query)
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        self.inner.begin_transaction()
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        self.inner.commit_transaction()
+    }
+
+    // What was read during the transaction may be gone, under a token that
+    // a later write can reuse.
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        *self.tasks.lock().unwrap() = None;
+        self.snapshot = None;
+        self.inner.rollback_transaction()
+    }
+}
+
+/// A call made through a `LoggingPersistence`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PersistenceCall {
+    method: &'static str,
+    elapsed: Duration,
+    error: Option<ErrorCode>,
+}
+impl PersistenceCall {
+    /// The name of the `Persistence` method, such as `"list_tasks"`.
+    pub fn method(&self) -> &'static str {
+        self.method
+    }
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+    /// The code of the error the call failed with, if it failed.
+    pub fn error(&self) -> Option<ErrorCode> {
+        self.error
+    }
+}
+
+type CallLog = Box<dyn Fn(&PersistenceCall) + Send + Sync>;
+
+/// Reports every call to another backend, with the time it took and its
+/// outcome, to a function given when it is created.
+pub struct LoggingPersistence<P> {
+    inner: P,
+    log: CallLog,
+}
+impl<P: Persistence> LoggingPersistence<P> {
+    /// Calls `log` after every call to `inner`.
+    pub fn new(inner: P, log: impl Fn(&PersistenceCall) + Send + Sync + 'static) -> Self {
+        LoggingPersistence {
+            inner,
+            log: Box::new(log),
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+
+// Times `call` and reports it to `log`. It is a function rather than a
+// method so that `call` can borrow the inner backend mutably.
+fn logged<T>(
+    log: &CallLog,
+    method: &'static str,
+    call: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let start = Instant::now();
+    let result = call();
+    log(&PersistenceCall {
+        method,
+        elapsed: start.elapsed(),
+        error: result.as_ref().err().map(|e| e.code()),
+    });
+    result
+}
+
+impl<P: Persistence> Persistence for LoggingPersistence<P> {
+    fn list_users(&self) -> Result<Vec<User>, Error> {
+        logged(&self.log, "list_users", || self.inner.list_users())
+    }
+
+    fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
+        logged(&self.log, "upsert_user", || self.inner.upsert_user(u))
+    }
+
+    fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        logged(&self.log, "get_user", || self.inner.get_user(u_id))
+    }
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        logged(&self.log, "get_num_votes_for_user_since", || {
+            self.inner.get_num_votes_for_user_since(u_id, since)
+        })
+    }
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        logged(&self.log, "list_votes_for_user_since", || {
+            self.inner.list_votes_for_user_since(u_id, since)
+        })
+    }
+
+    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        logged(&self.log, "list_tasks", || self.inner.list_tasks())
+    }
+
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        logged(&self.log, "get_task", || self.inner.get_task(t_id))
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        logged(&self.log, "upsert_task", || {
+            self.inner.upsert_task(t, author)
+        })
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        logged(&self.log, "list_task_versions", || {
+            self.inner.list_task_versions(t_id)
+        })
+    }
+
+    fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
+        logged(&self.log, "close_task", || self.inner.close_task(t_id))
+    }
+
+    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+        logged(&self.log, "get_snapshot", || self.inner.get_snapshot())
+    }
+
+    fn get_etag_token(&self) -> Result<String, Error> {
+        logged(&self.log, "get_etag_token", || self.inner.get_etag_token())
+    }
+
+    fn add_vote_and_update_ratings(
+        &mut self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> Result<(), Error> {
+        logged(&self.log, "add_vote_and_update_ratings", || {
+            self.inner.add_vote_and_update_ratings(etag, vote, r0, r1)
+        })
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        logged(&self.log, "list_votes", || self.inner.list_votes())
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        logged(&self.log, "get_vote", || self.inner.get_vote(v_id))
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        logged(&self.log, "list_votes_for_task", || {
+            self.inner.list_votes_for_task(t_id)
+        })
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        logged(&self.log, "list_retractions", || {
+            self.inner.list_retractions()
+        })
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        logged(&self.log, "list_retractions_for_user_since", || {
+            self.inner.list_retractions_for_user_since(u_id, since)
+        })
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        logged(&self.log, "retract_vote_and_replace_ratings", || {
+            self.inner
+                .retract_vote_and_replace_ratings(etag, retraction, replacement, ratings)
+        })
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        logged(&self.log, "list_rating_changes", || {
+            self.inner.list_rating_changes(t_id)
+        })
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        logged(&self.log, "get_ranking_at", || {
+            self.inner.get_ranking_at(time)
+        })
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        logged(&self.log, "get_vote_archive", || {
+            self.inner.get_vote_archive()
+        })
+    }
+
+    fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        logged(&self.log, "compact_votes", || {
+            self.inner.compact_votes(etag, archive)
+        })
+    }
+
+    fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        logged(&self.log, "query_tasks", || self.inner.query_tasks(query))
+    }
+
+    fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+        logged(&self.log, "count_tasks", || self.inner.count_tasks(query))
+    }
+
+    fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+        logged(&self.log, "query_users", || self.inner.query_users(query))
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        logged(&self.log, "begin_transaction", || {
+            self.inner.begin_transaction()
+        })
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        logged(&self.log, "commit_transaction", || {
+            self.inner.commit_transaction()
+        })
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        logged(&self.log, "rollback_transaction", || {
+            self.inner.rollback_transaction()
+        })
+    }
+}
+
+/// Lets reads through to another backend and rejects every write with
+/// `ErrorCode::ReadOnly`, for dry runs and for replicas that must not be
+/// changed.
+pub struct ReadOnlyPersistence<P> {
+    inner: P,
+}
+impl<P: Persistence> ReadOnlyPersistence<P> {
+    pub fn new(inner: P) -> Self {
+        ReadOnlyPersistence { inner }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+}
+impl<P: Persistence> Persistence for ReadOnlyPersistence<P> {
+    fn list_users(&self) -> Result<Vec<User>, Error> {
+        self.inner.list_users()
+    }
+
+    fn upsert_user(&mut self, _u: &User) -> Result<(), Error> {
+        Err(Error::read_only("store users"))
+    }
+
+    fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        self.inner.get_user(u_id)
+    }
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        self.inner.get_num_votes_for_user_since(u_id, since)
+    }
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        self.inner.list_votes_for_user_since(u_id, since)
+    }
+
+    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        self.inner.list_tasks()
+    }
+
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.inner.get_task(t_id)
+    }
+
+    fn upsert_task(&mut self, _t: &Task, _author: &str) -> Result<(), Error> {
+        Err(Error::read_only("store tasks"))
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        self.inner.list_task_versions(t_id)
+    }
+
+    fn close_task(&mut self, _t_id: &Uuid) -> Result<(), Error> {
+        Err(Error::read_only("close tasks"))
+    }
+
+    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+        self.inner.get_snapshot()
+    }
+
+    fn get_etag_token(&self) -> Result<String, Error> {
+        self.inner.get_etag_token()
+    }
+
+    fn add_vote_and_update_ratings(
+        &mut self,
+        _etag: &Etag,
+        _vote: &Vote,
+        _r0: &Rating,
+        _r1: &Rating,
+    ) -> Result<(), Error> {
+        Err(Error::read_only("record votes"))
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.inner.list_votes()
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        self.inner.get_vote(v_id)
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        self.inner.list_votes_for_task(t_id)
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.inner.list_retractions()
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        self.inner.list_retractions_for_user_since(u_id, since)
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        _etag: &Etag,
+        _retraction: &Retraction,
+        _replacement: Option<&Vote>,
+        _ratings: &[Rating],
+    ) -> Result<(), Error> {
+        Err(Error::read_only("retract votes"))
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        self.inner.list_rating_changes(t_id)
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        self.inner.get_ranking_at(time)
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        self.inner.get_vote_archive()
+    }
+
+    fn compact_votes(&mut self, _etag: &Etag, _archive: &VoteArchive) -> Result<(), Error> {
+        Err(Error::read_only("compact votes"))
+    }
+
+    fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        self.inner.query_tasks(query)
+    }
+
+    fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+        self.inner.count_tasks(query)
+    }
+
+    fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+        self.inner.query_users(query)
+    }
+
+    // Transactions are let through, as nothing can be written in them.
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        self.inner.begin_transaction()
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        self.inner.commit_transaction()
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        self.inner.rollback_transaction()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{Role, User};
+    use crate::elo::Outcome;
+    use crate::engine::Engine;
+    use crate::errors::ErrorCode;
+    use crate::fixtures::Fixture;
+    use crate::persistence::decorators::{
+        CachingPersistence, LoggingPersistence, PersistenceCall, ReadOnlyPersistence,
+    };
+    use crate::persistence::{InMemory, Persistence};
+
+    use std::sync::{Arc, Mutex};
+
+    mod conformance_caching {
+        crate::persistence_conformance_tests!(
+            crate::persistence::decorators::CachingPersistence::new(
+                crate::persistence::InMemory::new()
+            )
+        );
+    }
+
+    mod conformance_logging {
+        crate::persistence_conformance_tests!(
+            crate::persistence::decorators::LoggingPersistence::new(
+                crate::persistence::InMemory::new(),
+                |_| {}
+            )
+        );
+    }
+
+    fn fixture() -> Fixture {
+        Fixture::new()
+            .with_user(User::with_role("test_admin", -1, Role::Admin))
+            .with_new_task("task zero")
+            .with_new_task("task one")
+            .with_vote("test_admin", 0, 1, Outcome::P0Win)
+    }
+
+    // A backend that records the names of the methods called on it.
+    fn logged(inner: InMemory) -> (LoggingPersistence<InMemory>, Arc<Mutex<Vec<String>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let log = calls.clone();
+        let persistence = LoggingPersistence::new(inner, move |call: &PersistenceCall| {
+            log.lock().unwrap().push(call.method().to_string())
+        });
+        (persistence, calls)
+    }
+
+    #[test]
+    fn test_caching_reads_again_after_writes() {
+        let (inner, calls) = logged(fixture().in_memory().unwrap());
+        let mut database = CachingPersistence::new(inner);
+        let count = |method: &str| {
+            calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|m| *m == method)
+                .count()
+        };
+
+        assert_eq!(database.list_tasks().unwrap().len(), 2);
+        assert_eq!(database.list_tasks().unwrap().len(), 2);
+        assert_eq!(count("list_tasks"), 1);
+        let snapshot = database.get_snapshot().unwrap();
+        assert_eq!(
+            database.get_snapshot().unwrap().etag().token,
+            snapshot.etag().token
+        );
+        assert_eq!(count("get_snapshot"), 1);
+
+        let engine = Engine::new();
+        let tasks = database.list_tasks().unwrap();
+        engine
+            .answer_question(
+                &mut database,
+                "test_admin",
+                &tasks[0],
+                &tasks[1],
+                Outcome::Draw,
+                None,
+            )
+            .unwrap();
+        assert_ne!(
+            database.get_snapshot().unwrap().etag().token,
+            snapshot.etag().token
+        );
+        engine
+            .close_task(&mut database, "test_admin", tasks[0].id())
+            .unwrap();
+        assert!(database.list_tasks().unwrap().iter().any(|t| t.closed()));
+        assert_eq!(count("list_tasks"), 2);
+    }
+
+    #[test]
+    fn test_logging_records_calls() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let log = calls.clone();
+        let mut database = LoggingPersistence::new(InMemory::new(), move |call| {
+            log.lock().unwrap().push(call.clone())
+        });
+        database.upsert_user(&User::new("test_user", 1)).unwrap();
+        assert!(database.get_user("nobody").is_err());
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].method(), "upsert_user");
+        assert_eq!(calls[0].error(), None);
+        assert_eq!(calls[1].method(), "get_user");
+        assert_eq!(calls[1].error(), Some(ErrorCode::UserNotFound));
+    }
+
+    #[test]
+    fn test_read_only_rejects_writes() {
+        let fixture = fixture();
+        let mut database = ReadOnlyPersistence::new(fixture.in_memory().unwrap());
+        let engine = Engine::new();
+        let tasks = fixture.tasks();
+
+        assert_eq!(database.list_votes().unwrap().len(), 1);
+        assert_eq!(
+            engine
+                .answer_question(
+                    &mut database,
+                    "test_admin",
+                    &tasks[0],
+                    &tasks[1],
+                    Outcome::Draw,
+                    None,
+                )
+                .err()
+                .unwrap()
+                .code(),
+            ErrorCode::ReadOnly
+        );
+        assert_eq!(
+            database
+                .upsert_user(&User::new("test_user", 1))
+                .err()
+                .unwrap()
+                .code(),
+            ErrorCode::ReadOnly
+        );
+        assert_eq!(
+            database.close_task(tasks[0].id()).err().unwrap().code(),
+            ErrorCode::ReadOnly
+        );
+        assert_eq!(database.into_inner().list_votes().unwrap().len(), 1);
+    }
+}
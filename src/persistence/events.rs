@@ -0,0 +1,680 @@
+use chrono::{DateTime, Utc};
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::data::{Rating, RatingChange, Retraction, Task, TaskVersion, User, Vote};
+use crate::errors::Error;
+use crate::persistence::json::{write_atomically, JsonDocument};
+use crate::persistence::{Etag, InMemoryInner, Persistence, Snapshot};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// A change recorded in the log of an `EventLogPersistence`. Ratings are
+/// recorded together with the votes and retractions that changed them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    UserUpserted {
+        user: User,
+    },
+    TaskUpserted {
+        task: Task,
+        author: String,
+    },
+    TaskClosed {
+        task: Uuid,
+    },
+    VoteCast {
+        vote: Vote,
+        r0: Rating,
+        r1: Rating,
+    },
+    VoteRetracted {
+        retraction: Retraction,
+        replacement: Option<Vote>,
+        ratings: Vec<Rating>,
+    },
+}
+
+/// An event together with its position in the log, starting from 1, and the
+/// time at which it was recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    position: u64,
+    time: DateTime<Utc>,
+    event: Event,
+}
+impl RecordedEvent {
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+    pub fn time(&self) -> &DateTime<Utc> {
+        &self.time
+    }
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+}
+
+// The state of the projection after the event at `position`.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    position: u64,
+    state: JsonDocument,
+}
+
+/// Stores an append-only log of events, one JSON object per line, as the
+/// only source of truth. Users, tasks, votes and ratings are projections of
+/// the log, rebuilt in memory when the log is opened.
+///
+/// The events of a transaction are kept in memory and appended together when
+/// it is committed.
+///
+/// The etag is the position of the last event. Every few events the
+/// projection is saved to a checkpoint file next to the log, so that opening
+/// the log only needs to replay the events recorded after it. The log is
+/// locked for as long as it is open.
+pub struct EventLogPersistence {
+    path: PathBuf,
+    log: File,
+    position: u64,
+    projection: InMemoryInner,
+    checkpoint_interval: u64,
+    checkpoint_position: u64,
+    transaction: Option<PendingEvents>,
+}
+
+// The events of the transaction in progress, and the state to go back to if
+// it is rolled back.
+struct PendingEvents {
+    events: Vec<RecordedEvent>,
+    projection: InMemoryInner,
+    position: u64,
+}
+
+impl EventLogPersistence {
+    /// Opens the log at `path`, creating an empty one if it does not exist,
+    /// and rebuilds the projections from the latest checkpoint.
+    pub fn new(path: PathBuf) -> Result<Self, Error> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+        if !FileExt::try_lock_exclusive(&log)? {
+            return Err(Error::db_error(&format!(
+                "event log {} is already in use",
+                path.display()
+            )));
+        }
+        let checkpoint = read_checkpoint(&checkpoint_path(&path));
+        let checkpoint_position = checkpoint.as_ref().map(|c| c.position).unwrap_or(0);
+        let (projection, position) = replay(&path, checkpoint, u64::MAX)?;
+        Ok(EventLogPersistence {
+            path,
+            log,
+            position,
+            projection,
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            checkpoint_position,
+            transaction: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// The position of the last event in the log, 0 if the log is empty.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+    pub fn checkpoint_interval(&self) -> u64 {
+        self.checkpoint_interval
+    }
+    /// Sets how many events are recorded between two checkpoints.
+    pub fn set_checkpoint_interval(&mut self, interval: u64) {
+        self.checkpoint_interval = interval.max(1);
+    }
+
+    /// Saves the current projection, so that the next time the log is
+    /// opened only the later events are replayed. Fails during a
+    /// transaction, whose events are not in the log yet.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        if self.transaction.is_some() {
+            return Err(Error::transaction_in_progress());
+        }
+        let checkpoint = Checkpoint {
+            position: self.position,
+            state: self.projection.clone().into(),
+        };
+        write_atomically(&checkpoint_path(&self.path), &checkpoint)?;
+        self.checkpoint_position = self.position;
+        Ok(())
+    }
+
+    /// Reads the whole log, oldest event first.
+    pub fn list_events(&self) -> Result<Vec<RecordedEvent>, Error> {
+        let mut events = Vec::new();
+        for_each_event(&self.path, 0, |e| {
+            events.push(e);
+            Ok(())
+        })?;
+        Ok(events)
+    }
+
+    /// Rebuilds the ranking as it was right after the event at `position`.
+    pub fn snapshot_at(&self, position: u64) -> Result<Snapshot, Error> {
+        if position > self.position {
+            return Err(Error::generic(&format!(
+                "position {} is beyond the end of the log",
+                position
+            )));
+        }
+        let (mut projection, _) = replay(&self.path, None, position)?;
+        Ok(Snapshot {
+            ranking: projection.get_snapshot()?.ranking,
+            etag: position_etag(position),
+        })
+    }
+
+    // Checks the etag, if any, applies the event to the projection and then
+    // appends it to the log.
+    fn record(&mut self, etag: Option<&Etag>, event: Event) -> Result<(), Error> {
+        if etag.is_some_and(|e| e.token != position_etag(self.position).token) {
+            return Err(Error::retry_transaction());
+        }
+        let recorded = RecordedEvent {
+            position: self.position + 1,
+            time: SystemTime::now().into(),
+            event,
+        };
+        apply(&mut self.projection, &recorded)?;
+        self.position = recorded.position;
+        match &mut self.transaction {
+            Some(pending) => {
+                pending.events.push(recorded);
+                Ok(())
+            }
+            None => self.append(&[recorded]),
+        }
+    }
+
+    // Appends events that are already applied to the projection.
+    fn append(&mut self, events: &[RecordedEvent]) -> Result<(), Error> {
+        let mut lines = String::new();
+        for recorded in events {
+            lines.push_str(
+                &serde_json::to_string(recorded).map_err(|e| Error::db_error(&e.to_string()))?,
+            );
+            lines.push('\n');
+        }
+        if let Err(e) = self
+            .log
+            .write_all(lines.as_bytes())
+            .and_then(|_| self.log.sync_data())
+        {
+            // The projection is ahead of the log, start again from the log.
+            let checkpoint = read_checkpoint(&checkpoint_path(&self.path));
+            (self.projection, self.position) = replay(&self.path, checkpoint, u64::MAX)?;
+            return Err(e.into());
+        }
+        // The events are in the log already, so a checkpoint that cannot be
+        // saved must not fail the write. The next append tries again.
+        if self.position - self.checkpoint_position >= self.checkpoint_interval {
+            let _ = self.checkpoint();
+        }
+        Ok(())
+    }
+}
+
+impl Persistence for EventLogPersistence {
+    fn list_users(&self) -> Result<Vec<User>, Error> {
+        self.projection.list_users()
+    }
+
+    fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
+        self.record(None, Event::UserUpserted { user: u.clone() })
+    }
+
+    fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        self.projection.get_user(u_id)
+    }
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        self.projection.get_num_votes_for_user_since(u_id, since)
+    }
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        self.projection.list_votes_for_user_since(u_id, since)
+    }
+
+    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        self.projection.list_tasks()
+    }
+
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.projection.get_task(t_id)
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        self.record(
+            None,
+            Event::TaskUpserted {
+                task: t.clone(),
+                author: author.to_string(),
+            },
+        )
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        self.projection.list_task_versions(t_id)
+    }
+
+    fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
+        self.record(None, Event::TaskClosed { task: *t_id })
+    }
+
+    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+        let snapshot = self.projection.get_snapshot()?;
+        Ok(Snapshot {
+            ranking: snapshot.ranking,
+            etag: Etag::with_revisions(
+                &position_etag(self.position).token,
+                snapshot.etag.revisions,
+            ),
+        })
+    }
+
+    fn get_etag_token(&self) -> Result<String, Error> {
+        Ok(position_etag(self.position).token)
+    }
+
+    fn add_vote_and_update_ratings(
+        &mut self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> Result<(), Error> {
+        etag.check_revisions([r0, r1], |t| Ok(self.projection.revision(t)))?;
+        self.record(
+            None,
+            Event::VoteCast {
+                vote: vote.clone(),
+                r0: r0.clone(),
+                r1: r1.clone(),
+            },
+        )
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.projection.list_votes()
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        self.projection.get_vote(v_id)
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        self.projection.list_votes_for_task(t_id)
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.projection.list_retractions()
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        self.projection.list_retractions_for_user_since(u_id, since)
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        self.record(
+            Some(etag),
+            Event::VoteRetracted {
+                retraction: retraction.clone(),
+                replacement: replacement.cloned(),
+                ratings: ratings.to_vec(),
+            },
+        )
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        self.projection.list_rating_changes(t_id)
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        self.projection.get_ranking_at(time)
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        if self.transaction.is_some() {
+            return Err(Error::transaction_in_progress());
+        }
+        self.transaction = Some(PendingEvents {
+            events: Vec::new(),
+            projection: self.projection.clone(),
+            position: self.position,
+        });
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        let pending = self.transaction.take().ok_or_else(Error::no_transaction)?;
+        self.append(&pending.events)
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        let pending = self.transaction.take().ok_or_else(Error::no_transaction)?;
+        self.projection = pending.projection;
+        self.position = pending.position;
+        Ok(())
+    }
+}
+
+fn position_etag(position: u64) -> Etag {
+    Etag::new(&position.to_string())
+}
+
+fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut checkpoint = path.to_path_buf().into_os_string();
+    checkpoint.push(".checkpoint");
+    checkpoint.into()
+}
+
+// A checkpoint only saves time, so one that cannot be read is ignored.
+fn read_checkpoint(path: &Path) -> Option<Checkpoint> {
+    let file = File::open(path).ok()?;
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+// Applies an event to a projection. The projection checks that the event is
+// valid, but not the etag, which is the business of the log.
+fn apply(projection: &mut InMemoryInner, recorded: &RecordedEvent) -> Result<(), Error> {
+    match &recorded.event {
+        Event::UserUpserted { user } => projection.upsert_user(user),
+        Event::TaskUpserted { task, author } => {
+            projection.upsert_task_at(task, author, recorded.time)
+        }
+        Event::TaskClosed { task } => projection.close_task(task),
+        Event::VoteCast { vote, r0, r1 } => {
+            projection.add_vote_at(&projection.etag(), vote, r0, r1, recorded.time)
+        }
+        Event::VoteRetracted {
+            retraction,
+            replacement,
+            ratings,
+        } => projection.retract_vote_at(
+            &projection.etag(),
+            retraction,
+            replacement.as_ref(),
+            ratings,
+            recorded.time,
+        ),
+    }
+}
+
+// Rebuilds the projection up to and including the event at `until`,
+// starting from the checkpoint if there is a usable one. Returns the
+// projection and the position of the last event applied.
+fn replay(
+    path: &Path,
+    checkpoint: Option<Checkpoint>,
+    until: u64,
+) -> Result<(InMemoryInner, u64), Error> {
+    let (mut projection, start) = match checkpoint {
+        Some(c) if c.position <= until => (c.state.into(), c.position),
+        _ => (InMemoryInner::new(), 0),
+    };
+    let mut position = start;
+    let length = for_each_event(path, start, |recorded| {
+        if recorded.position > until {
+            return Ok(());
+        }
+        apply(&mut projection, &recorded)?;
+        position = recorded.position;
+        Ok(())
+    })?;
+    if length < start {
+        return Err(Error::db_error("the checkpoint is ahead of the event log"));
+    }
+    Ok((projection, position))
+}
+
+// Calls `f` for each event after position `skip`, checking that the
+// positions recorded in the log match the line numbers. Returns the length of
+// the log.
+fn for_each_event(
+    path: &Path,
+    skip: u64,
+    mut f: impl FnMut(RecordedEvent) -> Result<(), Error>,
+) -> Result<u64, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut length = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index as u64 + 1;
+        let line = line?;
+        length = line_number;
+        if line_number <= skip {
+            continue;
+        }
+        let corrupt = |reason: &str| {
+            Error::corrupt_data(&path.display().to_string(), line_number as i64, reason)
+        };
+        let recorded: RecordedEvent =
+            serde_json::from_str(&line).map_err(|e| corrupt(&e.to_string()))?;
+        if recorded.position != line_number {
+            return Err(corrupt(&format!(
+                "event has position {}",
+                recorded.position
+            )));
+        }
+        f(recorded)?;
+    }
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{Rating, Task, User, Vote};
+    use crate::elo::Outcome;
+    use crate::errors::ErrorCode;
+    use crate::persistence::events::{checkpoint_path, Event, EventLogPersistence};
+    use crate::persistence::Persistence;
+
+    use crate::fixtures::TempPath;
+
+    use std::path::PathBuf;
+    use url::Url;
+    use uuid::Uuid;
+
+    fn test_path() -> TempPath {
+        TempPath::new(".jsonl")
+    }
+
+    crate::persistence_conformance_tests!(
+        EventLogPersistence::new(test_path().to_path_buf()).unwrap()
+    );
+
+    fn setup(database: &mut EventLogPersistence) -> (Task, Task) {
+        database.upsert_user(&User::new("test_user", -1)).unwrap();
+        let t0 = Task::new(
+            Uuid::new_v4(),
+            "task zero",
+            Url::parse("https://localhost/0").unwrap(),
+            false,
+        );
+        let t1 = Task::new(
+            Uuid::new_v4(),
+            "task one",
+            Url::parse("https://localhost/1").unwrap(),
+            false,
+        );
+        database.upsert_task(&t0, "test_user").unwrap();
+        database.upsert_task(&t1, "test_user").unwrap();
+        (t0, t1)
+    }
+
+    fn vote(database: &mut EventLogPersistence, t0: &Task, t1: &Task, elo0: f32, elo1: f32) {
+        let etag = database.get_snapshot().unwrap().etag().clone();
+        database
+            .add_vote_and_update_ratings(
+                &etag,
+                &Vote::new(
+                    "test_user",
+                    std::time::SystemTime::now().into(),
+                    *t0.id(),
+                    *t1.id(),
+                    Outcome::P0Win,
+                ),
+                &Rating::with_elo(*t0.id(), elo0),
+                &Rating::with_elo(*t1.id(), elo1),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_events_replay_and_time_travel() {
+        let path = test_path();
+        let mut database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        let (t0, t1) = setup(&mut database);
+        assert_eq!(database.get_snapshot().unwrap().etag().token, "3");
+        vote(&mut database, &t0, &t1, 1216.0, 1184.0);
+        vote(&mut database, &t0, &t1, 1230.0, 1170.0);
+        database.close_task(t1.id()).unwrap();
+        assert_eq!(database.position(), 6);
+
+        // A failed write does not reach the log.
+        let result = database.close_task(&Uuid::new_v4());
+        assert_eq!(result.err().unwrap().code(), ErrorCode::TaskNotFound);
+        let events = database.list_events().unwrap();
+        assert_eq!(events.len(), 6);
+        assert!(matches!(events[3].event(), Event::VoteCast { .. }));
+        assert_eq!(events[5].position(), 6);
+
+        let past = database.snapshot_at(4).unwrap();
+        assert_eq!(past.etag().token, "4");
+        let r0 = past.ranking().iter().find(|r| r.task() == t0.id()).unwrap();
+        assert!((r0.elo() - 1216.0).abs() < 0.000001);
+        assert!(database.snapshot_at(7).is_err());
+
+        // The projections are rebuilt from the log.
+        drop(database);
+        let mut database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.position(), 6);
+        assert_eq!(database.list_votes().unwrap().len(), 2);
+        let closed = database
+            .list_tasks()
+            .unwrap()
+            .into_iter()
+            .find(|t| t.id() == t1.id())
+            .unwrap();
+        assert!(closed.closed());
+        let ranking = database.get_snapshot().unwrap().ranking().clone();
+        assert_eq!(ranking[1].task(), t0.id());
+        assert!((ranking[1].elo() - 1230.0).abs() < 0.000001);
+    }
+
+    #[test]
+    fn test_events_checkpoints() {
+        let path = test_path();
+        let mut database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        database.set_checkpoint_interval(4);
+        let (t0, t1) = setup(&mut database);
+        assert!(!checkpoint_path(path.path()).exists());
+        vote(&mut database, &t0, &t1, 1216.0, 1184.0);
+        assert!(checkpoint_path(path.path()).exists());
+        vote(&mut database, &t0, &t1, 1230.0, 1170.0);
+
+        // The log is locked while it is open.
+        assert!(EventLogPersistence::new(path.to_path_buf()).is_err());
+
+        let versions = database.list_task_versions(t0.id()).unwrap();
+        drop(database);
+        let database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.position(), 5);
+        assert_eq!(database.list_votes().unwrap().len(), 2);
+        let reloaded = database.list_task_versions(t0.id()).unwrap();
+        assert_eq!(reloaded[0].time(), versions[0].time());
+
+        // A checkpoint ahead of the log is rejected.
+        drop(database);
+        std::fs::write(path.path(), "").unwrap();
+        let result = EventLogPersistence::new(path.to_path_buf());
+        assert_eq!(result.err().unwrap().code(), ErrorCode::DatabaseError);
+    }
+
+    #[test]
+    fn test_events_checkpoint_failure() {
+        let path = test_path();
+        let mut database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        database.set_checkpoint_interval(1);
+        // A directory in the way of the file the checkpoint is written to.
+        let blocked = PathBuf::from(format!("{}.tmp", checkpoint_path(path.path()).display()));
+        std::fs::create_dir(&blocked).unwrap();
+
+        let (t0, t1) = setup(&mut database);
+        vote(&mut database, &t0, &t1, 1216.0, 1184.0);
+        assert!(!checkpoint_path(path.path()).exists());
+        assert_eq!(database.list_events().unwrap().len(), 4);
+
+        std::fs::remove_dir(&blocked).unwrap();
+        database.close_task(t1.id()).unwrap();
+        assert!(checkpoint_path(path.path()).exists());
+        drop(database);
+        let database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.position(), 5);
+    }
+
+    #[test]
+    fn test_events_transactions() {
+        let path = test_path();
+        let mut database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        let (t0, t1) = setup(&mut database);
+
+        database.begin_transaction().unwrap();
+        vote(&mut database, &t0, &t1, 1216.0, 1184.0);
+        database.close_task(t1.id()).unwrap();
+        assert_eq!(database.position(), 5);
+        assert!(database.checkpoint().is_err());
+        // Nothing is written before the commit.
+        assert_eq!(database.list_events().unwrap().len(), 3);
+        database.rollback_transaction().unwrap();
+        assert_eq!(database.position(), 3);
+        assert!(database.list_votes().unwrap().is_empty());
+
+        database
+            .transaction(|database| {
+                vote(database, &t0, &t1, 1216.0, 1184.0);
+                database.close_task(t1.id())
+            })
+            .unwrap();
+        drop(database);
+        let database = EventLogPersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.position(), 5);
+        assert_eq!(database.list_events().unwrap().len(), 5);
+        assert_eq!(database.list_votes().unwrap().len(), 1);
+    }
+}
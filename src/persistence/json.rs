@@ -0,0 +1,502 @@
+use chrono::{DateTime, Utc};
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::data::{Rating, RatingChange, Retraction, Task, TaskVersion, User, Vote, VoteArchive};
+use crate::errors::Error;
+use crate::persistence::{Etag, InMemoryInner, Persistence, Snapshot};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+// The content of the file. Collections are kept sorted so that the file
+// changes as little as possible from one write to the next.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct JsonDocument {
+    users: Vec<User>,
+    tasks: Vec<Task>,
+    task_versions: Vec<TaskVersion>,
+    ratings: Vec<Rating>,
+    votes: Vec<Vote>,
+    retractions: Vec<Retraction>,
+    #[serde(default)]
+    rating_changes: Vec<RatingChange>,
+    #[serde(default)]
+    vote_archive: VoteArchive,
+    #[serde(default)]
+    generation: u64,
+}
+impl From<JsonDocument> for InMemoryInner {
+    fn from(doc: JsonDocument) -> Self {
+        let mut inner = InMemoryInner::new();
+        inner.users = doc
+            .users
+            .into_iter()
+            .map(|u| (u.id().to_string(), u))
+            .collect();
+        inner.tasks = doc.tasks.into_iter().map(|t| (*t.id(), t)).collect();
+        inner.current_ranking = doc
+            .ratings
+            .into_iter()
+            .map(|r| (*r.task(), r.elo()))
+            .collect();
+        inner.task_versions = doc.task_versions;
+        inner.votes = doc.votes;
+        inner.retractions = doc.retractions;
+        inner.rating_changes = doc.rating_changes;
+        inner.archive = doc.vote_archive;
+        inner.generation = doc.generation;
+        inner
+    }
+}
+impl From<InMemoryInner> for JsonDocument {
+    fn from(inner: InMemoryInner) -> Self {
+        let mut users: Vec<User> = inner.users.into_values().collect();
+        users.sort_by(|a, b| a.id().cmp(b.id()));
+        let mut tasks: Vec<Task> = inner.tasks.into_values().collect();
+        tasks.sort_by_key(|t| *t.id());
+        let mut ratings: Vec<Rating> = inner
+            .current_ranking
+            .into_iter()
+            .map(|(t, elo)| Rating::with_elo(t, elo))
+            .collect();
+        ratings.sort_by_key(|r| *r.task());
+        JsonDocument {
+            users,
+            tasks,
+            task_versions: inner.task_versions,
+            ratings,
+            votes: inner.votes,
+            retractions: inner.retractions,
+            rating_changes: inner.rating_changes,
+            vote_archive: inner.archive,
+            generation: inner.generation,
+        }
+    }
+}
+
+/// Stores everything in a single human-readable JSON file, meant for small
+/// teams that want to keep their data in a git repository.
+///
+/// Every operation reads the file, and every change rewrites it atomically
+/// by writing a temporary file next to it and renaming it over the original.
+/// A lock on a separate `.lock` file serializes writers across processes, so
+/// that a vote based on a stale etag is always rejected.
+///
+/// A transaction holds that lock from beginning to end, and its writes are
+/// only saved to the file when it is committed.
+pub struct JsonFilePersistence {
+    path: PathBuf,
+    transaction: Mutex<Option<PendingTransaction>>,
+}
+
+// The lock and the content of the file while a transaction is in progress.
+struct PendingTransaction {
+    _lock: File,
+    inner: InMemoryInner,
+}
+impl JsonFilePersistence {
+    /// Opens the file at `path`, creating an empty one if it does not exist.
+    pub fn new(path: PathBuf) -> Result<Self, Error> {
+        let persistence = JsonFilePersistence {
+            path,
+            transaction: Mutex::new(None),
+        };
+        let _lock = persistence.lock(true)?;
+        if !persistence.path.exists() {
+            persistence.save(JsonDocument::default())?;
+        }
+        Ok(persistence)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&mut InMemoryInner) -> Result<T, Error>) -> Result<T, Error> {
+        if let Some(pending) = self.pending()?.as_mut() {
+            return f(&mut pending.inner);
+        }
+        let _lock = self.lock(false)?;
+        f(&mut self.load()?.into())
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut InMemoryInner) -> Result<T, Error>) -> Result<T, Error> {
+        if let Some(pending) = self.pending()?.as_mut() {
+            return f(&mut pending.inner);
+        }
+        let _lock = self.lock(true)?;
+        let mut inner: InMemoryInner = self.load()?.into();
+        let result = f(&mut inner)?;
+        self.save(inner.into())?;
+        Ok(result)
+    }
+
+    fn pending(&self) -> Result<MutexGuard<'_, Option<PendingTransaction>>, Error> {
+        self.transaction
+            .lock()
+            .map_err(|_| Error::generic("the transaction state is poisoned"))
+    }
+
+    // The lock is released when the returned file is dropped.
+    fn lock(&self, exclusive: bool) -> Result<File, Error> {
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)?;
+        if exclusive {
+            FileExt::lock_exclusive(&file)?;
+        } else {
+            FileExt::lock_shared(&file)?;
+        }
+        Ok(file)
+    }
+
+    fn load(&self) -> Result<JsonDocument, Error> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        serde_json::from_reader(reader).map_err(|e| {
+            Error::corrupt_data(
+                &self.path.display().to_string(),
+                e.line() as i64,
+                &e.to_string(),
+            )
+        })
+    }
+
+    fn save(&self, doc: JsonDocument) -> Result<(), Error> {
+        write_atomically(&self.path, &doc)
+    }
+}
+
+// Writes a value as pretty-printed JSON to a temporary file next to `path`,
+// then renames it over `path`.
+pub(super) fn write_atomically<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let file = File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, value)
+        .map_err(|e| Error::db_error(&e.to_string()))?;
+    writer.write_all(b"\n")?;
+    writer
+        .into_inner()
+        .map_err(|e| Error::db_error(&e.to_string()))?
+        .sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+impl Persistence for JsonFilePersistence {
+    fn list_users(&self) -> Result<Vec<User>, Error> {
+        self.read(|inner| inner.list_users())
+    }
+
+    fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
+        self.write(|inner| inner.upsert_user(u))
+    }
+
+    fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        self.read(|inner| inner.get_user(u_id))
+    }
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        self.read(|inner| inner.get_num_votes_for_user_since(u_id, since))
+    }
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        self.read(|inner| inner.list_votes_for_user_since(u_id, since))
+    }
+
+    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        self.read(|inner| inner.list_tasks())
+    }
+
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.read(|inner| inner.get_task(t_id))
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        self.write(|inner| inner.upsert_task(t, author))
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        self.read(|inner| inner.list_task_versions(t_id))
+    }
+
+    fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
+        self.write(|inner| inner.close_task(t_id))
+    }
+
+    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+        self.read(|inner| inner.get_snapshot())
+    }
+
+    fn get_etag_token(&self) -> Result<String, Error> {
+        self.read(|inner| inner.get_etag_token())
+    }
+
+    fn add_vote_and_update_ratings(
+        &mut self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> Result<(), Error> {
+        self.write(|inner| inner.add_vote_and_update_ratings(etag, vote, r0, r1))
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.read(|inner| inner.list_votes())
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        self.read(|inner| inner.get_vote(v_id))
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        self.read(|inner| inner.list_votes_for_task(t_id))
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.read(|inner| inner.list_retractions())
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        self.read(|inner| inner.list_retractions_for_user_since(u_id, since))
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        self.write(|inner| {
+            inner.retract_vote_and_replace_ratings(etag, retraction, replacement, ratings)
+        })
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        self.read(|inner| inner.list_rating_changes(t_id))
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        self.read(|inner| inner.get_ranking_at(time))
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        self.read(|inner| inner.get_vote_archive())
+    }
+
+    fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        self.write(|inner| inner.compact_votes(etag, archive))
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        let mut pending = self.pending()?;
+        if pending.is_some() {
+            return Err(Error::transaction_in_progress());
+        }
+        let lock = self.lock(true)?;
+        *pending = Some(PendingTransaction {
+            _lock: lock,
+            inner: self.load()?.into(),
+        });
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        let pending = self.pending()?.take().ok_or_else(Error::no_transaction)?;
+        self.save(pending.inner.into())
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        self.pending()?.take().ok_or_else(Error::no_transaction)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{Rating, Retraction, Task, User, Vote};
+    use crate::elo::Outcome;
+    use crate::errors::ErrorCode;
+    use crate::fixtures::TempPath;
+    use crate::persistence::json::JsonFilePersistence;
+    use crate::persistence::Persistence;
+
+    use std::path::PathBuf;
+    use url::Url;
+    use uuid::Uuid;
+
+    fn test_path() -> TempPath {
+        TempPath::new(".json")
+    }
+
+    crate::persistence_conformance_tests!(
+        JsonFilePersistence::new(test_path().to_path_buf()).unwrap()
+    );
+
+    #[test]
+    fn test_json_shared_file() {
+        let path = test_path();
+        let mut first = JsonFilePersistence::new(path.to_path_buf()).unwrap();
+        let mut second = JsonFilePersistence::new(path.to_path_buf()).unwrap();
+        first.upsert_user(&User::new("test_user", -1)).unwrap();
+        let t0 = Task::new(
+            Uuid::new_v4(),
+            "task zero",
+            Url::parse("https://localhost/0").unwrap(),
+            false,
+        );
+        let t1 = Task::new(
+            Uuid::new_v4(),
+            "task one",
+            Url::parse("https://localhost/1").unwrap(),
+            false,
+        );
+        first.upsert_task(&t0, "test_user").unwrap();
+        second.upsert_task(&t1, "test_user").unwrap();
+        assert_eq!(first.list_tasks().unwrap().len(), 2);
+
+        // Both instances start from the same etag, only the first vote wins.
+        let etag = first.get_snapshot().unwrap().etag().clone();
+        assert_eq!(second.get_snapshot().unwrap().etag().token, etag.token);
+        let vote = |outcome| {
+            Vote::new(
+                "test_user",
+                std::time::SystemTime::now().into(),
+                *t0.id(),
+                *t1.id(),
+                outcome,
+            )
+        };
+        first
+            .add_vote_and_update_ratings(
+                &etag,
+                &vote(Outcome::P0Win),
+                &Rating::with_elo(*t0.id(), 1216.0),
+                &Rating::with_elo(*t1.id(), 1184.0),
+            )
+            .unwrap();
+        let result = second.add_vote_and_update_ratings(
+            &etag,
+            &vote(Outcome::P1Win),
+            &Rating::with_elo(*t0.id(), 1184.0),
+            &Rating::with_elo(*t1.id(), 1216.0),
+        );
+        assert_eq!(
+            result.err().unwrap().code(),
+            ErrorCode::OptimisticConcurrencyRetryTransaction
+        );
+        assert_eq!(second.list_votes().unwrap().len(), 1);
+
+        // The file is plain JSON, and reopening it gives the same data.
+        let content = std::fs::read_to_string(path.path()).unwrap();
+        assert!(content.contains("\"summary\": \"task zero\""));
+        let mut reopened = JsonFilePersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(reopened.list_users().unwrap().len(), 1);
+        assert_eq!(
+            reopened.get_snapshot().unwrap().ranking()[1].task(),
+            t0.id()
+        );
+        assert!(!PathBuf::from(format!("{}.tmp", path.path().display())).exists());
+    }
+
+    #[test]
+    fn test_json_legacy_vote_ids() {
+        let path = test_path();
+        let mut database = JsonFilePersistence::new(path.to_path_buf()).unwrap();
+        database.upsert_user(&User::new("test_user", -1)).unwrap();
+        let t0 = Task::new(
+            Uuid::new_v4(),
+            "task zero",
+            Url::parse("https://localhost/0").unwrap(),
+            false,
+        );
+        let t1 = Task::new(
+            Uuid::new_v4(),
+            "task one",
+            Url::parse("https://localhost/1").unwrap(),
+            false,
+        );
+        database.upsert_task(&t0, "test_user").unwrap();
+        database.upsert_task(&t1, "test_user").unwrap();
+        let etag = database.get_snapshot().unwrap().etag().clone();
+        let vote = Vote::new(
+            "test_user",
+            std::time::SystemTime::now().into(),
+            *t0.id(),
+            *t1.id(),
+            Outcome::P0Win,
+        );
+        database
+            .add_vote_and_update_ratings(
+                &etag,
+                &vote,
+                &Rating::with_elo(*t0.id(), 1216.0),
+                &Rating::with_elo(*t1.id(), 1184.0),
+            )
+            .unwrap();
+
+        // Remove the id, as in files written before votes had one.
+        let content = std::fs::read_to_string(path.path()).unwrap();
+        let mut document: serde_json::Value = serde_json::from_str(&content).unwrap();
+        for vote in document["votes"].as_array_mut().unwrap() {
+            vote.as_object_mut().unwrap().remove("id");
+        }
+        std::fs::write(path.path(), document.to_string()).unwrap();
+
+        // Every read of the file gives the vote the same id.
+        let first = JsonFilePersistence::new(path.to_path_buf()).unwrap();
+        let mut second = JsonFilePersistence::new(path.to_path_buf()).unwrap();
+        let id = *first.list_votes().unwrap()[0].id();
+        assert_ne!(id, *vote.id());
+        assert_eq!(*second.list_votes().unwrap()[0].id(), id);
+        assert_eq!(*first.list_votes().unwrap()[0].id(), id);
+
+        // So the vote can be retracted.
+        let etag = second.get_snapshot().unwrap().etag().clone();
+        let retraction = Retraction::new(id, "test_user", vote.time().to_owned(), None);
+        second
+            .retract_vote_and_replace_ratings(
+                &etag,
+                &retraction,
+                None,
+                &[
+                    Rating::with_elo(*t0.id(), 1200.0),
+                    Rating::with_elo(*t1.id(), 1200.0),
+                ],
+            )
+            .unwrap();
+        assert_eq!(*first.list_retractions().unwrap()[0].vote(), id);
+    }
+
+    #[test]
+    fn test_json_corrupt_file() {
+        let path = test_path();
+        std::fs::write(path.path(), "{ \"users\": [ oops").unwrap();
+        let database = JsonFilePersistence::new(path.to_path_buf()).unwrap();
+        let result = database.list_users();
+        assert_eq!(result.err().unwrap().code(), ErrorCode::CorruptData);
+    }
+}
@@ -0,0 +1,634 @@
+use crate::errors::Error;
+
+use std::borrow::Cow;
+
+/// A step that brings the schema from `version - 1` to `version`.
+struct Migration {
+    version: u32,
+    statements: &'static [&'static str],
+}
+
+// The schema as it was before its first migration. A new database is created
+// by running these statements followed by every migration.
+const SCHEMA_V0: &[&str] = &[
+    "create table if not exists pelo_global_etag (
+         id integer primary key,
+         token text not null
+     )",
+    "create table if not exists pelo_users (
+         id text primary key,
+         limit_votes_per_week integer not null
+     )",
+    "create table if not exists pelo_tasks (
+         id text primary key,
+         summary text not null,
+         link text,
+         closed integer
+     )",
+    "create table if not exists pelo_ratings (
+         task text not null,
+         elo real not null
+     )",
+    "create table if not exists pelo_votes (
+         voter text not null,
+         time text not null,
+         task0 text not null,
+         task1 text not null,
+         outcome integer
+     )",
+    "create index if not exists pelo_votes_by_user_and_time
+         on pelo_votes(voter, time)",
+];
+
+// Migrations must be listed in order, and must never change once released.
+const MIGRATIONS: &[Migration] = &[
+    // User roles.
+    Migration {
+        version: 1,
+        statements: &["alter table pelo_users add column role text not null default 'voter'"],
+    },
+    // Per-user limit policies, stored as JSON.
+    Migration {
+        version: 2,
+        statements: &["alter table pelo_users add column limit_policy text"],
+    },
+    // Vote ids and retractions. Existing votes get a random version 4 uuid.
+    Migration {
+        version: 3,
+        statements: &[
+            "alter table pelo_votes add column id text",
+            "update pelo_votes set id =
+                 lower(hex(randomblob(4))) || '-' ||
+                 lower(hex(randomblob(2))) || '-4' ||
+                 substr(lower(hex(randomblob(2))), 2) || '-' ||
+                 substr('89ab', 1 + (abs(random()) % 4), 1) ||
+                 substr(lower(hex(randomblob(2))), 2) || '-' ||
+                 lower(hex(randomblob(6)))
+             where id is null",
+            "create unique index if not exists pelo_votes_by_id on pelo_votes(id)",
+            "create table if not exists pelo_vote_retractions (
+                 vote text primary key,
+                 voter text not null,
+                 time text not null,
+                 replacement text
+             )",
+        ],
+    },
+    // Vote comments.
+    Migration {
+        version: 4,
+        statements: &["alter table pelo_votes add column comment text"],
+    },
+    // Task versions. Existing tasks get a first version with an unknown
+    // author, and existing votes refer to an unknown version.
+    Migration {
+        version: 5,
+        statements: &[
+            "alter table pelo_tasks add column version integer not null default 1",
+            "create table if not exists pelo_task_versions (
+                 task text not null,
+                 version integer not null,
+                 time text not null,
+                 author text not null,
+                 summary text not null,
+                 link text,
+                 primary key (task, version)
+             )",
+            "insert into pelo_task_versions(task, version, time, author, summary, link)
+             select id, 1, strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now'), '', summary, link
+             from pelo_tasks",
+            "alter table pelo_votes add column task0_version integer not null default 0",
+            "alter table pelo_votes add column task1_version integer not null default 0",
+        ],
+    },
+    // One rating per task. Earlier versions inserted a new rating on every
+    // upsert of a task; the oldest row is the one that was kept up to date.
+    Migration {
+        version: 6,
+        statements: &[
+            "create table pelo_ratings_by_task (
+                 task text primary key,
+                 elo real not null
+             )",
+            "insert into pelo_ratings_by_task(task, elo)
+             select task, elo from pelo_ratings
+             where rowid in (select min(rowid) from pelo_ratings group by task)",
+            "drop table pelo_ratings",
+            "alter table pelo_ratings_by_task rename to pelo_ratings",
+        ],
+    },
+    // Rating revisions, which let votes on different tasks proceed
+    // concurrently.
+    Migration {
+        version: 7,
+        statements: &["alter table pelo_ratings add column revision integer not null default 0"],
+    },
+    // Rating history. Changes made before this version are not known.
+    Migration {
+        version: 8,
+        statements: &[
+            "create table pelo_rating_changes (
+                 task text not null,
+                 time text not null,
+                 old_elo real not null,
+                 new_elo real not null,
+                 vote text not null,
+                 retraction integer not null
+             )",
+            "create index pelo_rating_changes_by_task_and_time
+                 on pelo_rating_changes(task, time)",
+        ],
+    },
+    // Task tags.
+    Migration {
+        version: 9,
+        statements: &[
+            "create table pelo_task_tags (
+                 task text not null,
+                 tag text not null,
+                 primary key (task, tag)
+             )",
+            "create index pelo_task_tags_by_tag on pelo_task_tags(tag)",
+        ],
+    },
+    // Tenants. Every row belongs to a tenant, which is part of every key, and
+    // each tenant has an etag of its own. Existing rows belong to the default
+    // tenant, whose id is the empty string.
+    Migration {
+        version: 10,
+        statements: &[
+            "create table pelo_global_etag_v10 (
+                 tenant text primary key,
+                 token text not null
+             )",
+            "insert into pelo_global_etag_v10(tenant, token)
+             select '', token from pelo_global_etag order by id limit 1",
+            "drop table pelo_global_etag",
+            "alter table pelo_global_etag_v10 rename to pelo_global_etag",
+            "create table pelo_users_v10 (
+                 tenant text not null default '',
+                 id text not null,
+                 limit_votes_per_week integer not null,
+                 role text not null default 'voter',
+                 limit_policy text,
+                 primary key (tenant, id)
+             )",
+            "insert into pelo_users_v10(tenant, id, limit_votes_per_week, role, limit_policy)
+             select '', id, limit_votes_per_week, role, limit_policy from pelo_users",
+            "drop table pelo_users",
+            "alter table pelo_users_v10 rename to pelo_users",
+            "create table pelo_tasks_v10 (
+                 tenant text not null default '',
+                 id text not null,
+                 summary text not null,
+                 link text,
+                 closed integer,
+                 version integer not null default 1,
+                 primary key (tenant, id)
+             )",
+            "insert into pelo_tasks_v10(tenant, id, summary, link, closed, version)
+             select '', id, summary, link, closed, version from pelo_tasks",
+            "drop table pelo_tasks",
+            "alter table pelo_tasks_v10 rename to pelo_tasks",
+            "create table pelo_task_versions_v10 (
+                 tenant text not null default '',
+                 task text not null,
+                 version integer not null,
+                 time text not null,
+                 author text not null,
+                 summary text not null,
+                 link text,
+                 primary key (tenant, task, version)
+             )",
+            "insert into pelo_task_versions_v10(
+                 tenant, task, version, time, author, summary, link
+             )
+             select '', task, version, time, author, summary, link from pelo_task_versions",
+            "drop table pelo_task_versions",
+            "alter table pelo_task_versions_v10 rename to pelo_task_versions",
+            "create table pelo_ratings_v10 (
+                 tenant text not null default '',
+                 task text not null,
+                 elo real not null,
+                 revision integer not null default 0,
+                 primary key (tenant, task)
+             )",
+            "insert into pelo_ratings_v10(tenant, task, elo, revision)
+             select '', task, elo, revision from pelo_ratings",
+            "drop table pelo_ratings",
+            "alter table pelo_ratings_v10 rename to pelo_ratings",
+            "create table pelo_votes_v10 (
+                 tenant text not null default '',
+                 id text,
+                 voter text not null,
+                 time text not null,
+                 task0 text not null,
+                 task1 text not null,
+                 outcome integer,
+                 comment text,
+                 task0_version integer not null default 0,
+                 task1_version integer not null default 0
+             )",
+            "insert into pelo_votes_v10(
+                 tenant, id, voter, time, task0, task1, outcome, comment,
+                 task0_version, task1_version
+             )
+             select '', id, voter, time, task0, task1, outcome, comment,
+                    task0_version, task1_version
+             from pelo_votes order by rowid",
+            "drop table pelo_votes",
+            "alter table pelo_votes_v10 rename to pelo_votes",
+            "create unique index pelo_votes_by_id on pelo_votes(tenant, id)",
+            "create index pelo_votes_by_user_and_time on pelo_votes(tenant, voter, time)",
+            "create table pelo_vote_retractions_v10 (
+                 tenant text not null default '',
+                 vote text not null,
+                 voter text not null,
+                 time text not null,
+                 replacement text,
+                 primary key (tenant, vote)
+             )",
+            "insert into pelo_vote_retractions_v10(tenant, vote, voter, time, replacement)
+             select '', vote, voter, time, replacement from pelo_vote_retractions
+             order by rowid",
+            "drop table pelo_vote_retractions",
+            "alter table pelo_vote_retractions_v10 rename to pelo_vote_retractions",
+            "create table pelo_rating_changes_v10 (
+                 tenant text not null default '',
+                 task text not null,
+                 time text not null,
+                 old_elo real not null,
+                 new_elo real not null,
+                 vote text not null,
+                 retraction integer not null
+             )",
+            "insert into pelo_rating_changes_v10(
+                 tenant, task, time, old_elo, new_elo, vote, retraction
+             )
+             select '', task, time, old_elo, new_elo, vote, retraction
+             from pelo_rating_changes order by rowid",
+            "drop table pelo_rating_changes",
+            "alter table pelo_rating_changes_v10 rename to pelo_rating_changes",
+            "create index pelo_rating_changes_by_task_and_time
+                 on pelo_rating_changes(tenant, task, time)",
+            "create table pelo_task_tags_v10 (
+                 tenant text not null default '',
+                 task text not null,
+                 tag text not null,
+                 primary key (tenant, task, tag)
+             )",
+            "insert into pelo_task_tags_v10(tenant, task, tag)
+             select '', task, tag from pelo_task_tags",
+            "drop table pelo_task_tags",
+            "alter table pelo_task_tags_v10 rename to pelo_task_tags",
+            "create index pelo_task_tags_by_tag on pelo_task_tags(tenant, tag)",
+        ],
+    },
+    // Vote compaction. Each tenant has at most one archive, with the ratings
+    // reached with the compacted votes and a tally per pair of tasks.
+    Migration {
+        version: 11,
+        statements: &[
+            "create table pelo_vote_archive (
+                 tenant text not null primary key,
+                 cutoff text not null,
+                 retractions integer not null default 0
+             )",
+            "create table pelo_archived_ratings (
+                 tenant text not null,
+                 task text not null,
+                 elo real not null,
+                 primary key (tenant, task)
+             )",
+            "create table pelo_vote_tallies (
+                 tenant text not null,
+                 task0 text not null,
+                 task1 text not null,
+                 wins integer not null,
+                 draws integer not null,
+                 losses integer not null,
+                 retracted integer not null,
+                 primary key (tenant, task0, task1)
+             )",
+            "create index pelo_votes_by_time on pelo_votes(tenant, time)",
+        ],
+    },
+];
+
+/// The schema version that this version of the library creates and expects.
+pub const SCHEMA_VERSION: u32 = 11;
+
+/// The prefix of the names of the tables and indexes, as they appear in the
+/// statements of the library.
+pub const DEFAULT_TABLE_PREFIX: &str = "pelo_";
+
+/// Names the tables and indexes of a statement with `prefix` instead of the
+/// default one.
+pub fn prefixed<'a>(prefix: &str, sql: &'a str) -> Cow<'a, str> {
+    if prefix == DEFAULT_TABLE_PREFIX {
+        Cow::Borrowed(sql)
+    } else {
+        Cow::Owned(sql.replace(DEFAULT_TABLE_PREFIX, prefix))
+    }
+}
+
+/// Reads the schema version of the tables with `prefix`, which is 0 for empty
+/// databases and for databases created before the first migration.
+pub fn schema_version(conn: &rusqlite::Connection, prefix: &str) -> Result<u32, Error> {
+    let has_table: usize = conn.query_row(
+        &prefixed(
+            prefix,
+            "SELECT COUNT(*) FROM sqlite_master
+             WHERE type = 'table' AND name = 'pelo_schema_version'",
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    if has_table == 0 {
+        // Databases upgraded before the version table existed counted the
+        // migrations they went through in SQLite's `user_version`. Without
+        // pelo's tables the database is new, whatever its `user_version`.
+        let has_users: usize = conn.query_row(
+            &prefixed(
+                prefix,
+                "SELECT COUNT(*) FROM sqlite_master
+                 WHERE type = 'table' AND name = 'pelo_users'",
+            ),
+            [],
+            |row| row.get(0),
+        )?;
+        if has_users == 0 {
+            return Ok(0);
+        }
+        return Ok(conn.query_row("pragma user_version", [], |row| row.get(0))?);
+    }
+    let version: Option<u32> = conn
+        .query_row(
+            &prefixed(
+                prefix,
+                "SELECT version FROM pelo_schema_version WHERE id = 0",
+            ),
+            [],
+            |row| row.get(0),
+        )
+        .map(Some)
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            e => Err(e),
+        })?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Brings the schema of the database up to `SCHEMA_VERSION`, running all the
+/// pending migrations in a single transaction. Databases with a newer schema
+/// than this library knows about are rejected.
+pub fn migrate(conn: &mut rusqlite::Connection, prefix: &str) -> Result<(), Error> {
+    // Take the write lock before reading the version, so that connections
+    // upgrading the same database wait for each other instead of running the
+    // same migrations.
+    let tx = conn.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    let current = schema_version(&tx, prefix)?;
+    if current > SCHEMA_VERSION {
+        return Err(Error::schema_too_new(current, SCHEMA_VERSION));
+    }
+    if current == SCHEMA_VERSION {
+        return Ok(());
+    }
+    if current == 0 {
+        for statement in SCHEMA_V0 {
+            tx.execute(&prefixed(prefix, statement), ())?;
+        }
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        for statement in migration.statements {
+            tx.execute(&prefixed(prefix, statement), ())?;
+        }
+    }
+    tx.execute(
+        &prefixed(
+            prefix,
+            "create table if not exists pelo_schema_version (
+             id integer primary key,
+             version integer not null
+         )",
+        ),
+        (),
+    )?;
+    tx.execute(
+        &prefixed(
+            prefix,
+            "insert into pelo_schema_version (id, version)
+                values (0, ?1)
+                on conflict(id) do update set version = ?1",
+        ),
+        (SCHEMA_VERSION,),
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Fails unless the schema of the tables with `prefix` is the one this
+/// library expects, for databases that are opened without being upgraded.
+pub fn check(conn: &rusqlite::Connection, prefix: &str) -> Result<(), Error> {
+    let current = schema_version(conn, prefix)?;
+    if current > SCHEMA_VERSION {
+        return Err(Error::schema_too_new(current, SCHEMA_VERSION));
+    }
+    if current < SCHEMA_VERSION {
+        return Err(Error::db_error(&format!(
+            "database schema version {} must be upgraded to version {}",
+            current, SCHEMA_VERSION
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elo::Outcome;
+    use crate::errors::ErrorCode;
+    use crate::fixtures::TempPath;
+    use crate::persistence::migrations::{
+        migrate, schema_version, DEFAULT_TABLE_PREFIX, MIGRATIONS, SCHEMA_V0, SCHEMA_VERSION,
+    };
+    use crate::persistence::{Persistence, SQLitePersistence};
+
+    use uuid::Uuid;
+
+    fn test_path() -> TempPath {
+        TempPath::new(".db")
+    }
+
+    #[test]
+    fn test_upgrade_v0_database() {
+        let path = test_path();
+        let t0 = Uuid::new_v4();
+        let t1 = Uuid::new_v4();
+        {
+            let conn = rusqlite::Connection::open(path.path()).unwrap();
+            for statement in SCHEMA_V0 {
+                conn.execute(statement, ()).unwrap();
+            }
+            conn.execute("insert into pelo_global_etag values (0, 'old-etag')", ())
+                .unwrap();
+            conn.execute("insert into pelo_users values ('old_user', 3)", ())
+                .unwrap();
+            for (t, summary) in [(&t0, "task zero"), (&t1, "task one")] {
+                conn.execute(
+                    "insert into pelo_tasks values (?1, ?2, 'https://localhost/', 0)",
+                    (t.to_string(), summary),
+                )
+                .unwrap();
+            }
+            // Upserting a task used to add a duplicate rating.
+            conn.execute(
+                "insert into pelo_ratings values (?1, 1216.0), (?2, 1184.0), (?1, 1200.0)",
+                (t0.to_string(), t1.to_string()),
+            )
+            .unwrap();
+            conn.execute(
+                "insert into pelo_votes values ('old_user', '2023-06-10T12:00:00+00:00', ?1, ?2, -1)",
+                (t0.to_string(), t1.to_string()),
+            )
+            .unwrap();
+            assert_eq!(schema_version(&conn, DEFAULT_TABLE_PREFIX).unwrap(), 0);
+        }
+
+        let database = SQLitePersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.schema_version().unwrap(), SCHEMA_VERSION);
+
+        let user = database.get_user("old_user").unwrap();
+        assert_eq!(user.limit_votes_per_week(), 3);
+        assert_eq!(user.role(), crate::data::Role::Voter);
+        assert!(user.limit_policy().is_none());
+
+        let tasks = database.list_tasks().unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert!(tasks.iter().all(|t| t.version() == 1));
+        assert_eq!(database.list_task_versions(&t0).unwrap().len(), 1);
+
+        let mut database = database;
+        let ranking = database.get_snapshot().unwrap().ranking().clone();
+        assert_eq!(ranking.len(), 2);
+        let r0 = ranking.iter().find(|r| r.task() == &t0).unwrap();
+        assert!((r0.elo() - 1216.0).abs() < 0.000001);
+
+        let votes = database.list_votes().unwrap();
+        assert_eq!(votes.len(), 1);
+        assert!(matches!(votes[0].outcome(), Outcome::P0Win));
+        assert!(votes[0].comment().is_none());
+        assert_eq!(votes[0].task_versions(), (0, 0));
+        // The id assigned by the migration is stable.
+        assert_eq!(
+            database.get_vote(votes[0].id()).unwrap().id(),
+            votes[0].id()
+        );
+        drop(database);
+
+        // Opening an up-to-date database again is a no-op.
+        let database = SQLitePersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(database.list_votes().unwrap().len(), 1);
+        drop(database);
+
+        // The existing data belongs to the default tenant only.
+        let other = SQLitePersistence::with_tenant(path.to_path_buf(), "other").unwrap();
+        assert!(other.list_users().unwrap().is_empty());
+        assert!(other.list_votes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_upgrade_user_version_database() {
+        let path = test_path();
+        let t0 = Uuid::new_v4();
+        let t1 = Uuid::new_v4();
+        let v = Uuid::new_v4();
+        {
+            let conn = rusqlite::Connection::open(path.path()).unwrap();
+            for statement in SCHEMA_V0 {
+                conn.execute(statement, ()).unwrap();
+            }
+            for migration in MIGRATIONS.iter().filter(|m| m.version <= 3) {
+                for statement in migration.statements {
+                    conn.execute(statement, ()).unwrap();
+                }
+            }
+            conn.pragma_update(None, "user_version", 3).unwrap();
+            conn.execute(
+                "insert into pelo_users(id, limit_votes_per_week, role)
+                 values ('old_admin', 3, 'admin')",
+                (),
+            )
+            .unwrap();
+            for t in [&t0, &t1] {
+                conn.execute(
+                    "insert into pelo_tasks values (?1, 'task', 'https://localhost/', 0)",
+                    (t.to_string(),),
+                )
+                .unwrap();
+            }
+            conn.execute(
+                "insert into pelo_votes(id, voter, time, task0, task1, outcome)
+                 values (?1, 'old_admin', '2023-06-10T12:00:00+00:00', ?2, ?3, 1)",
+                (v.to_string(), t0.to_string(), t1.to_string()),
+            )
+            .unwrap();
+            assert_eq!(schema_version(&conn, DEFAULT_TABLE_PREFIX).unwrap(), 3);
+        }
+
+        let database = SQLitePersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(
+            database.get_user("old_admin").unwrap().role(),
+            crate::data::Role::Admin
+        );
+        let vote = database.get_vote(&v).unwrap();
+        assert!(vote.comment().is_none());
+        assert_eq!(vote.task_versions(), (0, 0));
+        assert_eq!(database.list_task_versions(&t0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_upgrade() {
+        let path = test_path();
+        {
+            let conn = rusqlite::Connection::open(path.path()).unwrap();
+            for statement in SCHEMA_V0 {
+                conn.execute(statement, ()).unwrap();
+            }
+        }
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let path = path.to_path_buf();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let mut conn = rusqlite::Connection::open(&path).unwrap();
+                    barrier.wait();
+                    migrate(&mut conn, DEFAULT_TABLE_PREFIX)
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap().unwrap();
+        }
+        let database = SQLitePersistence::new(path.to_path_buf()).unwrap();
+        assert_eq!(database.schema_version().unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_refuse_newer_database() {
+        let path = test_path();
+        drop(SQLitePersistence::new(path.to_path_buf()).unwrap());
+        {
+            let conn = rusqlite::Connection::open(path.path()).unwrap();
+            conn.execute(
+                "update pelo_schema_version set version = ?1",
+                (SCHEMA_VERSION + 1,),
+            )
+            .unwrap();
+        }
+        let result = SQLitePersistence::new(path.to_path_buf());
+        assert_eq!(result.err().unwrap().code(), ErrorCode::SchemaTooNew);
+    }
+}
@@ -0,0 +1,570 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::data::{Rating, RatingChange, Retraction, Task, TaskVersion, User, Vote, VoteArchive};
+use crate::errors::Error;
+use crate::persistence::{
+    Etag, Persistence, SQLitePersistence, SQLitePersistenceBuilder, Snapshot,
+};
+use crate::query::{TaskQuery, UserQuery};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread::ThreadId;
+use std::time::Duration;
+
+const DEFAULT_POOL_SIZE: usize = 4;
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A SQLite database shared between threads through a pool of connections.
+///
+/// It is `Send + Sync`, and `Persistence` is implemented for
+/// `&PooledSQLitePersistence` as well, so that every thread can use the same
+/// instance without a lock around it:
+/// `engine.answer_question(&mut &pool, ...)`. Each call borrows a connection
+/// for its duration only, and waits for one when all are in use. Concurrent
+/// writes are arbitrated by SQLite, and the etag rejects votes based on a
+/// ranking that has changed in the meantime.
+///
+/// A transaction takes a connection out of the pool for the thread that
+/// begins it, and every call that thread makes goes to that connection until
+/// the transaction is committed or rolled back.
+pub struct PooledSQLitePersistence {
+    path: PathBuf,
+    idle: Mutex<Vec<SQLitePersistence>>,
+    released: Condvar,
+    // The connections of the transactions in progress, by thread.
+    pinned: Mutex<HashMap<ThreadId, SQLitePersistence>>,
+}
+impl PooledSQLitePersistence {
+    /// Opens a pool of 4 connections in WAL mode, with a busy timeout of 5
+    /// seconds.
+    pub fn new(db_path: PathBuf) -> Result<Self, Error> {
+        Self::with_options(db_path, DEFAULT_POOL_SIZE, DEFAULT_BUSY_TIMEOUT, true)
+    }
+
+    /// Opens `size` connections. Writers wait up to `busy_timeout` for each
+    /// other before failing with a database error. `wal` turns on the
+    /// write-ahead log, which lets readers proceed while a write is going on;
+    /// the setting is stored in the database file.
+    pub fn with_options(
+        db_path: PathBuf,
+        size: usize,
+        busy_timeout: Duration,
+        wal: bool,
+    ) -> Result<Self, Error> {
+        if size == 0 {
+            return Err(Error::generic("a pool needs at least one connection"));
+        }
+        let mut options =
+            SQLitePersistenceBuilder::new(db_path.clone()).with_busy_timeout(busy_timeout);
+        if wal {
+            options = options.with_journal_mode("WAL");
+        }
+        let mut idle = Vec::with_capacity(size);
+        for _ in 0..size {
+            idle.push(options.open()?);
+        }
+        Ok(PooledSQLitePersistence {
+            path: db_path,
+            idle: Mutex::new(idle),
+            released: Condvar::new(),
+            pinned: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Copies the data of the default tenant into the file at `path` while
+    /// the pool stays in use. See `SQLitePersistence::backup`.
+    pub fn backup(&self, path: &Path) -> Result<(), Error> {
+        self.with(|p| p.backup(path))
+    }
+
+    /// Replaces the data of the default tenant with its data in the backup at
+    /// `path`. See `SQLitePersistence::restore`.
+    pub fn restore(&self, path: &Path) -> Result<(), Error> {
+        self.with(|p| p.restore(path))
+    }
+
+    /// Copies the whole database into the file at `path` while the pool stays
+    /// in use. See `SQLitePersistence::backup_database`.
+    pub fn backup_database(&self, path: &Path) -> Result<(), Error> {
+        self.with(|p| p.backup_database(path))
+    }
+
+    /// Replaces the whole database with the backup at `path`. The other
+    /// connections of the pool see the restored content from their next
+    /// call. See `SQLitePersistence::restore_database`.
+    pub fn restore_database(&self, path: &Path) -> Result<(), Error> {
+        self.with(|p| p.restore_database(path))
+    }
+
+    // Runs `f` on the connection of the transaction of the calling thread if
+    // there is one, or else on a connection taken from the pool, which is put
+    // back afterwards.
+    fn with<T>(
+        &self,
+        f: impl FnOnce(&mut SQLitePersistence) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let thread = std::thread::current().id();
+        let pinned = self.lock_pinned()?.remove(&thread);
+        if let Some(mut connection) = pinned {
+            let result = f(&mut connection);
+            self.lock_pinned()?.insert(thread, connection);
+            return result;
+        }
+        let mut connection = self.acquire()?;
+        let result = f(&mut connection);
+        self.release(connection)?;
+        result
+    }
+
+    // Begins a transaction on a connection taken from the pool, which stays
+    // with the calling thread until the transaction ends.
+    fn begin(&self) -> Result<(), Error> {
+        let thread = std::thread::current().id();
+        if self.lock_pinned()?.contains_key(&thread) {
+            return Err(Error::transaction_in_progress());
+        }
+        let mut connection = self.acquire()?;
+        if let Err(e) = connection.begin_transaction() {
+            self.release(connection)?;
+            return Err(e);
+        }
+        self.lock_pinned()?.insert(thread, connection);
+        Ok(())
+    }
+
+    // Commits or rolls back the transaction of the calling thread, and puts
+    // its connection back in the pool. A failed commit is rolled back, so
+    // that the connection goes back without a transaction in progress.
+    fn end(&self, commit: bool) -> Result<(), Error> {
+        let thread = std::thread::current().id();
+        let mut connection = self
+            .lock_pinned()?
+            .remove(&thread)
+            .ok_or(Error::no_transaction())?;
+        let result = if commit {
+            connection.commit_transaction()
+        } else {
+            connection.rollback_transaction()
+        };
+        if result.is_err() && commit {
+            let _ = connection.rollback_transaction();
+        }
+        self.release(connection)?;
+        result
+    }
+
+    // Takes a connection from the pool, waiting for one if all are in use.
+    fn acquire(&self) -> Result<SQLitePersistence, Error> {
+        let mut idle = self.lock()?;
+        loop {
+            match idle.pop() {
+                Some(connection) => return Ok(connection),
+                None => {
+                    idle = self
+                        .released
+                        .wait(idle)
+                        .map_err(|_| Error::generic("the connection pool is poisoned"))?
+                }
+            }
+        }
+    }
+
+    fn release(&self, connection: SQLitePersistence) -> Result<(), Error> {
+        self.lock()?.push(connection);
+        self.released.notify_one();
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Vec<SQLitePersistence>>, Error> {
+        self.idle
+            .lock()
+            .map_err(|_| Error::generic("the connection pool is poisoned"))
+    }
+
+    fn lock_pinned(&self) -> Result<MutexGuard<'_, HashMap<ThreadId, SQLitePersistence>>, Error> {
+        self.pinned
+            .lock()
+            .map_err(|_| Error::generic("the connection pool is poisoned"))
+    }
+}
+
+// The same implementation serves the pool itself and shared references to it.
+macro_rules! impl_pooled_persistence {
+    ($pool:ty) => {
+        impl Persistence for $pool {
+            fn list_users(&self) -> Result<Vec<User>, Error> {
+                self.with(|p| p.list_users())
+            }
+
+            fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
+                self.with(|p| p.upsert_user(u))
+            }
+
+            fn get_user(&self, u_id: &str) -> Result<User, Error> {
+                self.with(|p| p.get_user(u_id))
+            }
+
+            fn get_num_votes_for_user_since(
+                &self,
+                u_id: &str,
+                since: &DateTime<Utc>,
+            ) -> Result<usize, Error> {
+                self.with(|p| p.get_num_votes_for_user_since(u_id, since))
+            }
+
+            fn list_votes_for_user_since(
+                &self,
+                u_id: &str,
+                since: &DateTime<Utc>,
+            ) -> Result<Vec<Vote>, Error> {
+                self.with(|p| p.list_votes_for_user_since(u_id, since))
+            }
+
+            fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+                self.with(|p| p.list_tasks())
+            }
+
+            fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+                self.with(|p| p.get_task(t_id))
+            }
+
+            fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+                self.with(|p| p.upsert_task(t, author))
+            }
+
+            fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+                self.with(|p| p.list_task_versions(t_id))
+            }
+
+            fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
+                self.with(|p| p.close_task(t_id))
+            }
+
+            fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+                self.with(|p| p.get_snapshot())
+            }
+
+            fn get_etag_token(&self) -> Result<String, Error> {
+                self.with(|p| p.get_etag_token())
+            }
+
+            fn add_vote_and_update_ratings(
+                &mut self,
+                etag: &Etag,
+                vote: &Vote,
+                r0: &Rating,
+                r1: &Rating,
+            ) -> Result<(), Error> {
+                self.with(|p| p.add_vote_and_update_ratings(etag, vote, r0, r1))
+            }
+
+            fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+                self.with(|p| p.list_votes())
+            }
+
+            fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+                self.with(|p| p.get_vote(v_id))
+            }
+
+            fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+                self.with(|p| p.list_votes_for_task(t_id))
+            }
+
+            fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+                self.with(|p| p.list_retractions())
+            }
+
+            fn list_retractions_for_user_since(
+                &self,
+                u_id: &str,
+                since: &DateTime<Utc>,
+            ) -> Result<Vec<Retraction>, Error> {
+                self.with(|p| p.list_retractions_for_user_since(u_id, since))
+            }
+
+            fn retract_vote_and_replace_ratings(
+                &mut self,
+                etag: &Etag,
+                retraction: &Retraction,
+                replacement: Option<&Vote>,
+                ratings: &[Rating],
+            ) -> Result<(), Error> {
+                self.with(|p| {
+                    p.retract_vote_and_replace_ratings(etag, retraction, replacement, ratings)
+                })
+            }
+
+            fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+                self.with(|p| p.list_rating_changes(t_id))
+            }
+
+            fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+                self.with(|p| p.get_ranking_at(time))
+            }
+
+            fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+                self.with(|p| p.get_vote_archive())
+            }
+
+            fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+                self.with(|p| p.compact_votes(etag, archive))
+            }
+
+            fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+                self.with(|p| p.query_tasks(query))
+            }
+
+            fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+                self.with(|p| p.count_tasks(query))
+            }
+
+            fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+                self.with(|p| p.query_users(query))
+            }
+
+            fn begin_transaction(&mut self) -> Result<(), Error> {
+                self.begin()
+            }
+
+            fn commit_transaction(&mut self) -> Result<(), Error> {
+                self.end(true)
+            }
+
+            fn rollback_transaction(&mut self) -> Result<(), Error> {
+                self.end(false)
+            }
+        }
+    };
+}
+
+impl_pooled_persistence!(PooledSQLitePersistence);
+impl_pooled_persistence!(&PooledSQLitePersistence);
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{Role, Task, User};
+    use crate::elo::Outcome;
+    use crate::engine::Engine;
+    use crate::errors::ErrorCode;
+    use crate::persistence::pool::PooledSQLitePersistence;
+    use crate::persistence::{Persistence, SQLitePersistence};
+
+    use crate::fixtures::TempPath;
+
+    use std::time::Duration;
+    use url::Url;
+    use uuid::Uuid;
+
+    fn test_path() -> TempPath {
+        TempPath::new(".db")
+    }
+
+    crate::persistence_conformance_tests!(
+        PooledSQLitePersistence::new(test_path().to_path_buf()).unwrap()
+    );
+
+    #[test]
+    fn test_pool_is_send_and_sync() {
+        fn shareable<T: Send + Sync>() {}
+        shareable::<PooledSQLitePersistence>();
+    }
+
+    #[test]
+    fn test_pool_concurrent_votes() {
+        const THREADS: usize = 8;
+        const VOTES_PER_THREAD: usize = 25;
+
+        let path = test_path();
+        let pool = PooledSQLitePersistence::with_options(
+            path.to_path_buf(),
+            THREADS / 2,
+            Duration::from_secs(30),
+            true,
+        )
+        .unwrap();
+        let mut shared = &pool;
+        shared
+            .upsert_user(&User::with_role("test_admin", -1, Role::Admin))
+            .unwrap();
+        let mut tasks = Vec::new();
+        for i in 0..2 {
+            let task = Task::new(
+                Uuid::new_v4(),
+                &format!("task {}", i),
+                Url::parse(&format!("https://localhost/{}", i)).unwrap(),
+                false,
+            );
+            shared.upsert_task(&task, "test_admin").unwrap();
+            tasks.push(task);
+        }
+
+        std::thread::scope(|scope| {
+            for n in 0..THREADS {
+                let (pool, tasks) = (&pool, &tasks);
+                scope.spawn(move || {
+                    let engine = Engine::new();
+                    let mut shared = pool;
+                    let outcome = if n % 2 == 0 {
+                        Outcome::P0Win
+                    } else {
+                        Outcome::P1Win
+                    };
+                    for _ in 0..VOTES_PER_THREAD {
+                        // A vote may lose the race more often than the engine
+                        // retries, in which case it is sent again.
+                        loop {
+                            match engine.answer_question(
+                                &mut shared,
+                                "test_admin",
+                                &tasks[0],
+                                &tasks[1],
+                                outcome,
+                                None,
+                            ) {
+                                Ok(_) => break,
+                                Err(e) => assert_eq!(
+                                    e.code(),
+                                    ErrorCode::OptimisticConcurrencyTooManyRetryAttempts
+                                ),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let votes = shared.list_votes().unwrap();
+        assert_eq!(votes.len(), THREADS * VOTES_PER_THREAD);
+        let ranking = shared.get_snapshot().unwrap();
+        let total: f32 = ranking.ranking().iter().map(|r| r.elo()).sum();
+        assert!((total - 2400.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_pool_transactions() {
+        let path = test_path();
+        let pool = PooledSQLitePersistence::new(path.to_path_buf()).unwrap();
+        let mut shared = &pool;
+        shared
+            .upsert_user(&User::with_role("test_admin", -1, Role::Admin))
+            .unwrap();
+        let engine = Engine::new();
+        let tasks: Vec<Task> = (0..10)
+            .map(|i| {
+                Task::new(
+                    Uuid::new_v4(),
+                    &format!("task {}", i),
+                    Url::parse(&format!("https://localhost/{}", i)).unwrap(),
+                    false,
+                )
+            })
+            .collect();
+        engine.add_tasks(&mut shared, "test_admin", &tasks).unwrap();
+        assert_eq!(shared.list_tasks().unwrap().len(), 10);
+
+        let mut to_close: Vec<Uuid> = tasks.iter().map(|t| *t.id()).collect();
+        to_close.push(Uuid::new_v4());
+        let result = engine.close_tasks(&mut shared, "test_admin", &to_close);
+        assert_eq!(result.err().unwrap().code(), ErrorCode::TaskNotFound);
+        assert!(shared.list_tasks().unwrap().iter().all(|t| !t.closed()));
+
+        // Other threads do not see the writes of a transaction in progress.
+        shared.begin_transaction().unwrap();
+        shared.close_task(tasks[0].id()).unwrap();
+        assert!(shared.get_task(tasks[0].id()).unwrap().closed());
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut other = &pool;
+                assert!(other.list_tasks().unwrap().iter().all(|t| !t.closed()));
+                assert_eq!(
+                    other.commit_transaction().err().unwrap().code(),
+                    ErrorCode::GenericError
+                );
+            });
+        });
+        shared.commit_transaction().unwrap();
+        assert!(shared.get_task(tasks[0].id()).unwrap().closed());
+    }
+
+    #[test]
+    fn test_pool_backup_while_voting() {
+        const VOTES: usize = 50;
+
+        let path = test_path();
+        let pool = PooledSQLitePersistence::new(path.to_path_buf()).unwrap();
+        let mut shared = &pool;
+        shared
+            .upsert_user(&User::with_role("test_admin", -1, Role::Admin))
+            .unwrap();
+        let mut tasks = Vec::new();
+        for i in 0..2 {
+            let task = Task::new(
+                Uuid::new_v4(),
+                &format!("task {}", i),
+                Url::parse(&format!("https://localhost/{}", i)).unwrap(),
+                false,
+            );
+            shared.upsert_task(&task, "test_admin").unwrap();
+            tasks.push(task);
+        }
+
+        let backup = test_path();
+        std::thread::scope(|scope| {
+            let (pool, tasks) = (&pool, &tasks);
+            scope.spawn(move || {
+                let engine = Engine::new();
+                let mut shared = pool;
+                for _ in 0..VOTES {
+                    engine
+                        .answer_question(
+                            &mut shared,
+                            "test_admin",
+                            &tasks[0],
+                            &tasks[1],
+                            Outcome::P0Win,
+                            None,
+                        )
+                        .unwrap();
+                }
+            });
+            pool.backup_database(backup.path()).unwrap();
+        });
+        assert_eq!(shared.list_votes().unwrap().len(), VOTES);
+
+        let copy_path = test_path();
+        let copy = SQLitePersistence::restore_into(backup.path(), copy_path.to_path_buf()).unwrap();
+        assert!(copy.check_integrity().unwrap().is_empty());
+        let copied_votes = copy.list_votes().unwrap().len();
+        assert!(copied_votes <= VOTES);
+        let history = copy.list_rating_changes(tasks[0].id()).unwrap();
+        assert_eq!(history.len(), copied_votes);
+
+        let stale = shared.get_snapshot().unwrap();
+        pool.restore_database(backup.path()).unwrap();
+        assert_eq!(shared.list_votes().unwrap().len(), copied_votes);
+        let r0 = stale.ranking()[0].clone();
+        let r1 = stale.ranking()[1].clone();
+        let vote = crate::data::Vote::new(
+            "test_admin",
+            chrono::Utc::now(),
+            *r0.task(),
+            *r1.task(),
+            Outcome::Draw,
+        );
+        assert_eq!(
+            shared
+                .add_vote_and_update_ratings(stale.etag(), &vote, &r0, &r1)
+                .err()
+                .unwrap()
+                .code(),
+            ErrorCode::OptimisticConcurrencyRetryTransaction
+        );
+    }
+}
@@ -0,0 +1,1171 @@
+use chrono::{DateTime, Utc};
+use postgres::types::ToSql;
+use postgres::{Client, GenericClient, NoTls, Row};
+use url::Url;
+use uuid::Uuid;
+
+use crate::data::{
+    PairTally, Rating, RatingChange, Retraction, Task, TaskVersion, User, Vote, VoteArchive,
+};
+use crate::elo::Outcome;
+use crate::errors::Error;
+use crate::persistence::{
+    archive_cutoff, like_pattern, next_task_version, outcome_to_column, parse_time, sorted_ranking,
+    Etag, Persistence, Snapshot,
+};
+use crate::query::{TaskOrder, TaskQuery, TaskStatus, UserQuery};
+
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// The schema version that this version of the library creates and expects in
+/// PostgreSQL databases.
+pub const POSTGRES_SCHEMA_VERSION: u32 = 2;
+
+// The steps that bring the schema to each version, in order. The tables are
+// those of the SQLite schema, with native types for ids and flags, and a
+// sequence number where SQLite relies on the rowid for the order of the rows.
+const POSTGRES_MIGRATIONS: &[(u32, &[&str])] = &[
+    (
+        1,
+        &[
+            "create table pelo_global_etag (
+             id integer primary key,
+             token text not null
+         )",
+            "insert into pelo_global_etag(id, token) values (0, '')",
+            "create table pelo_users (
+             seq bigserial not null,
+             id text primary key,
+             limit_votes_per_week integer not null,
+             role text not null default 'voter',
+             limit_policy text
+         )",
+            "create table pelo_tasks (
+             seq bigserial not null,
+             id uuid primary key,
+             summary text not null,
+             link text not null,
+             closed boolean not null,
+             version integer not null
+         )",
+            "create table pelo_task_versions (
+             task uuid not null,
+             version integer not null,
+             time text not null,
+             author text not null,
+             summary text not null,
+             link text not null,
+             primary key (task, version)
+         )",
+            "create table pelo_task_tags (
+             task uuid not null,
+             tag text not null,
+             primary key (task, tag)
+         )",
+            "create index pelo_task_tags_by_tag on pelo_task_tags(tag)",
+            "create table pelo_ratings (
+             task uuid primary key,
+             elo real not null,
+             revision bigint not null default 0
+         )",
+            "create table pelo_votes (
+             seq bigserial not null,
+             id uuid primary key,
+             voter text not null,
+             time text not null,
+             task0 uuid not null,
+             task1 uuid not null,
+             outcome integer not null,
+             comment text,
+             task0_version integer not null,
+             task1_version integer not null
+         )",
+            "create index pelo_votes_by_user_and_time on pelo_votes(voter, time)",
+            "create table pelo_vote_retractions (
+             seq bigserial not null,
+             vote uuid primary key,
+             voter text not null,
+             time text not null,
+             replacement uuid
+         )",
+            "create table pelo_rating_changes (
+             seq bigserial not null,
+             task uuid not null,
+             time text not null,
+             old_elo real not null,
+             new_elo real not null,
+             vote uuid not null,
+             retraction boolean not null
+         )",
+            "create index pelo_rating_changes_by_task_and_time
+             on pelo_rating_changes(task, time)",
+        ],
+    ),
+    // Vote compaction: the archive, with the ratings reached with the
+    // compacted votes and a tally per pair of tasks.
+    (
+        2,
+        &[
+            "create table pelo_vote_archive (
+                 id integer primary key,
+                 cutoff text not null,
+                 retractions integer not null
+             )",
+            "create table pelo_archived_ratings (
+                 seq bigserial not null,
+                 task uuid primary key,
+                 elo real not null
+             )",
+            "create table pelo_vote_tallies (
+                 seq bigserial not null,
+                 task0 uuid not null,
+                 task1 uuid not null,
+                 wins integer not null,
+                 draws integer not null,
+                 losses integer not null,
+                 retracted integer not null,
+                 primary key (task0, task1)
+             )",
+            "create index pelo_votes_by_time on pelo_votes(time)",
+        ],
+    ),
+];
+
+// The key of the advisory lock held while the schema is upgraded, so that
+// replicas starting together upgrade it once.
+const MIGRATION_LOCK: i64 = 0x7065_6c6f;
+
+const USER_COLUMNS: &str = "id, limit_votes_per_week, role, limit_policy";
+const TASK_COLUMNS: &str = "id, summary, link, closed, version,
+     array(SELECT tag FROM pelo_task_tags WHERE task = pelo_tasks.id ORDER BY tag)";
+const TASK_VERSION_COLUMNS: &str = "task, version, time, author, summary, link";
+const VOTE_COLUMNS: &str =
+    "id, voter, time, task0, task1, outcome, comment, task0_version, task1_version";
+const RETRACTION_COLUMNS: &str = "vote, voter, time, replacement";
+const RATING_CHANGE_COLUMNS: &str = "task, time, old_elo, new_elo, vote, retraction";
+const TALLY_COLUMNS: &str = "task0, task1, wins, draws, losses, retracted";
+
+/// A PostgreSQL database, which several processes can share.
+///
+/// The semantics are those of `SQLitePersistence`: every write runs in a
+/// transaction that first locks the row of the etag, so that writers are
+/// serialized and the etag they check cannot change before they commit.
+/// Votes are checked against the revisions of the ratings of their tasks,
+/// and retractions against the etag token. Transactions that the server
+/// aborts as serialization failures or deadlocks are reported as
+/// `OptimisticConcurrencyRetryTransaction`, so that the engine retries them.
+pub struct PostgresPersistence {
+    // Reads need the client mutably but take `&self`.
+    client: RefCell<Client>,
+    in_transaction: bool,
+}
+impl PostgresPersistence {
+    /// Connects without TLS, with a configuration string such as
+    /// `host=localhost user=pelo dbname=pelo` or a `postgresql://` URL, and
+    /// creates or upgrades the schema if necessary.
+    pub fn new(config: &str) -> Result<Self, Error> {
+        Self::with_client(Client::connect(config, NoTls)?)
+    }
+
+    /// Uses a client connected by the caller, for instance over TLS.
+    pub fn with_client(mut client: Client) -> Result<Self, Error> {
+        migrate(&mut client)?;
+        rotate_etag(&mut client)?;
+        Ok(PostgresPersistence {
+            client: RefCell::new(client),
+            in_transaction: false,
+        })
+    }
+
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        schema_version(&mut *self.client())
+    }
+
+    fn client(&self) -> RefMut<'_, Client> {
+        self.client.borrow_mut()
+    }
+
+    fn read<T>(
+        &self,
+        sql: &str,
+        params: &[&(dyn ToSql + Sync)],
+        decode: fn(&Row) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        query(&mut self.client(), sql, params, decode)
+    }
+
+    // Runs a write in a transaction of its own, or in a savepoint of the
+    // explicit transaction in progress, after locking the row of the etag.
+    // The write is undone if it fails, and the etag is rotated if it
+    // succeeds.
+    fn write<T>(&mut self, f: impl FnOnce(&mut Client) -> Result<T, Error>) -> Result<T, Error> {
+        let nested = self.in_transaction;
+        let client = self.client.get_mut();
+        client.batch_execute(if nested {
+            "SAVEPOINT pelo_write"
+        } else {
+            "BEGIN"
+        })?;
+        let result = client
+            .execute(
+                "SELECT token FROM pelo_global_etag WHERE id = 0 FOR UPDATE",
+                &[],
+            )
+            .map_err(Error::from)
+            .and_then(|_| f(&mut *client))
+            .and_then(|value| rotate_etag(&mut *client).map(|_| value));
+        match result {
+            Ok(value) => {
+                client.batch_execute(if nested {
+                    "RELEASE SAVEPOINT pelo_write"
+                } else {
+                    "COMMIT"
+                })?;
+                Ok(value)
+            }
+            Err(e) => {
+                client.batch_execute(if nested {
+                    "ROLLBACK TO SAVEPOINT pelo_write; RELEASE SAVEPOINT pelo_write"
+                } else {
+                    "ROLLBACK"
+                })?;
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Persistence for PostgresPersistence {
+    fn list_users(&self) -> Result<Vec<User>, Error> {
+        self.read(
+            &format!("SELECT {} FROM pelo_users ORDER BY seq", USER_COLUMNS),
+            &[],
+            user_from_row,
+        )
+    }
+
+    fn upsert_user(&mut self, u: &User) -> Result<(), Error> {
+        let policy = u
+            .limit_policy()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| Error::generic(&e.to_string()))?;
+        self.write(|client| {
+            client.execute(
+                "insert into pelo_users(id, limit_votes_per_week, role, limit_policy)
+                 values ($1, $2, $3, $4)
+                 on conflict(id) do update set
+                     (limit_votes_per_week, role, limit_policy) = ($2, $3, $4)",
+                &[
+                    &u.id(),
+                    &u.limit_votes_per_week(),
+                    &u.role().as_str(),
+                    &policy,
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_user(&self, u_id: &str) -> Result<User, Error> {
+        self.read(
+            &format!("SELECT {} FROM pelo_users WHERE id = $1", USER_COLUMNS),
+            &[&u_id],
+            user_from_row,
+        )?
+        .pop()
+        .ok_or_else(|| Error::user_not_found(u_id))
+    }
+
+    fn get_num_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<usize, Error> {
+        let count: i64 = self
+            .client()
+            .query_one(
+                "SELECT COUNT(*) FROM pelo_votes WHERE voter = $1 AND time >= $2",
+                &[&u_id, &since.to_rfc3339()],
+            )?
+            .try_get(0)?;
+        Ok(count as usize)
+    }
+
+    fn list_votes_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Vote>, Error> {
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_votes WHERE voter = $1 AND time >= $2 ORDER BY seq",
+                VOTE_COLUMNS
+            ),
+            &[&u_id, &since.to_rfc3339()],
+            vote_from_row,
+        )
+    }
+
+    fn list_tasks(&self) -> Result<Vec<Task>, Error> {
+        self.read(
+            &format!("SELECT {} FROM pelo_tasks ORDER BY seq", TASK_COLUMNS),
+            &[],
+            task_from_row,
+        )
+    }
+
+    fn get_task(&self, t_id: &Uuid) -> Result<Task, Error> {
+        self.read(
+            &format!("SELECT {} FROM pelo_tasks WHERE id = $1", TASK_COLUMNS),
+            &[t_id],
+            task_from_row,
+        )?
+        .pop()
+        .ok_or(Error::task_not_found(t_id))
+    }
+
+    fn upsert_task(&mut self, t: &Task, author: &str) -> Result<(), Error> {
+        self.write(|client| {
+            let current = query(
+                client,
+                &format!("SELECT {} FROM pelo_tasks WHERE id = $1", TASK_COLUMNS),
+                &[t.id()],
+                task_from_row,
+            )?
+            .pop();
+            let mut version = current.as_ref().map(|c| c.version()).unwrap_or(0);
+            if let Some(v) = next_task_version(current.as_ref(), t) {
+                version = v;
+                client.execute(
+                    "insert into pelo_task_versions(task, version, time, author, summary, link)
+                     values ($1, $2, $3, $4, $5, $6)",
+                    &[
+                        t.id(),
+                        &(version as i32),
+                        &DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
+                        &author,
+                        &t.summary(),
+                        &t.link().to_string(),
+                    ],
+                )?;
+            }
+            client.execute(
+                "insert into pelo_tasks(id, summary, link, closed, version)
+                 values ($1, $2, $3, $4, $5)
+                 on conflict(id) do update set (summary, link, closed, version) = ($2, $3, $4, $5)",
+                &[
+                    t.id(),
+                    &t.summary(),
+                    &t.link().to_string(),
+                    &t.closed(),
+                    &(version as i32),
+                ],
+            )?;
+            client.execute("delete from pelo_task_tags where task = $1", &[t.id()])?;
+            for tag in t.tags() {
+                client.execute(
+                    "insert into pelo_task_tags(task, tag) values ($1, $2)",
+                    &[t.id(), tag],
+                )?;
+            }
+            client.execute(
+                "insert into pelo_ratings(task, elo)
+                 values ($1, $2)
+                 on conflict(task) do nothing",
+                &[t.id(), &Rating::new(*t.id()).elo()],
+            )?;
+            Ok(())
+        })
+    }
+
+    fn list_task_versions(&self, t_id: &Uuid) -> Result<Vec<TaskVersion>, Error> {
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_task_versions WHERE task = $1 ORDER BY version",
+                TASK_VERSION_COLUMNS
+            ),
+            &[t_id],
+            task_version_from_row,
+        )
+    }
+
+    fn close_task(&mut self, t_id: &Uuid) -> Result<(), Error> {
+        self.write(|client| {
+            let changed =
+                client.execute("update pelo_tasks set closed = true where id = $1", &[t_id])?;
+            if changed == 0 {
+                return Err(Error::task_not_found(t_id));
+            }
+            Ok(())
+        })
+    }
+
+    fn get_snapshot(&mut self) -> Result<Snapshot, Error> {
+        // The etag is read first: if another writer commits before the
+        // ranking is read, the etag is stale and writes based on it are
+        // rejected.
+        let token = self.get_etag_token()?;
+        let client = self.client.get_mut();
+        let mut revisions = HashMap::new();
+        let mut ranking = Vec::new();
+        for row in client.query("SELECT task, elo, revision FROM pelo_ratings", &[])? {
+            let task: Uuid = row.try_get(0)?;
+            let revision: i64 = row.try_get(2)?;
+            revisions.insert(task, revision as u64);
+            ranking.push(Rating::with_elo(task, row.try_get(1)?));
+        }
+        Ok(Snapshot {
+            ranking: sorted_ranking(ranking),
+            etag: Etag::with_revisions(&token, revisions),
+        })
+    }
+
+    fn get_etag_token(&self) -> Result<String, Error> {
+        Ok(self
+            .client()
+            .query_one("SELECT token FROM pelo_global_etag WHERE id = 0", &[])?
+            .try_get(0)?)
+    }
+
+    fn add_vote_and_update_ratings(
+        &mut self,
+        etag: &Etag,
+        vote: &Vote,
+        r0: &Rating,
+        r1: &Rating,
+    ) -> Result<(), Error> {
+        self.write(|client| {
+            etag.check_revisions([r0, r1], |t| {
+                Ok(client
+                    .query_opt("SELECT revision FROM pelo_ratings WHERE task = $1", &[t])?
+                    .map(|row| row.try_get::<_, i64>(0))
+                    .transpose()?
+                    .map(|revision| revision as u64))
+            })?;
+            let now = SystemTime::now().into();
+            for rating in [r0, r1] {
+                update_rating(client, rating, &now, vote.id(), false)?;
+            }
+            insert_vote(client, vote)
+        })
+    }
+
+    fn list_votes(&self) -> Result<Vec<Vote>, Error> {
+        self.read(
+            &format!("SELECT {} FROM pelo_votes ORDER BY time, seq", VOTE_COLUMNS),
+            &[],
+            vote_from_row,
+        )
+    }
+
+    fn get_vote(&self, v_id: &Uuid) -> Result<Vote, Error> {
+        self.read(
+            &format!("SELECT {} FROM pelo_votes WHERE id = $1", VOTE_COLUMNS),
+            &[v_id],
+            vote_from_row,
+        )?
+        .pop()
+        .ok_or(Error::vote_not_found(v_id))
+    }
+
+    fn list_votes_for_task(&self, t_id: &Uuid) -> Result<Vec<Vote>, Error> {
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_votes WHERE task0 = $1 OR task1 = $1 ORDER BY time, seq",
+                VOTE_COLUMNS
+            ),
+            &[t_id],
+            vote_from_row,
+        )
+    }
+
+    fn list_retractions(&self) -> Result<Vec<Retraction>, Error> {
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_vote_retractions ORDER BY seq",
+                RETRACTION_COLUMNS
+            ),
+            &[],
+            retraction_from_row,
+        )
+    }
+
+    fn list_retractions_for_user_since(
+        &self,
+        u_id: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Vec<Retraction>, Error> {
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_vote_retractions
+                 WHERE voter = $1 AND time >= $2 ORDER BY seq",
+                RETRACTION_COLUMNS
+            ),
+            &[&u_id, &since.to_rfc3339()],
+            retraction_from_row,
+        )
+    }
+
+    fn retract_vote_and_replace_ratings(
+        &mut self,
+        etag: &Etag,
+        retraction: &Retraction,
+        replacement: Option<&Vote>,
+        ratings: &[Rating],
+    ) -> Result<(), Error> {
+        self.get_vote(retraction.vote())?;
+        self.write(|client| {
+            let token: String = client
+                .query_one("SELECT token FROM pelo_global_etag WHERE id = 0", &[])?
+                .try_get(0)?;
+            if token != etag.token {
+                return Err(Error::retry_transaction());
+            }
+            let retracted = client.query_opt(
+                "SELECT 1 FROM pelo_vote_retractions WHERE vote = $1",
+                &[retraction.vote()],
+            )?;
+            if retracted.is_some() {
+                return Err(Error::retraction_not_allowed(
+                    retraction.vote(),
+                    "already retracted",
+                ));
+            }
+            client.execute(
+                "insert into pelo_vote_retractions(vote, voter, time, replacement)
+                 values ($1, $2, $3, $4)",
+                &[
+                    retraction.vote(),
+                    &retraction.voter(),
+                    &retraction.time().to_rfc3339(),
+                    &retraction.replacement(),
+                ],
+            )?;
+            if let Some(vote) = replacement {
+                insert_vote(client, vote)?;
+            }
+            let now = SystemTime::now().into();
+            for rating in ratings {
+                update_rating(client, rating, &now, retraction.vote(), true)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn list_rating_changes(&self, t_id: &Uuid) -> Result<Vec<RatingChange>, Error> {
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_rating_changes WHERE task = $1 ORDER BY time, seq",
+                RATING_CHANGE_COLUMNS
+            ),
+            &[t_id],
+            rating_change_from_row,
+        )
+    }
+
+    fn get_ranking_at(&self, time: &DateTime<Utc>) -> Result<Vec<Rating>, Error> {
+        let ranking = self.read(
+            "SELECT task, coalesce(
+                 (SELECT new_elo FROM pelo_rating_changes c
+                  WHERE c.task = r.task AND c.time <= $1
+                  ORDER BY c.time DESC, c.seq DESC LIMIT 1),
+                 $2
+             )
+             FROM pelo_ratings r
+             WHERE NOT EXISTS (
+                 SELECT 1 FROM pelo_task_versions v
+                 WHERE v.task = r.task AND v.version = 1 AND v.time > $1
+             )",
+            &[&time.to_rfc3339(), &Rating::new(Uuid::nil()).elo()],
+            |row| Ok(Rating::with_elo(row.try_get(0)?, row.try_get(1)?)),
+        )?;
+        Ok(sorted_ranking(ranking))
+    }
+
+    fn get_vote_archive(&self) -> Result<VoteArchive, Error> {
+        let archive = self.client().query_opt(
+            "SELECT cutoff, retractions FROM pelo_vote_archive WHERE id = 0",
+            &[],
+        )?;
+        let Some(archive) = archive else {
+            return Ok(VoteArchive::new());
+        };
+        let cutoff: String = archive.try_get(0)?;
+        let retractions: i32 = archive.try_get(1)?;
+        Ok(VoteArchive::with_contents(
+            parse_time(&cutoff)?,
+            self.read(
+                "SELECT task, elo FROM pelo_archived_ratings ORDER BY seq",
+                &[],
+                |row| Ok(Rating::with_elo(row.try_get(0)?, row.try_get(1)?)),
+            )?,
+            self.read(
+                &format!(
+                    "SELECT {} FROM pelo_vote_tallies ORDER BY seq",
+                    TALLY_COLUMNS
+                ),
+                &[],
+                tally_from_row,
+            )?,
+            retractions as u32,
+        ))
+    }
+
+    fn compact_votes(&mut self, etag: &Etag, archive: &VoteArchive) -> Result<(), Error> {
+        let cutoff = archive_cutoff(archive)?.to_rfc3339();
+        self.write(|client| {
+            let token: String = client
+                .query_one("SELECT token FROM pelo_global_etag WHERE id = 0", &[])?
+                .try_get(0)?;
+            if token != etag.token {
+                return Err(Error::retry_transaction());
+            }
+            client.execute(
+                "delete from pelo_vote_retractions
+                 WHERE vote IN (SELECT id FROM pelo_votes WHERE time < $1)",
+                &[&cutoff],
+            )?;
+            client.execute("delete from pelo_votes WHERE time < $1", &[&cutoff])?;
+            client.execute(
+                "insert into pelo_vote_archive(id, cutoff, retractions) values (0, $1, $2)
+                 on conflict(id) do update set (cutoff, retractions) = ($1, $2)",
+                &[&cutoff, &(archive.retractions() as i32)],
+            )?;
+            client.batch_execute(
+                "delete from pelo_archived_ratings; delete from pelo_vote_tallies",
+            )?;
+            for rating in archive.ratings() {
+                client.execute(
+                    "insert into pelo_archived_ratings(task, elo) values ($1, $2)",
+                    &[rating.task(), &rating.elo()],
+                )?;
+            }
+            for tally in archive.tallies() {
+                client.execute(
+                    "insert into pelo_vote_tallies(task0, task1, wins, draws, losses, retracted)
+                     values ($1, $2, $3, $4, $5, $6)",
+                    &[
+                        tally.task0(),
+                        tally.task1(),
+                        &(tally.wins() as i32),
+                        &(tally.draws() as i32),
+                        &(tally.losses() as i32),
+                        &(tally.retracted() as i32),
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn query_tasks(&self, query: &TaskQuery) -> Result<Vec<Task>, Error> {
+        let (filter, params) = task_filter(query);
+        let direction = if query.descending() { "DESC" } else { "ASC" };
+        // Summaries are compared byte by byte, as SQLite does.
+        let order = match query.order() {
+            TaskOrder::Id => format!("ORDER BY id {}", direction),
+            TaskOrder::Summary => format!("ORDER BY summary COLLATE \"C\" {0}, id {0}", direction),
+        };
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_tasks {} {} {}",
+                TASK_COLUMNS,
+                filter,
+                order,
+                page_clause(query.offset(), query.limit())
+            ),
+            &sql_params(&params),
+            task_from_row,
+        )
+    }
+
+    fn count_tasks(&self, query: &TaskQuery) -> Result<usize, Error> {
+        let (filter, params) = task_filter(query);
+        let count: i64 = self
+            .client()
+            .query_one(
+                &format!("SELECT COUNT(*) FROM pelo_tasks {}", filter),
+                &sql_params(&params),
+            )?
+            .try_get(0)?;
+        Ok(count as usize)
+    }
+
+    fn query_users(&self, query: &UserQuery) -> Result<Vec<User>, Error> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        if let Some(role) = query.role() {
+            params.push(role.as_str().to_string());
+            conditions.push(format!("role = ${}", params.len()));
+        }
+        if let Some(text) = query.text() {
+            params.push(like_pattern(text));
+            conditions.push(format!("id ILIKE ${} ESCAPE '\\'", params.len()));
+        }
+        self.read(
+            &format!(
+                "SELECT {} FROM pelo_users {} ORDER BY id COLLATE \"C\" {}",
+                USER_COLUMNS,
+                where_clause(&conditions),
+                page_clause(query.offset(), query.limit())
+            ),
+            &sql_params(&params),
+            user_from_row,
+        )
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), Error> {
+        if self.in_transaction {
+            return Err(Error::transaction_in_progress());
+        }
+        self.client.get_mut().batch_execute("BEGIN")?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    fn commit_transaction(&mut self) -> Result<(), Error> {
+        if !self.in_transaction {
+            return Err(Error::no_transaction());
+        }
+        self.in_transaction = false;
+        self.client.get_mut().batch_execute("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback_transaction(&mut self) -> Result<(), Error> {
+        if !self.in_transaction {
+            return Err(Error::no_transaction());
+        }
+        self.in_transaction = false;
+        self.client.get_mut().batch_execute("ROLLBACK")?;
+        Ok(())
+    }
+}
+
+// Reads the schema version, which is 0 for a database without pelo tables.
+fn schema_version(client: &mut impl GenericClient) -> Result<u32, Error> {
+    let row = client.query_one("SELECT to_regclass('pelo_schema_version') IS NOT NULL", &[])?;
+    if !row.try_get::<_, bool>(0)? {
+        return Ok(0);
+    }
+    let version: Option<i32> = client
+        .query_opt("SELECT version FROM pelo_schema_version WHERE id = 0", &[])?
+        .map(|row| row.try_get(0))
+        .transpose()?;
+    Ok(version.unwrap_or(0) as u32)
+}
+
+// Brings the schema up to `POSTGRES_SCHEMA_VERSION` in a single transaction,
+// and rejects databases with a newer schema.
+fn migrate(client: &mut Client) -> Result<(), Error> {
+    let mut transaction = client.transaction()?;
+    transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])?;
+    let current = schema_version(&mut transaction)?;
+    if current > POSTGRES_SCHEMA_VERSION {
+        return Err(Error::schema_too_new(current, POSTGRES_SCHEMA_VERSION));
+    }
+    if current == POSTGRES_SCHEMA_VERSION {
+        return Ok(());
+    }
+    for (_, statements) in POSTGRES_MIGRATIONS
+        .iter()
+        .filter(|(version, _)| *version > current)
+    {
+        for statement in *statements {
+            transaction.batch_execute(statement)?;
+        }
+    }
+    transaction.batch_execute(
+        "create table if not exists pelo_schema_version (
+             id integer primary key,
+             version integer not null
+         )",
+    )?;
+    transaction.execute(
+        "insert into pelo_schema_version (id, version)
+         values (0, $1)
+         on conflict(id) do update set version = $1",
+        &[&(POSTGRES_SCHEMA_VERSION as i32)],
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+fn query<T>(
+    client: &mut Client,
+    sql: &str,
+    params: &[&(dyn ToSql + Sync)],
+    decode: fn(&Row) -> Result<T, Error>,
+) -> Result<Vec<T>, Error> {
+    client.query(sql, params)?.iter().map(decode).collect()
+}
+
+fn rotate_etag(client: &mut Client) -> Result<(), Error> {
+    client.execute(
+        "update pelo_global_etag set token = $1 where id = 0",
+        &[&Uuid::new_v4().to_string()],
+    )?;
+    Ok(())
+}
+
+fn insert_vote(client: &mut Client, vote: &Vote) -> Result<(), Error> {
+    client.execute(
+        "insert into pelo_votes(
+             id, voter, time, task0, task1, outcome, comment, task0_version, task1_version
+         )
+         values ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        &[
+            vote.id(),
+            &vote.voter(),
+            &vote.time().to_rfc3339(),
+            vote.task0(),
+            vote.task1(),
+            &outcome_to_column(vote.outcome()),
+            &vote.comment(),
+            &(vote.task_versions().0 as i32),
+            &(vote.task_versions().1 as i32),
+        ],
+    )?;
+    Ok(())
+}
+
+// Stores a rating, recording the change unless a retraction left it as it
+// was.
+fn update_rating(
+    client: &mut Client,
+    rating: &Rating,
+    time: &DateTime<Utc>,
+    vote: &Uuid,
+    retraction: bool,
+) -> Result<(), Error> {
+    let old_elo: f32 = client
+        .query_opt(
+            "SELECT elo FROM pelo_ratings WHERE task = $1",
+            &[rating.task()],
+        )?
+        .ok_or(Error::task_not_found(rating.task()))?
+        .try_get(0)?;
+    client.execute(
+        "update pelo_ratings set elo = $2, revision = revision + 1 where task = $1",
+        &[rating.task(), &rating.elo()],
+    )?;
+    if !retraction || old_elo != rating.elo() {
+        client.execute(
+            "insert into pelo_rating_changes(task, time, old_elo, new_elo, vote, retraction)
+             values ($1, $2, $3, $4, $5, $6)",
+            &[
+                rating.task(),
+                &time.to_rfc3339(),
+                &old_elo,
+                &rating.elo(),
+                vote,
+                &retraction,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+// The conditions of a task query, and their parameters.
+fn task_filter(query: &TaskQuery) -> (String, Vec<String>) {
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    match query.status() {
+        TaskStatus::Any => {}
+        TaskStatus::Open => conditions.push("NOT closed".to_string()),
+        TaskStatus::Closed => conditions.push("closed".to_string()),
+    }
+    for tag in query.tags() {
+        params.push(tag.clone());
+        conditions.push(format!(
+            "EXISTS (SELECT 1 FROM pelo_task_tags
+                     WHERE task = pelo_tasks.id AND tag = ${})",
+            params.len()
+        ));
+    }
+    if let Some(text) = query.text() {
+        // ILIKE, since LIKE ignores the case of ASCII letters in SQLite.
+        params.push(like_pattern(text));
+        conditions.push(format!("summary ILIKE ${} ESCAPE '\\'", params.len()));
+    }
+    (where_clause(&conditions), params)
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+fn page_clause(offset: usize, limit: Option<usize>) -> String {
+    match limit {
+        Some(limit) => format!("LIMIT {} OFFSET {}", limit, offset),
+        None => format!("OFFSET {}", offset),
+    }
+}
+
+fn sql_params(values: &[String]) -> Vec<&(dyn ToSql + Sync)> {
+    values.iter().map(|v| v as &(dyn ToSql + Sync)).collect()
+}
+
+fn user_from_row(row: &Row) -> Result<User, Error> {
+    let id: String = row.try_get(0)?;
+    let limit: i32 = row.try_get(1)?;
+    let role: String = row.try_get(2)?;
+    let policy: Option<String> = row.try_get(3)?;
+    let mut user = User::with_role(&id, limit, role.parse()?);
+    if let Some(p) = policy {
+        user.set_limit_policy(Some(
+            serde_json::from_str(&p).map_err(|e| Error::db_error(&e.to_string()))?,
+        ));
+    }
+    Ok(user)
+}
+
+fn task_from_row(row: &Row) -> Result<Task, Error> {
+    let link: String = row.try_get(2)?;
+    let version: i32 = row.try_get(4)?;
+    let tags: Vec<String> = row.try_get(5)?;
+    let mut task = Task::with_version(
+        row.try_get(0)?,
+        row.try_get(1)?,
+        Url::parse(&link)?,
+        row.try_get(3)?,
+        version as u32,
+    );
+    task.set_tags(&tags);
+    Ok(task)
+}
+
+fn task_version_from_row(row: &Row) -> Result<TaskVersion, Error> {
+    let version: i32 = row.try_get(1)?;
+    let time: String = row.try_get(2)?;
+    let link: String = row.try_get(5)?;
+    Ok(TaskVersion::new(
+        row.try_get(0)?,
+        version as u32,
+        parse_time(&time)?,
+        row.try_get(3)?,
+        row.try_get(4)?,
+        Url::parse(&link)?,
+    ))
+}
+
+fn vote_from_row(row: &Row) -> Result<Vote, Error> {
+    let time: String = row.try_get(2)?;
+    let outcome: i32 = row.try_get(5)?;
+    let comment: Option<String> = row.try_get(6)?;
+    let versions: (i32, i32) = (row.try_get(7)?, row.try_get(8)?);
+    let mut vote = Vote::with_id(
+        row.try_get(0)?,
+        row.try_get(1)?,
+        parse_time(&time)?,
+        row.try_get(3)?,
+        row.try_get(4)?,
+        match outcome {
+            -1 => Outcome::P0Win,
+            0 => Outcome::Draw,
+            1 => Outcome::P1Win,
+            _ => return Err(Error::db_error(&format!("invalid outcome {}", outcome))),
+        },
+    );
+    vote.set_comment(comment.as_deref());
+    vote.set_task_versions(versions.0 as u32, versions.1 as u32);
+    Ok(vote)
+}
+
+fn tally_from_row(row: &Row) -> Result<PairTally, Error> {
+    let counts: [i32; 4] = [
+        row.try_get(2)?,
+        row.try_get(3)?,
+        row.try_get(4)?,
+        row.try_get(5)?,
+    ];
+    Ok(PairTally::with_counts(
+        row.try_get(0)?,
+        row.try_get(1)?,
+        counts[0] as u32,
+        counts[1] as u32,
+        counts[2] as u32,
+        counts[3] as u32,
+    ))
+}
+
+fn retraction_from_row(row: &Row) -> Result<Retraction, Error> {
+    let time: String = row.try_get(2)?;
+    Ok(Retraction::new(
+        row.try_get(0)?,
+        row.try_get(1)?,
+        parse_time(&time)?,
+        row.try_get(3)?,
+    ))
+}
+
+fn rating_change_from_row(row: &Row) -> Result<RatingChange, Error> {
+    let time: String = row.try_get(1)?;
+    Ok(RatingChange::new(
+        row.try_get(0)?,
+        parse_time(&time)?,
+        row.try_get(2)?,
+        row.try_get(3)?,
+        row.try_get(4)?,
+        row.try_get(5)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conformance;
+    use crate::data::{Rating, Task, User, Vote};
+    use crate::elo::Outcome;
+    use crate::errors::ErrorCode;
+    use crate::persistence::postgresql::POSTGRES_SCHEMA_VERSION;
+    use crate::persistence::{Etag, Persistence, PostgresPersistence};
+
+    use postgres::{Client, NoTls};
+    use url::Url;
+    use uuid::Uuid;
+
+    use std::cell::RefCell;
+
+    // The tests run against the server named by this variable, a
+    // configuration string such as "host=localhost user=postgres", each in a
+    // schema of its own. They are ignored by default, and run with
+    // `cargo test --features postgres -- --ignored` once it is set.
+    const TEST_SERVER_VARIABLE: &str = "PELO_TEST_POSTGRES";
+
+    // Schemas created for a test, and dropped at its end.
+    struct TestSchemas {
+        config: String,
+        names: RefCell<Vec<String>>,
+    }
+    impl TestSchemas {
+        fn new() -> Self {
+            let config = std::env::var(TEST_SERVER_VARIABLE)
+                .unwrap_or_else(|_| panic!("{} is not set", TEST_SERVER_VARIABLE));
+            TestSchemas {
+                config,
+                names: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn create(&self) -> String {
+            let name = format!("pelo_test_{}", Uuid::new_v4().simple());
+            Client::connect(&self.config, NoTls)
+                .unwrap()
+                .batch_execute(&format!("create schema {}", name))
+                .unwrap();
+            self.names.borrow_mut().push(name.clone());
+            name
+        }
+
+        fn connect(&self, schema: &str) -> Result<PostgresPersistence, crate::errors::Error> {
+            PostgresPersistence::new(&format!(
+                "{} options='-c search_path={}'",
+                self.config, schema
+            ))
+        }
+
+        fn open(&self) -> PostgresPersistence {
+            self.connect(&self.create()).unwrap()
+        }
+    }
+    impl Drop for TestSchemas {
+        fn drop(&mut self) {
+            if let Ok(mut client) = Client::connect(&self.config, NoTls) {
+                for name in self.names.borrow().iter() {
+                    let _ = client.batch_execute(&format!("drop schema {} cascade", name));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs PELO_TEST_POSTGRES"]
+    fn test_postgres_conformance() {
+        let schemas = TestSchemas::new();
+        conformance::check_all(|| schemas.open());
+    }
+
+    #[test]
+    #[ignore = "needs PELO_TEST_POSTGRES"]
+    fn test_postgres_shared_database() {
+        let schemas = TestSchemas::new();
+        let schema = schemas.create();
+        let mut first = schemas.connect(&schema).unwrap();
+        let mut second = schemas.connect(&schema).unwrap();
+        assert_eq!(first.schema_version().unwrap(), POSTGRES_SCHEMA_VERSION);
+
+        first.upsert_user(&User::new("voter", -1)).unwrap();
+        let tasks: Vec<Task> = (0..2)
+            .map(|i| {
+                let link = Url::parse(&format!("https://localhost/{}", i)).unwrap();
+                Task::new(Uuid::new_v4(), &format!("task {}", i), link, false)
+            })
+            .collect();
+        for task in &tasks {
+            first.upsert_task(task, "voter").unwrap();
+        }
+        assert_eq!(second.list_tasks().unwrap().len(), 2);
+
+        let (t0, t1) = (*tasks[0].id(), *tasks[1].id());
+        let vote = |p: &mut PostgresPersistence, etag: &Etag| {
+            p.add_vote_and_update_ratings(
+                etag,
+                &Vote::new(
+                    "voter",
+                    std::time::SystemTime::now().into(),
+                    t0,
+                    t1,
+                    Outcome::P0Win,
+                ),
+                &Rating::with_elo(t0, 1216.0),
+                &Rating::with_elo(t1, 1184.0),
+            )
+        };
+        let stale = second.get_snapshot().unwrap();
+        let fresh = first.get_snapshot().unwrap();
+        vote(&mut first, fresh.etag()).unwrap();
+        assert_eq!(
+            vote(&mut second, stale.etag()).err().unwrap().code(),
+            ErrorCode::OptimisticConcurrencyRetryTransaction
+        );
+        assert_eq!(second.list_votes().unwrap().len(), 1);
+
+        // A transaction of one connection is invisible to the other until it
+        // is committed.
+        first.begin_transaction().unwrap();
+        first.close_task(&t0).unwrap();
+        assert_eq!(
+            first.close_task(&Uuid::new_v4()).err().unwrap().code(),
+            ErrorCode::TaskNotFound
+        );
+        assert!(!second.list_tasks().unwrap()[0].closed());
+        first.commit_transaction().unwrap();
+        assert!(second.list_tasks().unwrap()[0].closed());
+    }
+
+    #[test]
+    #[ignore = "needs PELO_TEST_POSTGRES"]
+    fn test_postgres_refuse_newer_database() {
+        let schemas = TestSchemas::new();
+        let schema = schemas.create();
+        drop(schemas.connect(&schema).unwrap());
+        let mut client = Client::connect(&schemas.config, NoTls).unwrap();
+        client
+            .execute(
+                &format!("update {}.pelo_schema_version set version = $1", schema),
+                &[&(POSTGRES_SCHEMA_VERSION as i32 + 1)],
+            )
+            .unwrap();
+        assert_eq!(
+            schemas.connect(&schema).err().unwrap().code(),
+            ErrorCode::SchemaTooNew
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::data::{Role, Task, User};
+
+/// Which tasks a `TaskQuery` selects according to whether they are closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TaskStatus {
+    #[default]
+    Any,
+    Open,
+    Closed,
+}
+
+/// The order of the tasks returned by a `TaskQuery`. Ties are broken by id,
+/// so that pages do not overlap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TaskOrder {
+    #[default]
+    Id,
+    Summary,
+}
+
+/// Selects a page of tasks for `Persistence::query_tasks`.
+///
+/// Texts are matched as substrings of the summary, ignoring the case of ASCII
+/// letters. A task must have every tag of the query to be selected.
+#[derive(Debug, Clone, Default)]
+pub struct TaskQuery {
+    status: TaskStatus,
+    tags: Vec<String>,
+    text: Option<String>,
+    order: TaskOrder,
+    descending: bool,
+    limit: Option<usize>,
+    offset: usize,
+}
+impl TaskQuery {
+    /// Selects every task, ordered by id.
+    pub fn new() -> Self {
+        TaskQuery::default()
+    }
+
+    pub fn with_status(mut self, status: TaskStatus) -> Self {
+        self.status = status;
+        self
+    }
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_string());
+        self
+    }
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+    pub fn with_order(mut self, order: TaskOrder, descending: bool) -> Self {
+        self.order = order;
+        self.descending = descending;
+        self
+    }
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.status
+    }
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+    pub fn order(&self) -> TaskOrder {
+        self.order
+    }
+    pub fn descending(&self) -> bool {
+        self.descending
+    }
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub(crate) fn matches(&self, t: &Task) -> bool {
+        let status = match self.status {
+            TaskStatus::Any => true,
+            TaskStatus::Open => !t.closed(),
+            TaskStatus::Closed => t.closed(),
+        };
+        status
+            && self.tags.iter().all(|tag| t.tags().contains(tag))
+            && self
+                .text
+                .as_ref()
+                .is_none_or(|text| contains_ignoring_case(t.summary(), text))
+    }
+
+    // Filters, sorts and pages tasks, for backends that cannot do it while
+    // reading them.
+    pub(crate) fn apply(&self, tasks: Vec<Task>) -> Vec<Task> {
+        let mut tasks: Vec<Task> = tasks.into_iter().filter(|t| self.matches(t)).collect();
+        match self.order {
+            TaskOrder::Id => tasks.sort_by_key(|t| *t.id()),
+            TaskOrder::Summary => {
+                tasks.sort_by(|a, b| (a.summary(), a.id()).cmp(&(b.summary(), b.id())))
+            }
+        }
+        if self.descending {
+            tasks.reverse();
+        }
+        page(tasks, self.offset, self.limit)
+    }
+}
+
+/// Selects a page of users for `Persistence::query_users`, ordered by id.
+///
+/// Texts are matched as substrings of the id, ignoring the case of ASCII
+/// letters.
+#[derive(Debug, Clone, Default)]
+pub struct UserQuery {
+    role: Option<Role>,
+    text: Option<String>,
+    limit: Option<usize>,
+    offset: usize,
+}
+impl UserQuery {
+    /// Selects every user.
+    pub fn new() -> Self {
+        UserQuery::default()
+    }
+
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = Some(role);
+        self
+    }
+    pub fn with_text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+    pub fn with_offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn role(&self) -> Option<Role> {
+        self.role
+    }
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // Filters, sorts and pages users, for backends that cannot do it while
+    // reading them.
+    pub(crate) fn apply(&self, users: Vec<User>) -> Vec<User> {
+        let mut users: Vec<User> = users
+            .into_iter()
+            .filter(|u| {
+                self.role.is_none_or(|role| u.role() == role)
+                    && self
+                        .text
+                        .as_ref()
+                        .is_none_or(|text| contains_ignoring_case(u.id(), text))
+            })
+            .collect();
+        users.sort_by(|a, b| a.id().cmp(b.id()));
+        page(users, self.offset, self.limit)
+    }
+}
+
+fn page<T>(items: Vec<T>, offset: usize, limit: Option<usize>) -> Vec<T> {
+    items
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect()
+}
+
+// Matches like the SQL LIKE operator of SQLite, which only folds ASCII.
+fn contains_ignoring_case(haystack: &str, needle: &str) -> bool {
+    haystack
+        .to_ascii_lowercase()
+        .contains(&needle.to_ascii_lowercase())
+}